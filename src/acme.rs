@@ -0,0 +1,197 @@
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+
+use anyhow::Context;
+use rcgen::{CertificateParams, CustomExtension, KeyPair};
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::sign::CertifiedKey;
+use salvo::acme::certon::{
+    self, AcmeIssuer, CacheOptions, CertCache, CertResolver, FileStorage, Solver,
+};
+use salvo::async_trait;
+use salvo::prelude::*;
+use sha2::{Digest, Sha256};
+
+use crate::tls::TlsConfig;
+
+/// How the ACME server checks that we control the domains.
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Challenge {
+    /// Fetches `/.well-known/acme-challenge/<TOKEN>` over HTTP on port 80.
+    #[value(name = "http-01")]
+    Http01,
+    /// Connects over TLS on port 443, asking for the `acme-tls/1` protocol.
+    #[value(name = "tls-alpn-01")]
+    TlsAlpn01,
+}
+
+#[derive(Debug)]
+pub(crate) struct AcmeConfig {
+    pub(crate) domains: Vec<String>,
+    /// Keeps the account and the certificates.
+    pub(crate) dir: String,
+    /// URL of the ACME directory.
+    pub(crate) directory: String,
+    pub(crate) contact: Option<String>,
+    pub(crate) challenge: Challenge,
+}
+
+/// Key authorizations of the pending HTTP-01 challenges, by token.
+static HTTP_CHALLENGES: Mutex<BTreeMap<String, String>> = Mutex::new(BTreeMap::new());
+
+struct Http01Solver;
+
+#[async_trait]
+impl Solver for Http01Solver {
+    async fn present(&self, _domain: &str, token: &str, key_auth: &str) -> certon::Result<()> {
+        HTTP_CHALLENGES
+            .lock()
+            .unwrap()
+            .insert(token.to_string(), key_auth.to_string());
+        Ok(())
+    }
+
+    async fn cleanup(&self, _domain: &str, token: &str, _key_auth: &str) -> certon::Result<()> {
+        HTTP_CHALLENGES.lock().unwrap().remove(token);
+        Ok(())
+    }
+}
+
+/// Answers TLS-ALPN-01 challenges from the listeners' own certificate
+/// resolver, so that no extra port is needed.
+struct TlsAlpn01Solver(Arc<CertResolver>);
+
+#[async_trait]
+impl Solver for TlsAlpn01Solver {
+    async fn present(&self, domain: &str, _token: &str, key_auth: &str) -> certon::Result<()> {
+        let cert =
+            challenge_cert(domain, key_auth).map_err(|e| certon::Error::Other(format!("{e:#}")))?;
+        self.0
+            .set_challenge_cert(domain.to_string(), Arc::new(cert))
+            .await;
+        Ok(())
+    }
+
+    async fn cleanup(&self, domain: &str, _token: &str, _key_auth: &str) -> certon::Result<()> {
+        self.0.remove_challenge_cert(domain).await;
+        Ok(())
+    }
+}
+
+/// The self-signed certificate that proves control of `domain` to a
+/// TLS-ALPN-01 validation (RFC 8737).
+fn challenge_cert(domain: &str, key_auth: &str) -> anyhow::Result<CertifiedKey> {
+    let key_pair = KeyPair::generate()?;
+    let mut params = CertificateParams::new(vec![domain.to_string()])?;
+    params
+        .custom_extensions
+        .push(CustomExtension::new_acme_identifier(&Sha256::digest(
+            key_auth,
+        )));
+    let cert = params.self_signed(&key_pair)?;
+    // rustls cannot check that the key matches, since webpki rejects the
+    // critical acmeIdentifier extension.
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .context("No TLS crypto provider installed")?;
+    let key = provider
+        .key_provider
+        .load_private_key(PrivatePkcs8KeyDer::from(key_pair.serialize_der()).into())?;
+    Ok(CertifiedKey::new(vec![cert.der().clone()], key))
+}
+
+/// Answers HTTP-01 challenges.
+#[handler]
+pub(crate) async fn http_challenge(req: &mut Request, res: &mut Response) {
+    let token = req.param::<String>("token").unwrap_or_default();
+    let key_auth = HTTP_CHALLENGES.lock().unwrap().get(&token).cloned();
+    match key_auth {
+        Some(key_auth) => res.render(Text::Plain(key_auth)),
+        None => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+    }
+}
+
+/// Obtains certificates for `config.domains` and renews them in the
+/// background, loading those already in `config.dir` first. The returned
+/// configuration serves them as soon as they are issued.
+pub(crate) async fn start(config: AcmeConfig) -> anyhow::Result<TlsConfig> {
+    std::fs::create_dir_all(&config.dir)
+        .with_context(|| format!("Failed to create {}", config.dir))?;
+    let storage = Arc::new(FileStorage::new(&config.dir));
+    let cache = CertCache::new(CacheOptions::default());
+    let mut resolver = CertResolver::new(cache.clone());
+    // Clients that do not send SNI, such as those connecting by address,
+    // get the first domain's certificate.
+    resolver.set_default_server_name(config.domains.first().cloned());
+    let resolver = Arc::new(resolver);
+
+    let mut issuer = AcmeIssuer::builder()
+        .ca(&config.directory)
+        .agreed(true)
+        .storage(storage.clone())
+        .disable_distributed_solvers(true);
+    if let Some(contact) = &config.contact {
+        issuer = issuer.email(contact);
+    }
+    issuer = match config.challenge {
+        Challenge::Http01 => issuer
+            .http01_solver(Arc::new(Http01Solver))
+            .disable_tlsalpn_challenge(true),
+        Challenge::TlsAlpn01 => issuer
+            .tlsalpn01_solver(Arc::new(TlsAlpn01Solver(resolver.clone())))
+            .disable_http_challenge(true),
+    };
+
+    let certon = certon::Config::builder()
+        .storage(storage)
+        .issuers(vec![Arc::new(issuer.build())])
+        .cache(cache)
+        .build();
+    certon
+        .manage_async(&config.domains)
+        .await
+        .context("Failed to manage certificates")?;
+    certon::start_maintenance(&certon);
+    tracing::info!(
+        "Managing certificates for {} with {}",
+        config.domains.join(", "),
+        config.directory
+    );
+    Ok(TlsConfig::new(
+        resolver,
+        config.challenge == Challenge::TlsAlpn01,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::prelude::*;
+    use salvo::test::{ResponseExt, TestClient};
+
+    use super::{HTTP_CHALLENGES, challenge_cert, http_challenge};
+
+    #[tokio::test]
+    async fn test_http_challenge() {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .ok();
+        HTTP_CHALLENGES.lock().unwrap().insert(
+            "test-token".to_string(),
+            "test-token.thumbprint".to_string(),
+        );
+        let router = Router::with_path(".well-known/acme-challenge/{token}").get(http_challenge);
+        let service = Service::new(router);
+
+        let mut res = TestClient::get("http://127.0.0.1/.well-known/acme-challenge/test-token")
+            .send(&service)
+            .await;
+        assert_eq!(res.take_string().await.unwrap(), "test-token.thumbprint");
+        let res = TestClient::get("http://127.0.0.1/.well-known/acme-challenge/other")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+
+        challenge_cert("time.example.org", "test-token.thumbprint").unwrap();
+    }
+}
@@ -1,10 +1,13 @@
 use std::sync::OnceLock;
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::UNIX_EPOCH;
 
 use rust_embed::RustEmbed;
 use salvo::prelude::*;
 use salvo::serve_static::static_embed;
 
+use crate::router;
+use crate::tls::TlsConfig;
+
 #[derive(RustEmbed)]
 #[folder = "dist/"]
 struct Asset;
@@ -12,7 +15,9 @@ struct Asset;
 #[derive(Debug)]
 pub(crate) struct QuicInfo {
     pub(crate) port: u16,
-    pub(crate) cert_hash: String,
+    /// Pages pin its certificates by hash when they are eligible, following
+    /// reloads and rotations.
+    pub(crate) tls: TlsConfig,
 }
 
 static QUIC_INFO: OnceLock<Option<QuicInfo>> = OnceLock::new();
@@ -29,9 +34,11 @@ fn serve_html(path: &str, res: &mut Response) {
     let wt_port = quic
         .map(|w| w.port.to_string())
         .unwrap_or_else(|| "0".to_string());
-    let wt_cert = quic.map(|w| w.cert_hash.as_str()).unwrap_or("");
+    let wt_cert = quic
+        .map(|w| w.tls.cert_hashes().join(","))
+        .unwrap_or_default();
 
-    let timestamp = match SystemTime::now().duration_since(UNIX_EPOCH) {
+    let timestamp = match crate::clock::now().duration_since(UNIX_EPOCH) {
         Ok(ts) => (ts.as_secs_f64() * 1_000.0).to_string(),
         Err(_) => {
             res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
@@ -42,13 +49,26 @@ fn serve_html(path: &str, res: &mut Response) {
     let body = contents
         .replace("{{INITIAL_SERVER_TIME}}", &timestamp)
         .replace("{{WEB_TRANSPORT_PORT}}", &wt_port)
-        .replace("{{WEB_TRANSPORT_CERT}}", wt_cert);
+        .replace("{{WEB_TRANSPORT_CERT}}", &wt_cert)
+        .replace("{{TELEMETRY}}", &crate::telemetry::enabled().to_string())
+        .replace("{{BASE_PATH}}", router::base_path());
 
     res.render(Text::Html(body));
 }
 
 #[handler]
-pub(crate) async fn index(res: &mut Response) {
+pub(crate) async fn index(req: &mut Request, res: &mut Response) {
+    // Under a base path, the page's relative links need the trailing slash.
+    let path = req.uri().path();
+    if !path.ends_with('/') {
+        let query = req
+            .uri()
+            .query()
+            .map(|q| format!("?{q}"))
+            .unwrap_or_default();
+        res.render(Redirect::permanent(format!("{path}/{query}")));
+        return;
+    }
     serve_html("index.html", res);
 }
 
@@ -7,6 +7,15 @@ use std::time::{SystemTime, UNIX_EPOCH};
 use wtransport::tls::Sha256Digest;
 use wtransport::{ClientConfig, Endpoint};
 
+/// Clock probe sent by servers run with `--probe-interval`: these two bytes
+/// and the server time as a little-endian f64, never a time response.
+const PROBE_REQUEST: &[u8] = b"P?";
+const PROBE_REQUEST_LEN: usize = 10;
+
+fn is_probe(payload: &[u8]) -> bool {
+    payload.len() == PROBE_REQUEST_LEN && payload.starts_with(PROBE_REQUEST)
+}
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
@@ -24,13 +33,25 @@ struct Args {
     /// WebTransport server certificate SHA-256 fingerprint (base64)
     #[arg(long)]
     cert_hash: Option<String>,
+
+    /// Query the DNS TXT responder for this name instead of HTTP; the URL is
+    /// then the server address (e.g., 192.0.2.1 or dns.example.com:5353)
+    #[arg(long, conflicts_with_all = ["web_transport", "web_socket"])]
+    dns_name: Option<String>,
+
+    /// Verify the Ed25519 signature on HTTP time responses using this public
+    /// key (base64), as served at /.well-known/time/key
+    #[arg(long, conflicts_with_all = ["web_transport", "web_socket", "dns_name"])]
+    verify_key: Option<String>,
 }
 
 #[tokio::main]
 async fn main() -> Result<()> {
     let args = Args::parse();
 
-    if args.web_transport {
+    if let Some(dns_name) = &args.dns_name {
+        run_dns(&args.url, dns_name).await?;
+    } else if args.web_transport {
         run_web_transport(&args).await?;
     } else if args.web_socket {
         run_web_socket(&args).await?;
@@ -61,6 +82,8 @@ async fn run_http(args: &Args) -> Result<()> {
         .await
         .with_context(|| format!("Failed to connect to {}", url))?;
 
+    let nonce = format!("{:016x}{:016x}", fastrand::u64(..), fastrand::u64(..));
+
     let t1 = SystemTime::now()
         .duration_since(UNIX_EPOCH)
         .context("Local clock is before epoch")?
@@ -68,6 +91,7 @@ async fn run_http(args: &Args) -> Result<()> {
 
     let response = client
         .get(&url)
+        .header("x-httpstime-nonce", &nonce)
         .send()
         .await
         .with_context(|| format!("Failed to connect to {}", url))?;
@@ -92,11 +116,45 @@ async fn run_http(args: &Args) -> Result<()> {
         .parse()
         .context("Failed to parse server time as float")?;
 
+    if let Some(verify_key) = &args.verify_key {
+        let identity = verify_signature(&response, verify_key, &nonce, server_time_str)?;
+        println!("Signature:   valid for {}", identity);
+    }
+
     print_results(&url, server_time_secs, t1, t2);
 
     Ok(())
 }
 
+/// Verifies the x-httpstime-signature header, returning the signed identity.
+fn verify_signature(
+    response: &reqwest::Response,
+    public_key: &str,
+    nonce: &str,
+    timestamp: &str,
+) -> Result<String> {
+    let public_key = base64::engine::general_purpose::STANDARD
+        .decode(public_key)
+        .context("Invalid base64 in verify-key")?;
+    let header = |name: &str| -> Result<&str> {
+        response
+            .headers()
+            .get(name)
+            .with_context(|| format!("Server response missing {} header", name))?
+            .to_str()
+            .with_context(|| format!("Invalid {} header format", name))
+    };
+    let signature = base64::engine::general_purpose::STANDARD
+        .decode(header("x-httpstime-signature")?)
+        .context("Invalid base64 in x-httpstime-signature")?;
+    let identity = header("x-httpstime-identity")?;
+    let message = format!("foxtime-time-v1\n{}\n{}\n{}", identity, nonce, timestamp);
+    ring::signature::UnparsedPublicKey::new(&ring::signature::ED25519, &public_key)
+        .verify(message.as_bytes(), &signature)
+        .map_err(|_| anyhow::anyhow!("Invalid signature on server time"))?;
+    Ok(identity.to_string())
+}
+
 async fn run_web_socket(args: &Args) -> Result<()> {
     let mut url = args.url.clone();
     if !url.starts_with("ws://") && !url.starts_with("wss://") {
@@ -135,10 +193,16 @@ async fn run_web_socket(args: &Args) -> Result<()> {
         .send(reqwest_websocket::Message::Binary(vec![0].into()))
         .await?;
 
-    let message = websocket
-        .next()
-        .await
-        .context("WebSocket closed before receiving response")??;
+    let message = loop {
+        let message = websocket
+            .next()
+            .await
+            .context("WebSocket closed before receiving response")??;
+        match &message {
+            reqwest_websocket::Message::Binary(bin) if is_probe(bin) => continue,
+            _ => break message,
+        }
+    };
 
     let t2 = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -207,10 +271,15 @@ async fn run_web_transport(args: &Args) -> Result<()> {
         .send_datagram(t1.to_le_bytes())
         .context("Failed to send datagram")?;
 
-    let response = session
-        .receive_datagram()
-        .await
-        .context("Failed to receive datagram")?;
+    let response = loop {
+        let response = session
+            .receive_datagram()
+            .await
+            .context("Failed to receive datagram")?;
+        if !is_probe(&response) {
+            break response;
+        }
+    };
 
     let t2 = SystemTime::now()
         .duration_since(UNIX_EPOCH)
@@ -229,6 +298,193 @@ async fn run_web_transport(args: &Args) -> Result<()> {
     Ok(())
 }
 
+fn dns_query(id: u16, name: &str) -> Result<Vec<u8>> {
+    let mut query = Vec::with_capacity(512);
+    query.extend_from_slice(&id.to_be_bytes());
+    // Standard query, recursion desired so that a local resolver forwards
+    // the fresh nonce label, one question.
+    query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+    for label in name.trim_end_matches('.').split('.') {
+        if label.is_empty() || label.len() > 63 {
+            anyhow::bail!("Invalid DNS name: {}", name);
+        }
+        query.push(label.len() as u8);
+        query.extend_from_slice(label.as_bytes());
+    }
+    // Root label, QTYPE TXT, QCLASS IN.
+    query.extend_from_slice(&[0, 0x00, 0x10, 0x00, 0x01]);
+    Ok(query)
+}
+
+fn skip_dns_name(msg: &[u8], mut offset: usize) -> Result<usize> {
+    loop {
+        let len = *msg.get(offset).context("Truncated DNS name")? as usize;
+        if len == 0 {
+            return Ok(offset + 1);
+        }
+        if len & 0xc0 == 0xc0 {
+            return Ok(offset + 2);
+        }
+        offset += len + 1;
+    }
+}
+
+/// Returns the TXT strings from the first TXT answer in a DNS response.
+fn dns_txt_strings(query: &[u8], response: &[u8]) -> Result<Vec<Vec<u8>>> {
+    if response.len() < 12 || response[..2] != query[..2] {
+        anyhow::bail!("DNS response does not match query");
+    }
+    let rcode = response[3] & 0x0f;
+    if rcode != 0 {
+        anyhow::bail!("DNS server returned rcode {}", rcode);
+    }
+    let qdcount = u16::from_be_bytes([response[4], response[5]]);
+    let ancount = u16::from_be_bytes([response[6], response[7]]);
+    let mut offset = 12;
+    for _ in 0..qdcount {
+        offset = skip_dns_name(response, offset)? + 4;
+    }
+    for _ in 0..ancount {
+        offset = skip_dns_name(response, offset)?;
+        let fixed = response
+            .get(offset..offset + 10)
+            .context("Truncated DNS answer")?;
+        let rtype = u16::from_be_bytes([fixed[0], fixed[1]]);
+        let rdlength = u16::from_be_bytes([fixed[8], fixed[9]]) as usize;
+        offset += 10;
+        let mut rdata = response
+            .get(offset..offset + rdlength)
+            .context("Truncated DNS answer")?;
+        offset += rdlength;
+        if rtype != 16 {
+            continue;
+        }
+        let mut strings = Vec::new();
+        while let Some((&len, rest)) = rdata.split_first() {
+            let s = rest.get(..len as usize).context("Truncated TXT string")?;
+            strings.push(s.to_vec());
+            rdata = &rest[len as usize..];
+        }
+        return Ok(strings);
+    }
+    anyhow::bail!("DNS response has no TXT answer")
+}
+
+/// Repeats a DNS query over TCP, returning the response with the local times
+/// it was sent and received.
+async fn dns_tcp_exchange(addr: std::net::SocketAddr, query: &[u8]) -> Result<(Vec<u8>, f64, f64)> {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    let timeout = std::time::Duration::from_secs(5);
+    let mut stream = tokio::time::timeout(timeout, tokio::net::TcpStream::connect(addr))
+        .await
+        .with_context(|| format!("Timed out connecting to {}", addr))??;
+    let mut message = (query.len() as u16).to_be_bytes().to_vec();
+    message.extend_from_slice(query);
+
+    let t1 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Local clock is before epoch")?
+        .as_secs_f64();
+
+    stream.write_all(&message).await?;
+    let response = tokio::time::timeout(timeout, async {
+        let len = stream.read_u16().await?;
+        let mut response = vec![0u8; len as usize];
+        stream.read_exact(&mut response).await?;
+        std::io::Result::Ok(response)
+    })
+    .await
+    .with_context(|| format!("Timed out waiting for {}", addr))??;
+
+    let t2 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Local clock is before epoch")?
+        .as_secs_f64();
+
+    Ok((response, t1, t2))
+}
+
+async fn run_dns(server: &str, dns_name: &str) -> Result<()> {
+    let server_addr = if let Ok(ip) = server.parse::<std::net::IpAddr>() {
+        std::net::SocketAddr::new(ip, 53).to_string()
+    } else if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{}:53", server)
+    };
+    let addr = tokio::net::lookup_host(&server_addr)
+        .await
+        .with_context(|| format!("Failed to resolve {}", server_addr))?
+        .next()
+        .with_context(|| format!("No addresses for {}", server_addr))?;
+
+    let bind_addr: std::net::SocketAddr = if addr.is_ipv4() {
+        (std::net::Ipv4Addr::UNSPECIFIED, 0).into()
+    } else {
+        (std::net::Ipv6Addr::UNSPECIFIED, 0).into()
+    };
+    let socket = tokio::net::UdpSocket::bind(bind_addr).await?;
+    socket.connect(addr).await?;
+
+    // The nonce label is echoed back by the server, tying the answer to this
+    // query even through caching resolvers that ignore the zero TTL.
+    let nonce_label = format!("{:x}", fastrand::u64(..));
+    let query = dns_query(fastrand::u16(..), &format!("{}.{}", nonce_label, dns_name))?;
+    let mut buf = [0u8; 512];
+
+    let mut t1 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Local clock is before epoch")?
+        .as_secs_f64();
+
+    socket.send(&query).await?;
+    let len = tokio::time::timeout(std::time::Duration::from_secs(5), socket.recv(&mut buf))
+        .await
+        .with_context(|| format!("Timed out waiting for {}", addr))??;
+
+    let mut t2 = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("Local clock is before epoch")?
+        .as_secs_f64();
+
+    let mut response = buf[..len].to_vec();
+    // A truncated answer (TC) is repeated in full over TCP.
+    if response.get(2).is_some_and(|flags| flags & 0x02 != 0) {
+        (response, t1, t2) = dns_tcp_exchange(addr, &query).await?;
+    }
+
+    let strings = dns_txt_strings(&query, &response)?;
+    let mut server_time_secs = None;
+    let mut echoed_nonce = None;
+    for s in &strings {
+        if let Some(ts) = s.strip_prefix(b"t=") {
+            server_time_secs = Some(
+                std::str::from_utf8(ts)
+                    .ok()
+                    .and_then(|ts| ts.parse::<f64>().ok())
+                    .context("Failed to parse server time as float")?,
+            );
+        } else if let Some(n) = s.strip_prefix(b"n=") {
+            echoed_nonce = Some(n);
+        }
+    }
+    // DNS names are case-insensitive and resolvers may randomize case.
+    if !echoed_nonce.is_some_and(|n| n.eq_ignore_ascii_case(nonce_label.as_bytes())) {
+        anyhow::bail!("DNS response did not echo the query nonce");
+    }
+    let server_time_secs = server_time_secs.context("DNS response missing timestamp")?;
+
+    print_results(
+        &format!("{} via {}", dns_name, addr),
+        server_time_secs,
+        t1,
+        t2,
+    );
+
+    Ok(())
+}
+
 fn print_results(url: &str, server_time_secs: f64, t1: f64, t2: f64) {
     let rtt = t2 - t1;
     let adjusted_local_time = (t1 + t2) / 2.0;
@@ -0,0 +1,163 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::{Deserialize, Serialize};
+
+/// Maximum number of time responses held back on a single WS or WT session.
+pub(crate) const MAX_PENDING: usize = 64;
+
+/// Transports whose time responses can be disturbed independently.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Transport {
+    Http,
+    Ws,
+    Wt,
+}
+
+/// Simulated network conditions, parsed from comma-separated `key=value`
+/// pairs such as `before=0.05,after=0.01,jitter=0.02,drop=0.1`. Delays are in
+/// seconds and `drop` and `duplicate` are probabilities.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub(crate) struct Chaos {
+    /// Delay between receiving a request and taking the timestamp, standing
+    /// in for the client-to-server path.
+    pub(crate) before: Duration,
+    /// Delay between taking the timestamp and sending the response, standing
+    /// in for the server-to-client path.
+    pub(crate) after: Duration,
+    /// Upper bound of a uniformly random extra delay added to each of
+    /// `before` and `after`.
+    pub(crate) jitter: Duration,
+    /// Probability that a response is not sent at all.
+    pub(crate) drop: f64,
+    /// Probability that a response is sent twice.
+    pub(crate) duplicate: f64,
+}
+
+pub(crate) fn parse_chaos(s: &str) -> Result<Chaos, String> {
+    let parse = || -> anyhow::Result<Chaos> {
+        let mut chaos = Chaos::default();
+        for pair in s.split(',').filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .with_context(|| format!("Expected key=value: {pair}"))?;
+            let value: f64 = value
+                .parse()
+                .with_context(|| format!("Invalid number: {value}"))?;
+            let secs = || Duration::try_from_secs_f64(value).context("Invalid delay");
+            let probability = || {
+                if (0.0..=1.0).contains(&value) {
+                    Ok(value)
+                } else {
+                    Err(anyhow::anyhow!("Probability must be between 0 and 1"))
+                }
+            };
+            match key {
+                "before" => chaos.before = secs()?,
+                "after" => chaos.after = secs()?,
+                "jitter" => chaos.jitter = secs()?,
+                "drop" => chaos.drop = probability()?,
+                "duplicate" => chaos.duplicate = probability()?,
+                _ => anyhow::bail!("Unknown key: {key}"),
+            }
+        }
+        Ok(chaos)
+    };
+    parse().map_err(|e| format!("{e:#}"))
+}
+
+impl Chaos {
+    fn jittered(&self, delay: Duration) -> Duration {
+        delay + self.jitter.mul_f64(fastrand::f64())
+    }
+
+    /// How many copies of a response to send: 0 when dropped, 2 when
+    /// duplicated and 1 otherwise.
+    fn copies(&self) -> usize {
+        if fastrand::f64() < self.drop {
+            0
+        } else if fastrand::f64() < self.duplicate {
+            2
+        } else {
+            1
+        }
+    }
+}
+
+static CHAOS: OnceLock<[Option<Chaos>; 3]> = OnceLock::new();
+
+pub(crate) fn set_chaos(http: Option<Chaos>, ws: Option<Chaos>, wt: Option<Chaos>) {
+    CHAOS.set([http, ws, wt]).ok();
+}
+
+fn chaos(transport: Transport) -> Option<&'static Chaos> {
+    CHAOS.get()?[transport as usize].as_ref()
+}
+
+async fn sleep(delay: Duration) {
+    if !delay.is_zero() {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// Waits out the simulated delay before a time response's timestamp is taken.
+pub(crate) async fn before(transport: Transport) {
+    if let Some(chaos) = chaos(transport) {
+        sleep(chaos.jittered(chaos.before)).await;
+    }
+}
+
+/// Waits out the simulated delay after a time response's timestamp is taken.
+pub(crate) async fn after(transport: Transport) {
+    if let Some(chaos) = chaos(transport) {
+        sleep(chaos.jittered(chaos.after)).await;
+    }
+}
+
+/// How many copies of a time response to send.
+pub(crate) fn copies(transport: Transport) -> usize {
+    chaos(transport).map_or(1, Chaos::copies)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{Chaos, parse_chaos};
+
+    #[test]
+    fn test_chaos() {
+        let chaos =
+            parse_chaos("before=0.05,after=0.01,jitter=0.002,drop=0.1,duplicate=1").unwrap();
+        assert_eq!(
+            chaos,
+            Chaos {
+                before: Duration::from_millis(50),
+                after: Duration::from_millis(10),
+                jitter: Duration::from_millis(2),
+                drop: 0.1,
+                duplicate: 1.0,
+            }
+        );
+        assert_eq!(parse_chaos("").unwrap(), Chaos::default());
+        assert!(parse_chaos("delay=1").is_err());
+        assert!(parse_chaos("before").is_err());
+        assert!(parse_chaos("before=-1").is_err());
+        assert!(parse_chaos("drop=2").is_err());
+
+        for _ in 0..100 {
+            let delay = chaos.jittered(chaos.before);
+            assert!(delay >= chaos.before && delay <= chaos.before + chaos.jitter);
+        }
+        let always = |drop, duplicate| Chaos {
+            drop,
+            duplicate,
+            ..Chaos::default()
+        };
+        assert_eq!(always(1.0, 0.0).copies(), 0);
+        assert_eq!(always(0.0, 1.0).copies(), 2);
+        assert_eq!(always(0.0, 0.0).copies(), 1);
+    }
+}
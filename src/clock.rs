@@ -0,0 +1,90 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Frequency tolerance assumed for the system clock between reference
+/// updates, as in NTP (15 PPM).
+const PHI: f64 = 15e-6;
+
+/// How long the last reference update keeps the clock synchronized.
+const HOLDOVER: Duration = Duration::from_secs(60);
+
+/// The kernel's view of how good the system clock is, as maintained by the
+/// host's NTP daemon through adjtimex(2).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct ClockStatus {
+    pub(crate) synchronized: bool,
+    /// Upper bound on the clock error.
+    pub(crate) max_error: Duration,
+    /// Estimated clock error.
+    pub(crate) est_error: Duration,
+}
+
+struct Reference {
+    updated: Instant,
+    error: Duration,
+}
+
+/// Offset of the served time from the system clock, in nanoseconds.
+static OFFSET: AtomicI64 = AtomicI64::new(0);
+static REFERENCE: Mutex<Option<Reference>> = Mutex::new(None);
+
+/// The current time as served on every endpoint: the system clock corrected
+/// by the offset measured against the reference source, if any, and shifted
+/// by the fake clock in test mode.
+pub(crate) fn now() -> SystemTime {
+    adjust(SystemTime::now())
+}
+
+/// Applies the reference offset and the fake clock to a system clock
+/// timestamp, such as one taken by the kernel.
+pub(crate) fn adjust(time: SystemTime) -> SystemTime {
+    let offset = OFFSET.load(Ordering::Relaxed);
+    let time = if offset >= 0 {
+        time + Duration::from_nanos(offset as u64)
+    } else {
+        time - Duration::from_nanos(offset.unsigned_abs())
+    };
+    crate::fake::apply(time)
+}
+
+/// Records a new measurement of the reference time minus the system time, in
+/// nanoseconds, with its estimated error.
+pub(crate) fn set_reference(offset: i64, error: Duration) {
+    OFFSET.store(offset, Ordering::Relaxed);
+    *REFERENCE.lock().unwrap() = Some(Reference {
+        updated: Instant::now(),
+        error,
+    });
+}
+
+/// The last measured reference time minus system time, in seconds, if the
+/// time is served from a reference source.
+pub(crate) fn reference_offset() -> Option<f64> {
+    REFERENCE.lock().unwrap().as_ref()?;
+    Some(OFFSET.load(Ordering::Relaxed) as f64 * 1e-9)
+}
+
+pub(crate) fn status() -> ClockStatus {
+    if let Some(reference) = &*REFERENCE.lock().unwrap() {
+        let age = reference.updated.elapsed();
+        return ClockStatus {
+            synchronized: age < HOLDOVER,
+            max_error: reference.error + age.mul_f64(PHI),
+            est_error: reference.error,
+        };
+    }
+
+    // SAFETY: timex is plain data, and with modes = 0 adjtimex only reads the
+    // kernel clock state into it.
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+    let micros = |us: libc::c_long| Duration::from_micros(us.max(0) as u64);
+    ClockStatus {
+        synchronized: state >= 0
+            && state != libc::TIME_ERROR
+            && timex.status & libc::STA_UNSYNC == 0,
+        max_error: micros(timex.maxerror),
+        est_error: micros(timex.esterror),
+    }
+}
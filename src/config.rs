@@ -0,0 +1,206 @@
+use std::ffi::OsString;
+
+use anyhow::Context;
+use clap::parser::ValueSource;
+use clap::{ArgAction, Command, CommandFactory, FromArgMatches};
+
+/// Prefix of the environment variables that stand in for options, such as
+/// `FOXTIME_TLS_CERT` for `--tls-cert`.
+const ENV_PREFIX: &str = "FOXTIME_";
+
+/// Options that cannot be set from the configuration file.
+const CLI_ONLY: [&str; 3] = ["config", "help", "version"];
+
+fn command<T: CommandFactory>() -> Command {
+    T::command().mut_args(|arg| {
+        if matches!(arg.get_id().as_str(), "help" | "version") {
+            return arg;
+        }
+        let env = format!(
+            "{ENV_PREFIX}{}",
+            arg.get_id().as_str().to_uppercase().replace('-', "_")
+        );
+        arg.env(env)
+    })
+}
+
+/// Finds `--config` and where the other options were given. Validation waits
+/// for the final pass, as the file may provide what other options require.
+fn first_pass<T: CommandFactory>(cli: &[OsString]) -> clap::ArgMatches {
+    command::<T>().ignore_errors(true).get_matches_from(cli)
+}
+
+/// Converts a TOML value into `--long=value` arguments. Booleans turn flags on
+/// or leave them off, and arrays repeat the option.
+fn push_value(args: &mut Vec<OsString>, long: &str, value: &toml::Value) -> anyhow::Result<()> {
+    match value {
+        toml::Value::Boolean(true) => args.push(format!("--{long}").into()),
+        toml::Value::Boolean(false) => {}
+        toml::Value::String(s) => args.push(format!("--{long}={s}").into()),
+        toml::Value::Integer(i) => args.push(format!("--{long}={i}").into()),
+        toml::Value::Float(f) => args.push(format!("--{long}={f}").into()),
+        toml::Value::Array(values) => {
+            for value in values {
+                if value.is_array() || value.is_table() {
+                    anyhow::bail!("Nested arrays are not supported for `{long}`");
+                }
+                push_value(args, long, value)?;
+            }
+        }
+        toml::Value::Table(_) | toml::Value::Datetime(_) => {
+            anyhow::bail!("Unsupported value type for `{long}`")
+        }
+    }
+    Ok(())
+}
+
+/// Flattens a TOML table into option names and values. Keys of a section are
+/// prefixed with its name, so that `device` under `[gps]` is `gps-device`.
+fn flatten<'a>(table: &'a toml::Table, prefix: &str, options: &mut Vec<(String, &'a toml::Value)>) {
+    for (key, value) in table {
+        let name = if prefix.is_empty() {
+            key.replace('_', "-")
+        } else {
+            format!("{prefix}-{}", key.replace('_', "-"))
+        };
+        match value {
+            toml::Value::Table(table) => flatten(table, &name, options),
+            _ => options.push((name, value)),
+        }
+    }
+}
+
+/// Converts a configuration file into arguments for the options that were not
+/// given on the command line or in the environment.
+fn config_args(
+    command: &Command,
+    matches: &clap::ArgMatches,
+    config: &str,
+) -> anyhow::Result<Vec<OsString>> {
+    let table: toml::Table = config.parse()?;
+    let mut options = Vec::new();
+    flatten(&table, "", &mut options);
+
+    let mut args = Vec::new();
+    for (name, value) in options {
+        let Some(arg) = command
+            .get_arguments()
+            .find(|arg| arg.get_long() == Some(name.as_str()))
+            .filter(|arg| !CLI_ONLY.contains(&arg.get_id().as_str()))
+        else {
+            anyhow::bail!("Unknown option `{name}`");
+        };
+        if matches!(
+            matches.value_source(arg.get_id().as_str()),
+            Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+        ) {
+            continue;
+        }
+        if matches!(arg.get_action(), ArgAction::SetTrue) && !value.is_bool() {
+            anyhow::bail!("`{name}` must be true or false");
+        }
+        push_value(&mut args, &name, value)?;
+    }
+    Ok(args)
+}
+
+/// Parses the command line, the `FOXTIME_*` environment variables and the
+/// TOML file given to `--config`, in decreasing order of precedence.
+pub(crate) fn parse<T: CommandFactory + FromArgMatches>() -> anyhow::Result<T> {
+    let cli: Vec<OsString> = std::env::args_os().collect();
+    let matches = first_pass::<T>(&cli);
+    let Some(path) = matches.get_one::<String>("config") else {
+        let matches = command::<T>().get_matches_from(&cli);
+        return Ok(T::from_arg_matches(&matches)?);
+    };
+
+    let config = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    let args = config_args(&command::<T>(), &matches, &config)
+        .with_context(|| format!("Invalid configuration in {path}"))?;
+    let matches = command::<T>()
+        .try_get_matches_from(cli.into_iter().chain(args))
+        .with_context(|| format!("Invalid configuration in {path}"))?;
+    Ok(T::from_arg_matches(&matches)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::ffi::OsString;
+
+    use clap::{CommandFactory, Parser};
+
+    use super::{config_args, first_pass};
+
+    #[derive(Parser, Debug)]
+    struct Args {
+        #[arg(long)]
+        config: Option<String>,
+
+        #[arg(long, default_value_t = false)]
+        listen_any: bool,
+
+        #[arg(long, default_value_t = 8123)]
+        port: u16,
+
+        #[arg(long)]
+        gps_device: Option<String>,
+
+        #[arg(long, requires = "gps_device")]
+        gps_baud: Option<u32>,
+
+        #[arg(long, allow_negative_numbers = true)]
+        fake_offset: Option<f64>,
+
+        #[arg(long)]
+        peer: Vec<String>,
+    }
+
+    fn parse(cli: &[&str], config: &str) -> anyhow::Result<Args> {
+        let cli: Vec<String> = ["foxtime"]
+            .iter()
+            .chain(cli)
+            .map(|s| s.to_string())
+            .collect();
+        let matches = first_pass::<Args>(&cli.iter().map(OsString::from).collect::<Vec<_>>());
+        let args = config_args(&Args::command(), &matches, config)?;
+        Ok(Args::try_parse_from(cli.into_iter().chain(
+            args.into_iter().map(|a| a.into_string().unwrap()),
+        ))?)
+    }
+
+    #[test]
+    fn test_config() {
+        let config = r#"
+            listen-any = true
+            port = 9000
+            fake_offset = -1.5
+            peer = ["ws://a/time-ws", "ws://b/time-ws"]
+
+            [gps]
+            device = "/dev/ttyS0"
+        "#;
+        let args = parse(&[], config).unwrap();
+        assert!(args.listen_any);
+        assert_eq!(args.port, 9000);
+        assert_eq!(args.fake_offset, Some(-1.5));
+        assert_eq!(args.peer, ["ws://a/time-ws", "ws://b/time-ws"]);
+        assert_eq!(args.gps_device.as_deref(), Some("/dev/ttyS0"));
+
+        // The command line wins, including for lists.
+        let args = parse(&["--port", "9001", "--peer", "ws://c/time-ws"], config).unwrap();
+        assert_eq!(args.port, 9001);
+        assert_eq!(args.peer, ["ws://c/time-ws"]);
+
+        // An option may require one that only the file gives.
+        let args = parse(&["--gps-baud", "4800"], config).unwrap();
+        assert_eq!(args.gps_baud, Some(4800));
+        assert_eq!(args.gps_device.as_deref(), Some("/dev/ttyS0"));
+        assert!(parse(&["--gps-baud", "4800"], "").is_err());
+
+        let error = parse(&[], "prot = 9000").unwrap_err();
+        assert_eq!(error.to_string(), "Unknown option `prot`");
+        assert!(parse(&[], "config = \"other.toml\"").is_err());
+        assert!(parse(&[], "listen-any = 1").is_err());
+        assert!(parse(&[], "port = \"http\"").is_err());
+    }
+}
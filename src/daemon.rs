@@ -0,0 +1,496 @@
+use std::collections::BTreeMap;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::unix::fs::PermissionsExt;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::Duration;
+
+use anyhow::Context;
+use serde::Serialize;
+use tokio::net::{UdpSocket, UnixDatagram};
+
+/// How often the daemon is asked for its status.
+const POLL_INTERVAL: Duration = Duration::from_secs(16);
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+pub(crate) const DEFAULT_CHRONY_SOCKET: &str = "/var/run/chrony/chronyd.sock";
+pub(crate) const DEFAULT_NTPD_ADDR: &str = "127.0.0.1:123";
+
+// chrony command protocol, from candm.h.
+const CHRONY_PROTO_VERSION: u8 = 6;
+const CHRONY_PKT_TYPE_REQUEST: u8 = 1;
+const CHRONY_PKT_TYPE_REPLY: u8 = 2;
+const CHRONY_REQ_TRACKING: u16 = 33;
+const CHRONY_RPY_TRACKING: u16 = 5;
+const CHRONY_REPLY_HEADER_LEN: usize = 28;
+const CHRONY_TRACKING_LEN: usize = 80;
+const CHRONY_IPADDR_INET4: u16 = 1;
+const CHRONY_IPADDR_INET6: u16 = 2;
+
+// NTP mode 6 control messages, from RFC 9327.
+const MODE6_HEADER_LEN: usize = 12;
+const MODE6_READVAR: u8 = 2;
+const MODE6_RESPONSE: u8 = 0x80;
+const MODE6_ERROR: u8 = 0x40;
+const MODE6_MORE: u8 = 0x20;
+
+/// The host time daemon's view of the clock.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct DaemonStatus {
+    pub(crate) daemon: &'static str,
+    pub(crate) reference_id: String,
+    pub(crate) stratum: u16,
+    /// Seconds.
+    pub(crate) root_delay: f64,
+    /// Seconds.
+    pub(crate) root_dispersion: f64,
+    /// Seconds.
+    pub(crate) last_offset: f64,
+}
+
+impl DaemonStatus {
+    /// Formats the status for the `x-httpstime-daemon` header.
+    pub(crate) fn header_value(&self) -> String {
+        format!(
+            "{}; refid={}; stratum={}; root-delay={:.6}; root-dispersion={:.6}; offset={:.6}",
+            self.daemon,
+            self.reference_id,
+            self.stratum,
+            self.root_delay,
+            self.root_dispersion,
+            self.last_offset
+        )
+    }
+}
+
+pub(crate) enum ChronyTransport {
+    Unix { socket: UnixDatagram, path: PathBuf },
+    Udp(UdpSocket),
+}
+
+pub(crate) enum Daemon {
+    Chrony {
+        transport: ChronyTransport,
+        sequence: u32,
+    },
+    Ntpd {
+        socket: UdpSocket,
+        sequence: u16,
+    },
+}
+
+impl Drop for ChronyTransport {
+    fn drop(&mut self) {
+        if let Self::Unix { path, .. } = self {
+            std::fs::remove_file(path).ok();
+        }
+    }
+}
+
+impl Daemon {
+    /// Connects to chronyd's command socket: a Unix socket path, or a UDP
+    /// address such as `127.0.0.1:323`. For a Unix socket, our end is bound
+    /// next to chronyd's, as chronyc does, so this must run before dropping
+    /// privileges.
+    pub(crate) async fn chrony(addr: &str) -> anyhow::Result<Self> {
+        let transport = if addr.starts_with('/') {
+            let dir = Path::new(addr).parent().unwrap_or(Path::new("/"));
+            let path = dir.join(format!("foxtime.{}.sock", std::process::id()));
+            std::fs::remove_file(&path).ok();
+            let socket =
+                UnixDatagram::bind(&path).with_context(|| format!("Bind {}", path.display()))?;
+            // Dropping the transport removes our socket file on any error.
+            let transport = ChronyTransport::Unix { socket, path };
+            if let ChronyTransport::Unix { socket, path } = &transport {
+                // chronyd drops privileges too, and must be able to reply.
+                std::fs::set_permissions(path, std::fs::Permissions::from_mode(0o666))?;
+                socket
+                    .connect(addr)
+                    .with_context(|| format!("Connect to {addr}"))?;
+            }
+            transport
+        } else {
+            ChronyTransport::Udp(connect_udp(addr).await?)
+        };
+        Ok(Self::Chrony {
+            transport,
+            sequence: rand_u32(),
+        })
+    }
+
+    /// Connects to ntpd's mode 6 control port, such as `127.0.0.1:123`.
+    pub(crate) async fn ntpd(addr: &str) -> anyhow::Result<Self> {
+        Ok(Self::Ntpd {
+            socket: connect_udp(addr).await?,
+            sequence: rand_u32() as u16,
+        })
+    }
+
+    pub(crate) async fn query(&mut self) -> anyhow::Result<DaemonStatus> {
+        tokio::time::timeout(QUERY_TIMEOUT, async {
+            match self {
+                Self::Chrony {
+                    transport,
+                    sequence,
+                } => {
+                    *sequence = sequence.wrapping_add(1);
+                    query_chrony(transport, *sequence).await
+                }
+                Self::Ntpd { socket, sequence } => {
+                    *sequence = sequence.wrapping_add(1);
+                    query_ntpd(socket, *sequence).await
+                }
+            }
+        })
+        .await
+        .context("No response from time daemon")?
+    }
+}
+
+async fn connect_udp(addr: &str) -> anyhow::Result<UdpSocket> {
+    let addr: SocketAddr = addr
+        .parse()
+        .with_context(|| format!("Invalid address {addr}"))?;
+    let socket = if addr.is_ipv4() {
+        UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?
+    } else {
+        UdpSocket::bind((Ipv6Addr::UNSPECIFIED, 0)).await?
+    };
+    socket.connect(addr).await?;
+    Ok(socket)
+}
+
+fn rand_u32() -> u32 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .subsec_nanos()
+        ^ std::process::id()
+}
+
+/// Decodes chrony's 32-bit floating point format: a 7-bit exponent and a
+/// 25-bit coefficient, both two's complement.
+fn chrony_float(bytes: &[u8]) -> f64 {
+    let x = u32::from_be_bytes(bytes.try_into().unwrap());
+    let exp = (x as i32 >> 25) - 25;
+    let coef = ((x << 7) as i32) >> 7;
+    coef as f64 * 2f64.powi(exp)
+}
+
+fn chrony_request(sequence: u32) -> Vec<u8> {
+    // Requests are padded to the length of their reply, against amplification.
+    let mut request = vec![0u8; CHRONY_REPLY_HEADER_LEN + CHRONY_TRACKING_LEN];
+    request[0] = CHRONY_PROTO_VERSION;
+    request[1] = CHRONY_PKT_TYPE_REQUEST;
+    request[4..6].copy_from_slice(&CHRONY_REQ_TRACKING.to_be_bytes());
+    request[8..12].copy_from_slice(&sequence.to_be_bytes());
+    request
+}
+
+fn parse_chrony_tracking(reply: &[u8], sequence: u32) -> anyhow::Result<DaemonStatus> {
+    if reply.len() < CHRONY_REPLY_HEADER_LEN + CHRONY_TRACKING_LEN
+        || reply[0] != CHRONY_PROTO_VERSION
+        || reply[1] != CHRONY_PKT_TYPE_REPLY
+        || u16::from_be_bytes([reply[4], reply[5]]) != CHRONY_REQ_TRACKING
+        || u32::from_be_bytes(reply[16..20].try_into().unwrap()) != sequence
+    {
+        anyhow::bail!("Malformed chrony reply");
+    }
+    let status = u16::from_be_bytes([reply[8], reply[9]]);
+    if status != 0 {
+        anyhow::bail!("chrony returned status {status}");
+    }
+    if u16::from_be_bytes([reply[6], reply[7]]) != CHRONY_RPY_TRACKING {
+        anyhow::bail!("Unexpected chrony reply type");
+    }
+
+    // RPY_Tracking in chrony's candm.h.
+    let data = &reply[CHRONY_REPLY_HEADER_LEN..];
+    let ref_id = u32::from_be_bytes(data[0..4].try_into().unwrap());
+    let family = u16::from_be_bytes([data[20], data[21]]);
+    let reference_id = match family {
+        CHRONY_IPADDR_INET4 => {
+            Ipv4Addr::from(u32::from_be_bytes(data[4..8].try_into().unwrap())).to_string()
+        }
+        CHRONY_IPADDR_INET6 => {
+            Ipv6Addr::from(<[u8; 16]>::try_from(&data[4..20]).unwrap()).to_string()
+        }
+        _ => reference_id_string(ref_id),
+    };
+    Ok(DaemonStatus {
+        daemon: "chrony",
+        reference_id,
+        stratum: u16::from_be_bytes([data[24], data[25]]),
+        root_delay: chrony_float(&data[64..68]),
+        root_dispersion: chrony_float(&data[68..72]),
+        last_offset: chrony_float(&data[44..48]),
+    })
+}
+
+/// Formats a reference ID like chronyc: as ASCII for reference clocks, as
+/// hex otherwise.
+fn reference_id_string(ref_id: u32) -> String {
+    let bytes = ref_id.to_be_bytes();
+    let text = bytes.split(|&b| b == 0).next().unwrap_or_default();
+    if !text.is_empty() && text.iter().all(|b| b.is_ascii_graphic()) {
+        String::from_utf8_lossy(text).into_owned()
+    } else {
+        format!("{ref_id:08X}")
+    }
+}
+
+async fn query_chrony(transport: &ChronyTransport, sequence: u32) -> anyhow::Result<DaemonStatus> {
+    let request = chrony_request(sequence);
+    let mut reply = [0u8; 1024];
+    loop {
+        let len = match transport {
+            ChronyTransport::Unix { socket, .. } => {
+                socket.send(&request).await?;
+                socket.recv(&mut reply).await?
+            }
+            ChronyTransport::Udp(socket) => {
+                socket.send(&request).await?;
+                socket.recv(&mut reply).await?
+            }
+        };
+        // Skip late replies to earlier, timed out requests.
+        if len >= 20 && u32::from_be_bytes(reply[16..20].try_into().unwrap()) != sequence {
+            continue;
+        }
+        return parse_chrony_tracking(&reply[..len], sequence);
+    }
+}
+
+fn mode6_request(sequence: u16) -> [u8; MODE6_HEADER_LEN] {
+    let mut request = [0u8; MODE6_HEADER_LEN];
+    request[0] = (2 << 3) | 6;
+    request[1] = MODE6_READVAR;
+    request[2..4].copy_from_slice(&sequence.to_be_bytes());
+    request
+}
+
+/// Splits a mode 6 variable list into names and values, unquoting values.
+fn parse_variables(text: &str) -> BTreeMap<String, String> {
+    let mut vars = BTreeMap::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        let mut in_quotes = false;
+        let end = rest
+            .char_indices()
+            .find(|&(_, c)| {
+                if c == '"' {
+                    in_quotes = !in_quotes;
+                }
+                c == ',' && !in_quotes
+            })
+            .map_or(rest.len(), |(i, _)| i);
+        let (item, tail) = rest.split_at(end);
+        rest = tail.strip_prefix(',').unwrap_or(tail);
+        if let Some((name, value)) = item.split_once('=') {
+            vars.insert(
+                name.trim().to_string(),
+                value.trim().trim_matches('"').to_string(),
+            );
+        }
+    }
+    vars
+}
+
+async fn query_ntpd(socket: &UdpSocket, sequence: u16) -> anyhow::Result<DaemonStatus> {
+    socket.send(&mode6_request(sequence)).await?;
+
+    // Responses may be split into fragments, which can arrive in any order.
+    let mut fragments = BTreeMap::new();
+    let mut total = None;
+    let mut buf = [0u8; 2048];
+    loop {
+        let len = socket.recv(&mut buf).await?;
+        let msg = &buf[..len];
+        if len < MODE6_HEADER_LEN
+            || msg[1] & 0x1f != MODE6_READVAR
+            || msg[1] & MODE6_RESPONSE == 0
+            || u16::from_be_bytes([msg[2], msg[3]]) != sequence
+        {
+            continue;
+        }
+        if msg[1] & MODE6_ERROR != 0 {
+            anyhow::bail!("ntpd returned error {}", msg[4]);
+        }
+        let offset = u16::from_be_bytes([msg[8], msg[9]]) as usize;
+        let count = u16::from_be_bytes([msg[10], msg[11]]) as usize;
+        let data = msg
+            .get(MODE6_HEADER_LEN..MODE6_HEADER_LEN + count)
+            .context("Truncated ntpd response")?;
+        if msg[1] & MODE6_MORE == 0 {
+            total = Some(offset + count);
+        }
+        fragments.insert(offset, data.to_vec());
+
+        let Some(total) = total else {
+            continue;
+        };
+        let mut text = Vec::with_capacity(total);
+        for (&offset, data) in &fragments {
+            if offset != text.len() {
+                break;
+            }
+            text.extend_from_slice(data);
+        }
+        if text.len() == total {
+            return parse_ntpd_variables(&String::from_utf8_lossy(&text));
+        }
+    }
+}
+
+fn parse_ntpd_variables(text: &str) -> anyhow::Result<DaemonStatus> {
+    let vars = parse_variables(text);
+    let get = |name: &str| {
+        vars.get(name)
+            .with_context(|| format!("ntpd did not report {name}"))
+    };
+    // ntpd reports times in milliseconds.
+    let millis = |name: &str| -> anyhow::Result<f64> {
+        Ok(get(name)?
+            .parse::<f64>()
+            .with_context(|| format!("Invalid {name}"))?
+            / 1e3)
+    };
+    Ok(DaemonStatus {
+        daemon: "ntpd",
+        reference_id: get("refid")?.clone(),
+        stratum: get("stratum")?.parse().context("Invalid stratum")?,
+        root_delay: millis("rootdelay")?,
+        root_dispersion: millis("rootdisp")?,
+        last_offset: millis("offset")?,
+    })
+}
+
+static STATUS: Mutex<Option<DaemonStatus>> = Mutex::new(None);
+
+/// The daemon's status as of the last successful poll.
+pub(crate) fn status() -> Option<DaemonStatus> {
+    STATUS.lock().unwrap().clone()
+}
+
+/// Polls the daemon in the background, keeping `status()` up to date.
+pub(crate) fn spawn(mut daemon: Daemon) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(POLL_INTERVAL);
+        loop {
+            interval.tick().await;
+            let status = match daemon.query().await {
+                Ok(status) => Some(status),
+                Err(e) => {
+                    tracing::warn!("Failed to query time daemon: {e:#}");
+                    None
+                }
+            };
+            *STATUS.lock().unwrap() = status;
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::{UdpSocket, UnixDatagram};
+
+    use super::{
+        CHRONY_REPLY_HEADER_LEN, CHRONY_REQ_TRACKING, CHRONY_RPY_TRACKING, CHRONY_TRACKING_LEN,
+        Daemon, DaemonStatus,
+    };
+
+    /// Encodes chrony's floating point format, for values of moderate size.
+    fn chrony_float(x: f64) -> [u8; 4] {
+        let exp = x.abs().log2().floor() as i32 + 1 - 24;
+        let coef = (x / 2f64.powi(exp)).round() as i32;
+        let raw = (((exp + 25) as u32) << 25) | (coef as u32 & 0x01ff_ffff);
+        raw.to_be_bytes()
+    }
+
+    #[tokio::test]
+    async fn test_chrony_tracking() {
+        let dir = std::env::temp_dir().join(format!("foxtime-chrony-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let server_path = dir.join("chronyd.sock");
+        std::fs::remove_file(&server_path).ok();
+        let server = UnixDatagram::bind(&server_path).unwrap();
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 1024];
+            let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, CHRONY_REPLY_HEADER_LEN + CHRONY_TRACKING_LEN);
+            assert_eq!(&buf[4..6], &CHRONY_REQ_TRACKING.to_be_bytes());
+
+            let mut reply = vec![0u8; CHRONY_REPLY_HEADER_LEN + CHRONY_TRACKING_LEN];
+            reply[0] = 6;
+            reply[1] = 2;
+            reply[4..6].copy_from_slice(&CHRONY_REQ_TRACKING.to_be_bytes());
+            reply[6..8].copy_from_slice(&CHRONY_RPY_TRACKING.to_be_bytes());
+            reply[16..20].copy_from_slice(&buf[8..12]);
+            let data = &mut reply[CHRONY_REPLY_HEADER_LEN..];
+            data[0..4].copy_from_slice(b"GPS\0");
+            data[24..26].copy_from_slice(&1u16.to_be_bytes());
+            data[44..48].copy_from_slice(&chrony_float(-0.000_012_5));
+            // Field offsets from RPY_Tracking in chrony's candm.h.
+            data[64..68].copy_from_slice(&chrony_float(0.000_25));
+            data[68..72].copy_from_slice(&chrony_float(0.001_5));
+            // last_update_interval, which must not be mistaken for either.
+            data[72..76].copy_from_slice(&chrony_float(64.0));
+            server
+                .send_to(&reply, peer.as_pathname().unwrap())
+                .await
+                .unwrap();
+        });
+
+        let mut daemon = Daemon::chrony(server_path.to_str().unwrap()).await.unwrap();
+        let status = daemon.query().await.unwrap();
+        drop(daemon);
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(status.daemon, "chrony");
+        assert_eq!(status.reference_id, "GPS");
+        assert_eq!(status.stratum, 1);
+        assert!((status.last_offset + 0.000_012_5).abs() < 1e-9);
+        assert!((status.root_delay - 0.000_25).abs() < 1e-9);
+        assert!((status.root_dispersion - 0.001_5).abs() < 1e-9);
+    }
+
+    #[tokio::test]
+    async fn test_ntpd_readvar() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let text = b"version=\"ntpd 4.2.8p15, with spaces, and commas\", stratum=2, \
+            refid=192.0.2.1, rootdelay=12.345, rootdisp=3.210, offset=-0.250, sys_jitter=0.1";
+
+        tokio::spawn(async move {
+            let mut buf = [0u8; 64];
+            let (len, peer) = server.recv_from(&mut buf).await.unwrap();
+            assert_eq!(len, 12);
+            // Answer in two fragments, the last one first.
+            let split = 40;
+            for (offset, chunk, more) in [(split, &text[split..], false), (0, &text[..split], true)]
+            {
+                let mut msg = buf[..12].to_vec();
+                msg[1] = 0x80 | if more { 0x20 } else { 0 } | 2;
+                msg[8..10].copy_from_slice(&(offset as u16).to_be_bytes());
+                msg[10..12].copy_from_slice(&(chunk.len() as u16).to_be_bytes());
+                msg.extend_from_slice(chunk);
+                msg.resize(msg.len().next_multiple_of(4), 0);
+                server.send_to(&msg, peer).await.unwrap();
+            }
+        });
+
+        let mut daemon = Daemon::ntpd(&addr).await.unwrap();
+        assert_eq!(
+            daemon.query().await.unwrap(),
+            DaemonStatus {
+                daemon: "ntpd",
+                reference_id: "192.0.2.1".to_string(),
+                stratum: 2,
+                root_delay: 0.012345,
+                root_dispersion: 0.00321,
+                last_offset: -0.00025,
+            }
+        );
+    }
+}
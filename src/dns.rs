@@ -0,0 +1,344 @@
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream, UdpSocket};
+
+const TYPE_TXT: u16 = 16;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_ANY: u16 = 255;
+
+const RCODE_NOERROR: u8 = 0;
+const RCODE_FORMERR: u8 = 1;
+const RCODE_SERVFAIL: u8 = 2;
+const RCODE_NXDOMAIN: u8 = 3;
+const RCODE_NOTIMP: u8 = 4;
+const RCODE_REFUSED: u8 = 5;
+
+const HEADER_LEN: usize = 12;
+const MAX_UDP_QUERY: usize = 512;
+
+/// How long a TCP connection may sit idle, or take to send a query or read
+/// its answer, before it is closed (RFC 7766, section 6.2.3).
+const TCP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// The single name foxtime is authoritative for. Queries for the name itself
+/// or for `<nonce>.<name>` are answered with a TXT record carrying the current
+/// server time and, when present, the nonce label echoed back.
+#[derive(Debug)]
+pub(crate) struct Zone {
+    labels: Vec<Vec<u8>>,
+}
+
+impl Zone {
+    pub(crate) fn new(name: &str) -> anyhow::Result<Self> {
+        let labels: Vec<Vec<u8>> = name
+            .trim_end_matches('.')
+            .split('.')
+            .map(|label| label.to_ascii_lowercase().into_bytes())
+            .collect();
+        if labels.iter().any(|l| l.is_empty() || l.len() > 63) {
+            anyhow::bail!("Invalid DNS name: {name}");
+        }
+        if labels.iter().map(|l| l.len() + 1).sum::<usize>() + 1 > 255 {
+            anyhow::bail!("DNS name too long: {name}");
+        }
+        Ok(Self { labels })
+    }
+
+    fn respond(&self, query: &[u8]) -> Option<Vec<u8>> {
+        if query.len() < HEADER_LEN || query[2] & 0x80 != 0 {
+            // Too short to even echo the ID, or not a query at all.
+            return None;
+        }
+
+        let opcode = (query[2] >> 3) & 0x0f;
+        let qdcount = u16::from_be_bytes([query[4], query[5]]);
+        if opcode != 0 {
+            return Some(header(query, false, RCODE_NOTIMP, 0, 0));
+        }
+        if qdcount != 1 {
+            return Some(header(query, false, RCODE_FORMERR, 0, 0));
+        }
+
+        let Some((labels, question_end)) = parse_name(query, HEADER_LEN) else {
+            return Some(header(query, false, RCODE_FORMERR, 0, 0));
+        };
+        if query.len() < question_end + 4 {
+            return Some(header(query, false, RCODE_FORMERR, 0, 0));
+        }
+        let qtype = u16::from_be_bytes([query[question_end], query[question_end + 1]]);
+        let qclass = u16::from_be_bytes([query[question_end + 2], query[question_end + 3]]);
+        let question = &query[HEADER_LEN..question_end + 4];
+
+        let Some(prefix_len) = labels.len().checked_sub(self.labels.len()) else {
+            return Some(with_question(query, false, RCODE_REFUSED, question));
+        };
+        if !labels[prefix_len..]
+            .iter()
+            .zip(&self.labels)
+            .all(|(a, b)| a.eq_ignore_ascii_case(b))
+        {
+            return Some(with_question(query, false, RCODE_REFUSED, question));
+        }
+        let nonce = match prefix_len {
+            0 => None,
+            1 => Some(labels[0]),
+            _ => return Some(with_question(query, true, RCODE_NXDOMAIN, question)),
+        };
+        if (qclass != CLASS_IN && qclass != CLASS_ANY) || (qtype != TYPE_TXT && qtype != TYPE_ANY) {
+            return Some(with_question(query, true, RCODE_NOERROR, question));
+        }
+
+        let Ok(ts) = crate::clock::now().duration_since(UNIX_EPOCH) else {
+            return Some(with_question(query, true, RCODE_SERVFAIL, question));
+        };
+        let timestamp = format!("t={}", ts.as_secs_f64());
+        let mut rdata = Vec::with_capacity(2 + timestamp.len() + nonce.map_or(0, |n| n.len() + 2));
+        push_character_string(&mut rdata, timestamp.as_bytes());
+        if let Some(nonce) = nonce {
+            push_character_string(&mut rdata, &[b"n=", nonce].concat());
+        }
+
+        let mut response = header(query, true, RCODE_NOERROR, 1, 1);
+        response.extend_from_slice(question);
+        // Compression pointer back to the question name.
+        response.extend_from_slice(&[0xc0, HEADER_LEN as u8]);
+        response.extend_from_slice(&TYPE_TXT.to_be_bytes());
+        response.extend_from_slice(&CLASS_IN.to_be_bytes());
+        response.extend_from_slice(&0u32.to_be_bytes());
+        response.extend_from_slice(&(rdata.len() as u16).to_be_bytes());
+        response.extend_from_slice(&rdata);
+        Some(response)
+    }
+}
+
+/// Parses an uncompressed name starting at `offset`, returning its labels and
+/// the offset just past the terminating zero-length label.
+fn parse_name(msg: &[u8], mut offset: usize) -> Option<(Vec<&[u8]>, usize)> {
+    let mut labels = Vec::new();
+    let mut total = 1;
+    loop {
+        let len = *msg.get(offset)? as usize;
+        offset += 1;
+        if len == 0 {
+            return Some((labels, offset));
+        }
+        // Queries have nothing earlier to point at, so compression is invalid.
+        if len > 63 {
+            return None;
+        }
+        total += len + 1;
+        if total > 255 {
+            return None;
+        }
+        labels.push(msg.get(offset..offset + len)?);
+        offset += len;
+    }
+}
+
+fn header(query: &[u8], authoritative: bool, rcode: u8, qdcount: u16, ancount: u16) -> Vec<u8> {
+    let mut response = Vec::with_capacity(MAX_UDP_QUERY);
+    response.extend_from_slice(&query[..2]);
+    // QR, echo opcode and RD, set AA when answering for our zone.
+    response.push(0x80 | (query[2] & 0x79) | if authoritative { 0x04 } else { 0 });
+    response.push(rcode);
+    response.extend_from_slice(&qdcount.to_be_bytes());
+    response.extend_from_slice(&ancount.to_be_bytes());
+    response.extend_from_slice(&[0, 0, 0, 0]);
+    response
+}
+
+fn with_question(query: &[u8], authoritative: bool, rcode: u8, question: &[u8]) -> Vec<u8> {
+    let mut response = header(query, authoritative, rcode, 1, 0);
+    response.extend_from_slice(question);
+    response
+}
+
+fn push_character_string(rdata: &mut Vec<u8>, s: &[u8]) {
+    rdata.push(s.len() as u8);
+    rdata.extend_from_slice(s);
+}
+
+async fn serve_udp(zone: Arc<Zone>, socket: UdpSocket) {
+    let mut buf = [0u8; MAX_UDP_QUERY];
+    loop {
+        match socket.recv_from(&mut buf).await {
+            Ok((len, peer)) => {
+                if let Some(response) = zone.respond(&buf[..len])
+                    && let Err(e) = socket.send_to(&response, peer).await
+                {
+                    tracing::debug!("Failed to send DNS response to {peer}: {e:?}");
+                }
+            }
+            Err(e) => {
+                tracing::debug!("Failed to receive DNS query: {e:?}");
+            }
+        }
+    }
+}
+
+async fn serve_tcp_connection(zone: Arc<Zone>, mut stream: TcpStream) -> std::io::Result<()> {
+    loop {
+        let len = match tokio::time::timeout(TCP_TIMEOUT, stream.read_u16()).await {
+            Ok(Ok(len)) => len as usize,
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::UnexpectedEof => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            // Idle.
+            Err(_) => return Ok(()),
+        };
+        let exchange = async {
+            let mut query = vec![0u8; len];
+            stream.read_exact(&mut query).await?;
+            let Some(response) = zone.respond(&query) else {
+                return Ok(false);
+            };
+            let mut framed = Vec::with_capacity(2 + response.len());
+            framed.extend_from_slice(&(response.len() as u16).to_be_bytes());
+            framed.extend_from_slice(&response);
+            stream.write_all(&framed).await?;
+            Ok(true)
+        };
+        match tokio::time::timeout(TCP_TIMEOUT, exchange).await {
+            Ok(Ok(true)) => {}
+            Ok(Ok(false)) => return Ok(()),
+            Ok(Err(e)) => return Err(e),
+            Err(e) => return Err(e.into()),
+        }
+    }
+}
+
+async fn serve_tcp(zone: Arc<Zone>, listener: TcpListener) {
+    loop {
+        match listener.accept().await {
+            Ok((stream, peer)) => {
+                let zone = zone.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = serve_tcp_connection(zone, stream).await {
+                        tracing::debug!("DNS connection from {peer} failed: {e:?}");
+                    }
+                });
+            }
+            Err(e) => {
+                tracing::debug!("Failed to accept DNS connection: {e:?}");
+            }
+        }
+    }
+}
+
+/// Binds UDP and TCP sockets on `addr` and serves the zone on both in the
+/// background. When `addr` has port 0 the TCP socket reuses the port the OS
+/// picked for UDP. Returns the bound address.
+pub(crate) async fn spawn(zone: Arc<Zone>, addr: SocketAddr) -> anyhow::Result<SocketAddr> {
+    let udp = UdpSocket::bind(addr)
+        .await
+        .with_context(|| format!("Bind DNS UDP socket {addr}"))?;
+    let local_addr = udp.local_addr()?;
+    let tcp = TcpListener::bind(local_addr)
+        .await
+        .with_context(|| format!("Bind DNS TCP socket {local_addr}"))?;
+    tracing::info!("Serving DNS TXT time on {local_addr}");
+    tokio::spawn(serve_udp(zone.clone(), udp));
+    tokio::spawn(serve_tcp(zone, tcp));
+    Ok(local_addr)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{Zone, spawn};
+
+    fn query(id: u16, name: &str) -> Vec<u8> {
+        let mut query = Vec::new();
+        query.extend_from_slice(&id.to_be_bytes());
+        query.extend_from_slice(&[0x01, 0x00, 0x00, 0x01, 0, 0, 0, 0, 0, 0]);
+        for label in name.split('.') {
+            query.push(label.len() as u8);
+            query.extend_from_slice(label.as_bytes());
+        }
+        query.extend_from_slice(&[0, 0x00, 0x10, 0x00, 0x01]);
+        query
+    }
+
+    /// Returns the TXT strings of the single answer in `response`.
+    fn txt_strings(query: &[u8], response: &[u8]) -> Vec<String> {
+        assert_eq!(response[..2], query[..2], "response ID mismatch");
+        assert_eq!(response[2] & 0x84, 0x84, "response not authoritative");
+        assert_eq!(response[3] & 0x0f, 0, "unexpected rcode");
+        assert_eq!(response[6..8], [0, 1], "expected one answer");
+        let answer = &response[query.len()..];
+        assert_eq!(answer[..4], [0xc0, 0x0c, 0x00, 0x10]);
+        assert_eq!(answer[6..10], [0, 0, 0, 0], "TTL is not zero");
+        let mut rdata = &answer[12..];
+        let mut strings = Vec::new();
+        while let Some((&len, rest)) = rdata.split_first() {
+            strings.push(String::from_utf8(rest[..len as usize].to_vec()).unwrap());
+            rdata = &rest[len as usize..];
+        }
+        strings
+    }
+
+    fn parse_server_time(strings: &[String]) -> f64 {
+        strings[0]
+            .strip_prefix("t=")
+            .expect("missing timestamp")
+            .parse()
+            .expect("timestamp is not a valid float")
+    }
+
+    fn now() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64()
+    }
+
+    #[tokio::test]
+    async fn test_time_dns() {
+        let zone = Arc::new(Zone::new("time.example.com").unwrap());
+        let addr = spawn(zone, "127.0.0.1:0".parse().unwrap()).await.unwrap();
+
+        let udp = tokio::net::UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let q = query(0x1234, "Nonce-42.TIME.example.com");
+        let t1 = now();
+        udp.send_to(&q, addr).await.unwrap();
+        let mut buf = [0u8; 512];
+        let len = udp.recv(&mut buf).await.unwrap();
+        let t2 = now();
+        let strings = txt_strings(&q, &buf[..len]);
+        let server_time = parse_server_time(&strings);
+        assert_eq!(strings[1], "n=Nonce-42");
+        assert!(
+            server_time >= t1,
+            "server time {server_time} is before t1 {t1}"
+        );
+        assert!(
+            server_time <= t2,
+            "server time {server_time} is after t2 {t2}"
+        );
+
+        let mut tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+        let q = query(0x5678, "time.example.com");
+        tcp.write_u16(q.len() as u16).await.unwrap();
+        tcp.write_all(&q).await.unwrap();
+        let len = tcp.read_u16().await.unwrap();
+        let mut response = vec![0u8; len as usize];
+        tcp.read_exact(&mut response).await.unwrap();
+        let strings = txt_strings(&q, &response);
+        assert_eq!(strings.len(), 1, "unexpected nonce in {strings:?}");
+        parse_server_time(&strings);
+
+        let q = query(0x9abc, "example.org");
+        udp.send_to(&q, addr).await.unwrap();
+        let len = udp.recv(&mut buf).await.unwrap();
+        assert_eq!(buf[3] & 0x0f, 5, "foreign name was not refused");
+        assert_eq!(buf[6..8], [0, 0]);
+        assert_eq!(len, q.len());
+    }
+}
@@ -0,0 +1,167 @@
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::Context;
+
+/// Largest offset or step, in seconds: about 30 years either way.
+const MAX_SHIFT: f64 = 1e9;
+
+/// Largest frequency error, in parts per million: twice or zero speed.
+const MAX_SKEW_PPM: f64 = 1e6;
+
+/// A time step applied once, some time after startup.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Step {
+    /// Time since startup at which the step happens.
+    pub(crate) after: Duration,
+    /// Amount added to the served time from then on, in seconds.
+    pub(crate) step: f64,
+}
+
+/// A deliberately wrong clock for testing clients: the served time is shifted
+/// by a constant offset, drifts by a frequency error and jumps at scripted
+/// instants.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct FakeClock {
+    /// Constant offset, in seconds.
+    pub(crate) offset: f64,
+    /// Frequency error, in parts per million of the time since startup.
+    pub(crate) skew_ppm: f64,
+    pub(crate) steps: Vec<Step>,
+}
+
+impl FakeClock {
+    /// Total shift at `elapsed` since startup, in seconds.
+    fn shift(&self, elapsed: Duration) -> f64 {
+        let steps: f64 = self
+            .steps
+            .iter()
+            .filter(|s| s.after <= elapsed)
+            .map(|s| s.step)
+            .sum();
+        self.offset + elapsed.as_secs_f64() * self.skew_ppm * 1e-6 + steps
+    }
+}
+
+fn parse_bounded(s: &str, max: f64) -> anyhow::Result<f64> {
+    let value: f64 = s.parse().with_context(|| format!("Invalid number: {s}"))?;
+    if !value.is_finite() || value.abs() > max {
+        anyhow::bail!("Must be between -{max} and {max}");
+    }
+    Ok(value)
+}
+
+/// Parses an offset in seconds for `--fake-offset`.
+pub(crate) fn parse_offset(s: &str) -> Result<f64, String> {
+    parse_bounded(s, MAX_SHIFT).map_err(|e| format!("{e:#}"))
+}
+
+/// Parses a frequency error for `--fake-skew-ppm`.
+pub(crate) fn parse_skew_ppm(s: &str) -> Result<f64, String> {
+    parse_bounded(s, MAX_SKEW_PPM).map_err(|e| format!("{e:#}"))
+}
+
+/// Parses a step script: one `<seconds after startup> <step in seconds>` pair
+/// per line, such as `30 +1.5`. Blank lines and lines starting with `#` are
+/// ignored.
+pub(crate) fn parse_script(script: &str) -> anyhow::Result<Vec<Step>> {
+    let mut steps = Vec::new();
+    for (number, line) in script.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let parse = || -> anyhow::Result<Step> {
+            let mut fields = line.split_whitespace();
+            let (Some(after), Some(step), None) = (fields.next(), fields.next(), fields.next())
+            else {
+                anyhow::bail!("Expected two fields");
+            };
+            let after = Duration::try_from_secs_f64(after.parse()?)?;
+            let step = parse_bounded(step, MAX_SHIFT)?;
+            Ok(Step { after, step })
+        };
+        steps.push(parse().with_context(|| format!("Line {}: {line}", number + 1))?);
+    }
+    Ok(steps)
+}
+
+struct Active {
+    clock: FakeClock,
+    start: Instant,
+}
+
+static ACTIVE: OnceLock<Active> = OnceLock::new();
+
+/// Starts serving fake time. Elapsed time for the skew and the steps counts
+/// from this call.
+pub(crate) fn set_fake_clock(clock: FakeClock) {
+    ACTIVE
+        .set(Active {
+            clock,
+            start: Instant::now(),
+        })
+        .ok();
+}
+
+/// Shifts a timestamp by the fake clock, if one is set.
+pub(crate) fn apply(time: SystemTime) -> SystemTime {
+    let Some(active) = ACTIVE.get() else {
+        return time;
+    };
+    let shift = active.clock.shift(active.start.elapsed());
+    let shifted = Duration::try_from_secs_f64(shift.abs())
+        .ok()
+        .and_then(|amount| {
+            if shift >= 0.0 {
+                time.checked_add(amount)
+            } else {
+                time.checked_sub(amount)
+            }
+        });
+    shifted.unwrap_or(time)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::{FakeClock, Step, parse_offset, parse_script, parse_skew_ppm};
+
+    #[test]
+    fn test_fake_clock() {
+        let steps = parse_script("# warm up\n\n10 +2.5\n  20 -1\n").unwrap();
+        assert_eq!(
+            steps,
+            [
+                Step {
+                    after: Duration::from_secs(10),
+                    step: 2.5
+                },
+                Step {
+                    after: Duration::from_secs(20),
+                    step: -1.0
+                },
+            ]
+        );
+        assert!(parse_script("10").is_err());
+        assert!(parse_script("10 1 2").is_err());
+        assert!(parse_script("-1 1").is_err());
+        assert!(parse_script("10 inf").is_err());
+        assert_eq!(parse_offset("-3.5"), Ok(-3.5));
+        assert!(parse_offset("NaN").is_err());
+        assert!(parse_offset("1e300").is_err());
+        assert!(parse_skew_ppm("inf").is_err());
+
+        let clock = FakeClock {
+            offset: -3.0,
+            skew_ppm: 100.0,
+            steps,
+        };
+        let shift = |secs| clock.shift(Duration::from_secs(secs));
+        assert_eq!(shift(0), -3.0);
+        assert!((shift(5) - (-3.0 + 0.0005)).abs() < 1e-9);
+        assert!((shift(10) - (-0.5 + 0.001)).abs() < 1e-9);
+        assert!((shift(30) - (-1.5 + 0.003)).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,404 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use base64::Engine;
+use futures_util::{SinkExt, StreamExt};
+use reqwest_websocket::Upgrade;
+use serde::Serialize;
+use wtransport::tls::Sha256Digest;
+use wtransport::{ClientConfig, Endpoint};
+
+use crate::{clock, probe};
+
+/// Bit set in the flags byte of WS and WT time responses while degraded.
+pub(crate) const FLAG_DEGRADED: u8 = 0x01;
+
+/// Exchanges per measurement; the one with the shortest round trip is kept.
+const EXCHANGES: usize = 8;
+const MEASURE_TIMEOUT: Duration = Duration::from_secs(10);
+const EXCHANGE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub(crate) struct FederationConfig {
+    /// `ws://` or `wss://` URLs of peers' `/time-ws` endpoints, or `https://`
+    /// URLs of their `/time-wt` endpoints, optionally followed by
+    /// `#sha256=<base64>` to pin a self-signed certificate.
+    pub(crate) peers: Vec<String>,
+    /// Largest tolerated offset to a peer beyond the measurement uncertainty,
+    /// in seconds.
+    pub(crate) threshold: f64,
+    pub(crate) interval: Duration,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Measurement {
+    /// Peer time minus ours, in seconds.
+    offset: f64,
+    /// Round-trip time, in seconds.
+    delay: f64,
+    /// Whether the peer reports itself degraded.
+    degraded: bool,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub(crate) struct PeerStatus {
+    pub(crate) url: String,
+    /// Peer time minus ours, in seconds.
+    pub(crate) offset: Option<f64>,
+    /// Round-trip time of the best exchange, in seconds.
+    pub(crate) delay: Option<f64>,
+    pub(crate) degraded: Option<bool>,
+    pub(crate) error: Option<String>,
+}
+
+/// Parses a disagreement threshold in seconds for `--peer-threshold`.
+pub(crate) fn parse_threshold(s: &str) -> Result<f64, String> {
+    let parse = || -> anyhow::Result<f64> {
+        let threshold: f64 = s.parse().with_context(|| format!("Invalid number: {s}"))?;
+        if !threshold.is_finite() || threshold < 0.0 {
+            anyhow::bail!("Must be a non-negative number of seconds");
+        }
+        Ok(threshold)
+    };
+    parse().map_err(|e| format!("{e:#}"))
+}
+
+static DEGRADED: AtomicBool = AtomicBool::new(false);
+static PEERS: Mutex<Vec<PeerStatus>> = Mutex::new(Vec::new());
+
+/// Whether the peer majority disagrees with our time.
+pub(crate) fn degraded() -> bool {
+    DEGRADED.load(Ordering::Relaxed)
+}
+
+/// The peers as of the last round of measurements.
+pub(crate) fn peers() -> Vec<PeerStatus> {
+    PEERS.lock().unwrap().clone()
+}
+
+/// The flags byte appended to WS and WT time responses.
+pub(crate) fn flags() -> u8 {
+    if degraded() { FLAG_DEGRADED } else { 0 }
+}
+
+fn now() -> anyhow::Result<f64> {
+    Ok(clock::now().duration_since(UNIX_EPOCH)?.as_secs_f64())
+}
+
+fn keep_best(best: &mut Option<Measurement>, m: Measurement) {
+    if best.is_none_or(|b| m.delay < b.delay) {
+        *best = Some(m);
+    }
+}
+
+async fn measure_ws(url: &str) -> anyhow::Result<Measurement> {
+    let response = reqwest::Client::new()
+        .get(url)
+        .upgrade()
+        .send()
+        .await
+        .with_context(|| format!("Failed to connect to {url}"))?;
+    let mut websocket = response.into_websocket().await?;
+    let mut best = None;
+    for _ in 0..EXCHANGES {
+        let t1 = now()?;
+        websocket
+            .send(reqwest_websocket::Message::Binary(vec![0].into()))
+            .await?;
+        // Skip the clock probes of peers run with --probe-interval.
+        let bin = loop {
+            let message = websocket.next().await.context("WebSocket closed")??;
+            let reqwest_websocket::Message::Binary(bin) = message else {
+                anyhow::bail!("Unexpected WebSocket message type");
+            };
+            if !probe::is_request(&bin) {
+                break bin;
+            }
+        };
+        let t2 = now()?;
+        if bin.len() < 8 {
+            anyhow::bail!("Response too short: {} bytes", bin.len());
+        }
+        let peer_time = f64::from_le_bytes(bin[..8].try_into().unwrap());
+        keep_best(
+            &mut best,
+            Measurement {
+                offset: peer_time - (t1 + t2) / 2.0,
+                delay: t2 - t1,
+                degraded: bin.get(8).is_some_and(|f| f & FLAG_DEGRADED != 0),
+            },
+        );
+    }
+    websocket
+        .close(reqwest_websocket::CloseCode::Normal, None)
+        .await
+        .ok();
+    best.context("No exchanges")
+}
+
+async fn measure_wt(url: &str) -> anyhow::Result<Measurement> {
+    let (url, cert_hash) = match url.split_once("#sha256=") {
+        Some((url, hash)) => (url, Some(hash)),
+        None => (url, None),
+    };
+    let builder =
+        ClientConfig::builder().with_bind_config(wtransport::config::IpBindConfig::InAddrAnyDual);
+    let config = if let Some(hash) = cert_hash {
+        let hash = base64::engine::general_purpose::STANDARD
+            .decode(hash)
+            .context("Invalid base64 in certificate hash")?;
+        let hash = Sha256Digest::new(
+            hash.try_into()
+                .map_err(|_| anyhow::anyhow!("Certificate hash must be 32 bytes"))?,
+        );
+        builder.with_server_certificate_hashes([hash]).build()
+    } else {
+        builder.with_native_certs().build()
+    };
+    let session = Endpoint::client(config)?
+        .connect(url)
+        .await
+        .with_context(|| format!("Failed to connect to {url}"))?;
+
+    let mut best = None;
+    for _ in 0..EXCHANGES {
+        let t1 = now()?;
+        session.send_datagram(t1.to_le_bytes())?;
+        // Datagrams may be lost or reordered, so match the echoed send time.
+        let exchange = async {
+            loop {
+                let datagram = session.receive_datagram().await?;
+                let payload = datagram.payload();
+                if payload.len() >= 16 && payload[..8] == t1.to_le_bytes() {
+                    let peer_time = f64::from_le_bytes(payload[8..16].try_into().unwrap());
+                    let degraded = payload.get(16).is_some_and(|f| f & FLAG_DEGRADED != 0);
+                    return anyhow::Ok((peer_time, degraded));
+                }
+            }
+        };
+        let Ok(result) = tokio::time::timeout(EXCHANGE_TIMEOUT, exchange).await else {
+            continue;
+        };
+        let (peer_time, degraded) = result?;
+        let t2 = now()?;
+        keep_best(
+            &mut best,
+            Measurement {
+                offset: peer_time - (t1 + t2) / 2.0,
+                delay: t2 - t1,
+                degraded,
+            },
+        );
+    }
+    best.context("No datagrams answered")
+}
+
+async fn measure(url: &str) -> anyhow::Result<Measurement> {
+    tokio::time::timeout(MEASURE_TIMEOUT, async {
+        if url.starts_with("ws://") || url.starts_with("wss://") {
+            measure_ws(url).await
+        } else if url.starts_with("https://") {
+            measure_wt(url).await
+        } else {
+            anyhow::bail!("Peer URL must be ws://, wss:// or https://")
+        }
+    })
+    .await
+    .context("Timed out")?
+}
+
+/// Fewest peers that may outvote us: a lone peer cannot tell which of us is
+/// wrong.
+const MIN_VOTERS: usize = 2;
+
+/// Whether more than half of the peers that are not degraded themselves
+/// disagree with us by more than `threshold` beyond half the round trip. The
+/// voters must be at least `MIN_VOTERS` and a majority of the `peers`
+/// configured.
+fn disagrees_with_majority(measurements: &[Measurement], peers: usize, threshold: f64) -> bool {
+    let voters: Vec<_> = measurements.iter().filter(|m| !m.degraded).collect();
+    if voters.len() < MIN_VOTERS || voters.len() * 2 <= peers {
+        return false;
+    }
+    let disagreeing = voters
+        .iter()
+        .filter(|m| m.offset.abs() > threshold + m.delay / 2.0)
+        .count();
+    disagreeing * 2 > voters.len()
+}
+
+/// Measures the offset to every peer each `config.interval`, updating
+/// `peers()` and `degraded()`.
+pub(crate) fn spawn(config: FederationConfig) {
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(config.interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        loop {
+            interval.tick().await;
+            let results =
+                futures_util::future::join_all(config.peers.iter().map(|url| measure(url))).await;
+            let mut measurements = Vec::new();
+            let statuses = config
+                .peers
+                .iter()
+                .zip(results)
+                .map(|(url, result)| match result {
+                    Ok(m) => {
+                        measurements.push(m);
+                        PeerStatus {
+                            url: url.clone(),
+                            offset: Some(m.offset),
+                            delay: Some(m.delay),
+                            degraded: Some(m.degraded),
+                            error: None,
+                        }
+                    }
+                    Err(e) => {
+                        tracing::debug!("Failed to measure peer {url}: {e:#}");
+                        PeerStatus {
+                            url: url.clone(),
+                            offset: None,
+                            delay: None,
+                            degraded: None,
+                            error: Some(format!("{e:#}")),
+                        }
+                    }
+                })
+                .collect();
+            *PEERS.lock().unwrap() = statuses;
+
+            let degraded =
+                disagrees_with_majority(&measurements, config.peers.len(), config.threshold);
+            if DEGRADED.swap(degraded, Ordering::Relaxed) != degraded {
+                if degraded {
+                    tracing::warn!("Peer majority disagrees with our time, marking degraded");
+                } else {
+                    tracing::info!("Peer majority agrees with our time again");
+                }
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::conn::{Acceptor, TcpListener};
+    use salvo::prelude::*;
+
+    use std::time::UNIX_EPOCH;
+
+    use salvo::websocket::{Message, WebSocketUpgrade};
+
+    use super::{Measurement, disagrees_with_majority, measure, parse_threshold};
+    use crate::chaos::Transport;
+    use crate::clock;
+    use crate::probe::Prober;
+    use crate::router::router;
+
+    /// A WS time endpoint that probes its client before every time response,
+    /// as one run with --probe-interval may.
+    #[handler]
+    async fn probing_ws(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+        WebSocketUpgrade::new()
+            .upgrade(req, res, |mut ws| async move {
+                let prober = Prober::new(Transport::Ws, "127.0.0.1:1".to_string(), None);
+                while let Some(Ok(msg)) = ws.recv().await {
+                    if !msg.is_binary() {
+                        continue;
+                    }
+                    let server_ts = clock::now()
+                        .duration_since(UNIX_EPOCH)
+                        .unwrap()
+                        .as_secs_f64();
+                    let mut response = server_ts.to_le_bytes().to_vec();
+                    response.push(0);
+                    ws.send(Message::binary(prober.request())).await.unwrap();
+                    ws.send(Message::binary(response)).await.unwrap();
+                }
+            })
+            .await
+    }
+
+    #[tokio::test]
+    async fn test_federation() {
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let port = acceptor.holdings()[0]
+            .local_addr
+            .port()
+            .expect("could not get bound port");
+        let router = Router::new()
+            .push(Router::with_path("probing-ws").goal(probing_ws))
+            .push(router());
+        tokio::spawn(async move {
+            Server::new(acceptor).serve(router).await;
+        });
+
+        let peer = measure(&format!("ws://127.0.0.1:{port}/time-ws"))
+            .await
+            .unwrap();
+        assert!(
+            peer.delay >= 0.0 && peer.delay < 0.1,
+            "delay {}",
+            peer.delay
+        );
+        assert!(
+            peer.offset.abs() <= peer.delay / 2.0 + 0.001,
+            "offset {} to ourselves",
+            peer.offset
+        );
+        assert!(!peer.degraded);
+
+        // Probes are not taken for time responses.
+        let peer = measure(&format!("ws://127.0.0.1:{port}/probing-ws"))
+            .await
+            .unwrap();
+        assert!(
+            peer.offset.abs() <= peer.delay / 2.0 + 0.001,
+            "offset {} to a probing peer",
+            peer.offset
+        );
+
+        assert_eq!(parse_threshold("0.05"), Ok(0.05));
+        assert_eq!(parse_threshold("0"), Ok(0.0));
+        assert!(parse_threshold("-0.05").is_err());
+        assert!(parse_threshold("NaN").is_err());
+        assert!(parse_threshold("inf").is_err());
+
+        let peer = |offset, degraded| Measurement {
+            offset,
+            delay: 0.01,
+            degraded,
+        };
+        assert!(!disagrees_with_majority(&[], 3, 0.05));
+        assert!(!disagrees_with_majority(
+            &[peer(0.0, false), peer(0.01, false), peer(2.0, false)],
+            3,
+            0.05
+        ));
+        assert!(disagrees_with_majority(
+            &[peer(1.0, false), peer(-1.0, false), peer(0.0, false)],
+            3,
+            0.05
+        ));
+        // Degraded peers do not vote.
+        assert!(!disagrees_with_majority(
+            &[peer(1.0, true), peer(1.0, true), peer(0.0, false)],
+            3,
+            0.05
+        ));
+        // A lone peer, or a minority of the peers, cannot outvote us.
+        assert!(!disagrees_with_majority(&[peer(1.0, false)], 1, 0.05));
+        assert!(!disagrees_with_majority(
+            &[peer(1.0, false), peer(1.0, false)],
+            5,
+            0.05
+        ));
+        assert!(disagrees_with_majority(
+            &[peer(1.0, false), peer(1.0, false)],
+            3,
+            0.05
+        ));
+    }
+}
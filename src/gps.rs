@@ -0,0 +1,538 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read};
+use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use nix::errno::Errno;
+use nix::sys::termios::{self, BaudRate, SetArg};
+
+/// Number of samples the offset is the median of.
+const FILTER_LEN: usize = 16;
+
+/// Error assumed for offsets from NMEA sentences alone, whose timing varies
+/// with receiver firmware and serial buffering.
+const NMEA_ERROR: Duration = Duration::from_millis(20);
+
+/// Error assumed for offsets from PPS edges timestamped by the kernel.
+const PPS_ERROR: Duration = Duration::from_micros(1);
+
+/// How long an NMEA time stays usable for numbering PPS edges.
+const NMEA_MAX_AGE: Duration = Duration::from_secs(10);
+
+const PPS_TIMEOUT: Duration = Duration::from_secs(3);
+const REOPEN_DELAY: Duration = Duration::from_secs(5);
+
+const NANOS_PER_SEC: i64 = 1_000_000_000;
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub(crate) enum PpsSource {
+    /// A Linux PPS device, e.g. `/dev/pps0`.
+    Device(String),
+    /// A line on a GPIO character device, e.g. `/dev/gpiochip0` line 18.
+    Gpio { chip: String, line: u32 },
+}
+
+#[derive(Clone, Debug)]
+pub(crate) struct GpsConfig {
+    /// Serial device or pseudo-terminal the receiver writes NMEA to.
+    pub(crate) device: String,
+    pub(crate) baud: u32,
+    /// Time from the start of a GPS second to the receipt of its first NMEA
+    /// sentence, in seconds.
+    pub(crate) delay: f64,
+    pub(crate) pps: Option<PpsSource>,
+}
+
+/// Parses a `CHIP:LINE` GPIO line specification.
+pub(crate) fn parse_gpio_line(s: &str) -> Result<PpsSource, String> {
+    let (chip, line) = s
+        .rsplit_once(':')
+        .ok_or_else(|| "expected CHIP:LINE".to_string())?;
+    Ok(PpsSource::Gpio {
+        chip: chip.to_string(),
+        line: line.parse().map_err(|e| format!("invalid line: {e}"))?,
+    })
+}
+
+fn baud_rate(baud: u32) -> anyhow::Result<BaudRate> {
+    Ok(match baud {
+        4800 => BaudRate::B4800,
+        9600 => BaudRate::B9600,
+        19200 => BaudRate::B19200,
+        38400 => BaudRate::B38400,
+        57600 => BaudRate::B57600,
+        115200 => BaudRate::B115200,
+        230400 => BaudRate::B230400,
+        _ => anyhow::bail!("Unsupported baud rate {baud}"),
+    })
+}
+
+fn open_serial(path: &str, baud: BaudRate) -> anyhow::Result<File> {
+    let file = File::open(path).with_context(|| format!("Open GPS device {path}"))?;
+    let mut tio = termios::tcgetattr(&file).with_context(|| format!("{path} is not a tty"))?;
+    termios::cfmakeraw(&mut tio);
+    termios::cfsetspeed(&mut tio, baud)?;
+    termios::tcsetattr(&file, SetArg::TCSANOW, &tio)?;
+    Ok(file)
+}
+
+fn unix_nanos(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_nanos() as i64,
+        Err(e) => -(e.duration().as_nanos() as i64),
+    }
+}
+
+fn digits<T: std::str::FromStr>(s: &str, range: std::ops::Range<usize>) -> Option<T> {
+    s.get(range)?.parse().ok()
+}
+
+/// A time reported by an NMEA sentence, in Unix nanoseconds.
+#[derive(Debug, PartialEq, Eq)]
+enum Sentence {
+    Rmc(Option<i64>),
+    Zda(i64),
+}
+
+/// Parses the time out of an RMC or ZDA sentence. RMC sentences without a
+/// valid fix are returned without a time.
+fn parse_sentence(line: &str) -> Option<Sentence> {
+    let (body, checksum) = line.trim_end().strip_prefix('$')?.rsplit_once('*')?;
+    if body.bytes().fold(0, |a, b| a ^ b) != u8::from_str_radix(checksum, 16).ok()? {
+        return None;
+    }
+    let fields: Vec<&str> = body.split(',').collect();
+    let (rmc, time, day, month, year): (bool, &str, u8, u8, i32) = match fields[0].get(2..)? {
+        "RMC" => {
+            if *fields.get(2)? != "A" {
+                return Some(Sentence::Rmc(None));
+            }
+            let date = fields.get(9)?;
+            let year: i32 = digits(date, 4..6)?;
+            (
+                true,
+                *fields.get(1)?,
+                digits(date, 0..2)?,
+                digits(date, 2..4)?,
+                2000 + year,
+            )
+        }
+        "ZDA" => (
+            false,
+            *fields.get(1)?,
+            fields.get(2)?.parse().ok()?,
+            fields.get(3)?.parse().ok()?,
+            fields.get(4)?.parse().ok()?,
+        ),
+        _ => return None,
+    };
+    let date =
+        time::Date::from_calendar_date(year, time::Month::try_from(month).ok()?, day).ok()?;
+    let fraction: f64 = match time.get(6..)? {
+        "" => 0.0,
+        f => format!("0{f}").parse().ok()?,
+    };
+    let nanos = date
+        .with_hms(
+            digits(time, 0..2)?,
+            digits(time, 2..4)?,
+            digits(time, 4..6)?,
+        )
+        .ok()?
+        .assume_utc()
+        .unix_timestamp_nanos() as i64
+        + (fraction * NANOS_PER_SEC as f64).round() as i64;
+    Some(if rmc {
+        Sentence::Rmc(Some(nanos))
+    } else {
+        Sentence::Zda(nanos)
+    })
+}
+
+/// Median filter over recent offsets.
+struct Filter {
+    samples: VecDeque<i64>,
+    floor: Duration,
+}
+
+impl Filter {
+    fn new(floor: Duration) -> Self {
+        Self {
+            samples: VecDeque::with_capacity(FILTER_LEN),
+            floor,
+        }
+    }
+
+    /// Adds an offset and returns the filtered offset with its error, the
+    /// largest deviation of any sample from the median.
+    fn add(&mut self, offset: i64) -> (i64, Duration) {
+        if self.samples.len() == FILTER_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(offset);
+        let mut sorted: Vec<i64> = self.samples.iter().copied().collect();
+        sorted.sort_unstable();
+        let median = sorted[sorted.len() / 2];
+        let spread = sorted.iter().map(|s| s.abs_diff(median)).max().unwrap_or(0);
+        (median, Duration::from_nanos(spread).max(self.floor))
+    }
+}
+
+type Callback = dyn Fn(i64, Duration) + Send + Sync;
+
+/// The latest offset measured from NMEA alone, and when it was measured.
+type Coarse = Arc<Mutex<Option<(i64, Instant)>>>;
+
+/// Reads NMEA from `config.device` (and PPS edges, if configured) on
+/// background threads, calling `on_sample` with each new offset of GPS time
+/// from the system clock in nanoseconds and its estimated error.
+pub(crate) fn spawn(
+    config: GpsConfig,
+    on_sample: impl Fn(i64, Duration) + Send + Sync + 'static,
+) -> anyhow::Result<()> {
+    let baud = baud_rate(config.baud)?;
+    let serial = open_serial(&config.device, baud)?;
+    let pps = config.pps.as_ref().map(PpsReader::open).transpose()?;
+    let on_sample: Arc<Callback> = Arc::new(on_sample);
+    let coarse: Coarse = Arc::new(Mutex::new(None));
+
+    if let Some(pps) = pps {
+        let coarse = coarse.clone();
+        let on_sample = on_sample.clone();
+        std::thread::Builder::new()
+            .name("gps-pps".into())
+            .spawn(move || read_pps(pps, coarse, on_sample))?;
+    }
+    let nmea_only = config.pps.is_none();
+    std::thread::Builder::new()
+        .name("gps-nmea".into())
+        .spawn(move || {
+            let mut filter = nmea_only.then(|| Filter::new(NMEA_ERROR));
+            let mut serial = Some(serial);
+            loop {
+                let file = match serial.take() {
+                    Some(file) => file,
+                    None => match open_serial(&config.device, baud) {
+                        Ok(file) => file,
+                        Err(e) => {
+                            tracing::warn!("{e:#}");
+                            std::thread::sleep(REOPEN_DELAY);
+                            continue;
+                        }
+                    },
+                };
+                if let Err(e) = read_nmea(file, &config, &coarse, &mut filter, &*on_sample) {
+                    tracing::warn!("Failed to read GPS device {}: {e}", config.device);
+                } else {
+                    tracing::warn!("GPS device {} closed", config.device);
+                }
+                std::thread::sleep(REOPEN_DELAY);
+            }
+        })?;
+    Ok(())
+}
+
+fn read_nmea(
+    file: File,
+    config: &GpsConfig,
+    coarse: &Coarse,
+    filter: &mut Option<Filter>,
+    on_sample: &Callback,
+) -> std::io::Result<()> {
+    let delay = (config.delay * NANOS_PER_SEC as f64) as i64;
+    let mut reader = BufReader::new(file);
+    let mut line = Vec::new();
+    let mut last_second = None;
+    let mut fix = true;
+    loop {
+        line.clear();
+        if reader.read_until(b'\n', &mut line)? == 0 {
+            return Ok(());
+        }
+        let received = unix_nanos(SystemTime::now());
+        let Some(sentence) = std::str::from_utf8(&line).ok().and_then(parse_sentence) else {
+            continue;
+        };
+        // ZDA has no validity flag, so trust it only while RMC reports a fix.
+        let gps_time = match sentence {
+            Sentence::Rmc(time) => {
+                fix = time.is_some();
+                time
+            }
+            Sentence::Zda(time) => fix.then_some(time),
+        };
+        let Some(gps_time) = gps_time else {
+            continue;
+        };
+        // Only the first sentence of each second is timed, as later ones
+        // queue up behind it on the serial line.
+        let second = gps_time.div_euclid(NANOS_PER_SEC);
+        if last_second == Some(second) {
+            continue;
+        }
+        last_second = Some(second);
+
+        let offset = gps_time + delay - received;
+        *coarse.lock().unwrap() = Some((offset, Instant::now()));
+        if let Some(filter) = filter {
+            let (offset, error) = filter.add(offset);
+            on_sample(offset, error);
+        }
+    }
+}
+
+/// Numbers a PPS edge at system time `edge` with the GPS second nearest to
+/// it according to the coarse NMEA offset, returning the precise offset.
+fn pps_offset(edge: i64, coarse: i64) -> i64 {
+    let second = (edge + coarse + NANOS_PER_SEC / 2).div_euclid(NANOS_PER_SEC);
+    second * NANOS_PER_SEC - edge
+}
+
+fn read_pps(mut pps: PpsReader, coarse: Coarse, on_sample: Arc<Callback>) {
+    let mut filter = Filter::new(PPS_ERROR);
+    loop {
+        let edge = match pps.next_edge() {
+            Ok(Some(edge)) => edge,
+            Ok(None) => {
+                tracing::debug!("No PPS edge within {PPS_TIMEOUT:?}");
+                continue;
+            }
+            Err(e) => {
+                tracing::warn!("Failed to read PPS: {e}");
+                std::thread::sleep(REOPEN_DELAY);
+                continue;
+            }
+        };
+        let Some((offset, at)) = *coarse.lock().unwrap() else {
+            continue;
+        };
+        if at.elapsed() > NMEA_MAX_AGE {
+            continue;
+        }
+        let (offset, error) = filter.add(pps_offset(edge, offset));
+        on_sample(offset, error);
+    }
+}
+
+// Linux PPS API, from <linux/pps.h>.
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsKtime {
+    sec: i64,
+    nsec: i32,
+    flags: u32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsKinfo {
+    assert_sequence: u32,
+    clear_sequence: u32,
+    assert_tu: PpsKtime,
+    clear_tu: PpsKtime,
+    current_mode: i32,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct PpsFdata {
+    info: PpsKinfo,
+    timeout: PpsKtime,
+}
+
+// PPS_FETCH is declared with a pointer type, so its size is a pointer's.
+nix::ioctl_readwrite_bad!(
+    pps_fetch,
+    nix::request_code_readwrite!(b'p', 0xa4, std::mem::size_of::<*mut PpsFdata>()),
+    PpsFdata
+);
+
+// GPIO character device v2 API, from <linux/gpio.h>.
+
+const GPIO_V2_LINE_FLAG_INPUT: u64 = 1 << 2;
+const GPIO_V2_LINE_FLAG_EDGE_RISING: u64 = 1 << 4;
+const GPIO_V2_LINE_FLAG_EVENT_CLOCK_REALTIME: u64 = 1 << 11;
+const GPIO_V2_LINE_EVENT_SIZE: usize = 48;
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpioV2LineAttribute {
+    id: u32,
+    padding: u32,
+    value: u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct GpioV2LineConfigAttribute {
+    attr: GpioV2LineAttribute,
+    mask: u64,
+}
+
+#[repr(C)]
+struct GpioV2LineConfig {
+    flags: u64,
+    num_attrs: u32,
+    padding: [u32; 5],
+    attrs: [GpioV2LineConfigAttribute; 10],
+}
+
+#[repr(C)]
+struct GpioV2LineRequest {
+    offsets: [u32; 64],
+    consumer: [u8; 32],
+    config: GpioV2LineConfig,
+    num_lines: u32,
+    event_buffer_size: u32,
+    padding: [u32; 5],
+    fd: i32,
+}
+
+const _: () = assert!(std::mem::size_of::<GpioV2LineRequest>() == 592);
+
+nix::ioctl_readwrite!(gpio_v2_get_line, 0xb4, 0x07, GpioV2LineRequest);
+
+enum PpsReader {
+    Device { file: File, sequence: Option<u32> },
+    Gpio(File),
+}
+
+impl PpsReader {
+    fn open(source: &PpsSource) -> anyhow::Result<Self> {
+        match source {
+            PpsSource::Device(path) => Ok(Self::Device {
+                file: File::open(path).with_context(|| format!("Open PPS device {path}"))?,
+                sequence: None,
+            }),
+            PpsSource::Gpio { chip, line } => {
+                let chip_file =
+                    File::open(chip).with_context(|| format!("Open GPIO chip {chip}"))?;
+                // SAFETY: the request is plain data, for which all zeroes is
+                // a valid (empty) value.
+                let mut request: GpioV2LineRequest = unsafe { std::mem::zeroed() };
+                request.offsets[0] = *line;
+                request.num_lines = 1;
+                request.consumer[..7].copy_from_slice(b"foxtime");
+                request.config.flags = GPIO_V2_LINE_FLAG_INPUT
+                    | GPIO_V2_LINE_FLAG_EDGE_RISING
+                    | GPIO_V2_LINE_FLAG_EVENT_CLOCK_REALTIME;
+                // SAFETY: the request matches the kernel's struct layout, and
+                // on success the kernel fills in a new file descriptor we own.
+                unsafe { gpio_v2_get_line(chip_file.as_raw_fd(), &mut request) }
+                    .with_context(|| format!("Request GPIO line {chip}:{line}"))?;
+                let fd = unsafe { OwnedFd::from_raw_fd(request.fd) };
+                Ok(Self::Gpio(File::from(fd)))
+            }
+        }
+    }
+
+    /// Waits for the next rising edge and returns its system time in Unix
+    /// nanoseconds, or `None` on timeout.
+    fn next_edge(&mut self) -> std::io::Result<Option<i64>> {
+        match self {
+            Self::Device { file, sequence } => {
+                let mut data = PpsFdata {
+                    timeout: PpsKtime {
+                        sec: PPS_TIMEOUT.as_secs() as i64,
+                        ..Default::default()
+                    },
+                    ..Default::default()
+                };
+                // SAFETY: PpsFdata matches the kernel's struct pps_fdata.
+                match unsafe { pps_fetch(file.as_raw_fd(), &mut data) } {
+                    Ok(_) => {}
+                    Err(Errno::ETIMEDOUT | Errno::EINTR) => return Ok(None),
+                    Err(e) => return Err(e.into()),
+                }
+                if *sequence == Some(data.info.assert_sequence) {
+                    return Ok(None);
+                }
+                *sequence = Some(data.info.assert_sequence);
+                let ts = data.info.assert_tu;
+                Ok(Some(ts.sec * NANOS_PER_SEC + ts.nsec as i64))
+            }
+            Self::Gpio(file) => {
+                let mut event = [0u8; GPIO_V2_LINE_EVENT_SIZE];
+                file.read_exact(&mut event)?;
+                let timestamp = u64::from_ne_bytes(event[..8].try_into().unwrap());
+                Ok(Some(timestamp as i64))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+    use std::time::{Duration, SystemTime};
+
+    use super::{GpsConfig, NANOS_PER_SEC, pps_offset, unix_nanos};
+
+    /// Three seconds of receiver output: GGA, RMC and ZDA each second.
+    const NMEA_LOG: &str = "\
+$GPGGA,092640.00,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*6D
+$GPRMC,092640.00,A,4807.038,N,01131.000,E,0.02,31.66,140625,,,A*61
+$GPZDA,092640.00,14,06,2025,00,00*69
+$GPGGA,092641.00,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*6C
+$GPRMC,092641.00,A,4807.038,N,01131.000,E,0.02,31.66,140625,,,A*60
+$GPZDA,092641.00,14,06,2025,00,00*68
+$GPGGA,092642.00,4807.038,N,01131.000,E,1,08,0.9,545.4,M,46.9,M,,*6F
+$GPRMC,092642.00,A,4807.038,N,01131.000,E,0.02,31.66,140625,,,A*63
+$GPZDA,092642.00,14,06,2025,00,00*6B
+";
+
+    /// 2025-06-14T09:26:40Z
+    const LOG_START: i64 = 1_749_893_200;
+
+    #[test]
+    fn test_gps_nmea() {
+        let pty = nix::pty::openpty(None, None).unwrap();
+        let device = nix::unistd::ttyname(&pty.slave).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        super::spawn(
+            GpsConfig {
+                device: device.to_string_lossy().into_owned(),
+                baud: 9600,
+                delay: 0.0,
+                pps: None,
+            },
+            move |offset, error| tx.send((offset, error)).unwrap(),
+        )
+        .unwrap();
+
+        // Replay the log at its original pace, one second at a time.
+        let mut master = std::fs::File::from(pty.master);
+        let mut expected = Vec::new();
+        for (i, second) in NMEA_LOG.lines().collect::<Vec<_>>().chunks(3).enumerate() {
+            if i > 0 {
+                std::thread::sleep(Duration::from_secs(1));
+            }
+            let written = unix_nanos(SystemTime::now());
+            for line in second {
+                write!(master, "{line}\r\n").unwrap();
+            }
+            master.flush().unwrap();
+            expected.push((LOG_START + i as i64) * NANOS_PER_SEC - written);
+        }
+
+        for expected in expected {
+            let (offset, error) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            assert!(
+                offset.abs_diff(expected) < 50_000_000,
+                "offset {offset} differs from {expected}"
+            );
+            assert!(error < Duration::from_millis(100));
+        }
+        assert!(rx.recv_timeout(Duration::from_millis(100)).is_err());
+
+        // A PPS edge 1 ms after a second takes its number from NMEA even
+        // when the sentence timing is 300 ms off.
+        let edge = 1_000 * NANOS_PER_SEC + 1_000_000;
+        let coarse = LOG_START * NANOS_PER_SEC - edge + 300_000_000;
+        assert_eq!(pps_offset(edge, coarse), LOG_START * NANOS_PER_SEC - edge);
+    }
+}
@@ -0,0 +1,240 @@
+use std::collections::VecDeque;
+use std::fs::File;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::os::unix::fs::FileExt;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, OnceLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::chaos::Transport;
+use crate::clock;
+use crate::daemon::{self, DaemonStatus};
+use crate::federation::{self, PeerStatus};
+
+/// Most samples served by one history request.
+const MAX_SAMPLES: usize = 10_000;
+
+/// Time exchanges since the previous sample.
+#[derive(Debug, Serialize)]
+struct Requests {
+    http: u64,
+    ws: u64,
+    wt: u64,
+}
+
+/// The state of the served clock at one point in time, as stored in the
+/// history: a line of JSON per sample.
+#[derive(Debug, Serialize)]
+struct Sample {
+    /// Unix time, in seconds.
+    time: f64,
+    synchronized: bool,
+    /// Seconds.
+    max_error: f64,
+    /// Seconds.
+    est_error: f64,
+    /// Reference time minus system time when served from GPS or NTP, in
+    /// seconds.
+    reference_offset: Option<f64>,
+    daemon: Option<DaemonStatus>,
+    degraded: bool,
+    peers: Vec<PeerStatus>,
+    requests: Requests,
+}
+
+static REQUESTS: [AtomicU64; 3] = [const { AtomicU64::new(0) }; 3];
+static HISTORY: OnceLock<Arc<File>> = OnceLock::new();
+
+/// Counts a time exchange for the next sample.
+pub(crate) fn count(transport: Transport) {
+    REQUESTS[transport as usize].fetch_add(1, Ordering::Relaxed);
+}
+
+fn sample() -> anyhow::Result<Sample> {
+    let clock = clock::status();
+    let requests = |transport| REQUESTS[transport as usize].swap(0, Ordering::Relaxed);
+    Ok(Sample {
+        time: clock::now().duration_since(UNIX_EPOCH)?.as_secs_f64(),
+        synchronized: clock.synchronized,
+        max_error: clock.max_error.as_secs_f64(),
+        est_error: clock.est_error.as_secs_f64(),
+        reference_offset: clock::reference_offset(),
+        daemon: daemon::status(),
+        degraded: federation::degraded(),
+        peers: federation::peers(),
+        requests: Requests {
+            http: requests(Transport::Http),
+            ws: requests(Transport::Ws),
+            wt: requests(Transport::Wt),
+        },
+    })
+}
+
+/// Appends a sample to the file at `path` every `interval`. The file stays
+/// open, so that it can still be served after a chroot.
+pub(crate) fn spawn(path: &str, interval: Duration) -> anyhow::Result<()> {
+    let file = File::options()
+        .read(true)
+        .append(true)
+        .create(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path}"))?;
+    let file = HISTORY.get_or_init(|| Arc::new(file)).clone();
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        // The first tick completes immediately; skip it so that the first
+        // sample covers a full interval of requests.
+        interval.tick().await;
+        loop {
+            interval.tick().await;
+            let line = match sample() {
+                Ok(sample) => serde_json::to_string(&sample).unwrap() + "\n",
+                Err(e) => {
+                    tracing::error!("Failed to sample clock state: {e:#}");
+                    continue;
+                }
+            };
+            if let Err(e) = file.as_ref().write_all(line.as_bytes()) {
+                tracing::error!("Failed to write history: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+#[derive(Deserialize)]
+struct SampleTime {
+    time: f64,
+}
+
+/// Reads a file from the start with positional reads, so that concurrent
+/// readers don't move each other's offset and the sampler never waits.
+struct ReadAt<'a> {
+    file: &'a File,
+    position: u64,
+}
+
+impl Read for ReadAt<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.file.read_at(buf, self.position)?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+/// The newest `MAX_SAMPLES` samples of `history` taken between `from` and
+/// `to`, as a JSON array.
+fn range(history: impl BufRead, from: f64, to: f64) -> std::io::Result<String> {
+    let mut samples = VecDeque::new();
+    for line in history.split(b'\n') {
+        let Ok(line) = String::from_utf8(line?) else {
+            continue;
+        };
+        if !serde_json::from_str::<SampleTime>(&line)
+            .is_ok_and(|sample| (from..=to).contains(&sample.time))
+        {
+            continue;
+        }
+        if samples.len() == MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back(line);
+    }
+    Ok(format!("[{}]", Vec::from(samples).join(",")))
+}
+
+/// Serves the samples taken between the Unix times given by the `from` and
+/// `to` query parameters, by default the last day.
+#[handler]
+pub(crate) async fn time_history(req: &mut Request, res: &mut Response) {
+    let Some(file) = HISTORY.get().cloned() else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let now = clock::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |ts| ts.as_secs_f64());
+    let to = req.query::<f64>("to").unwrap_or(now);
+    let from = req.query::<f64>("from").unwrap_or(to - 86400.0);
+    let samples = tokio::task::spawn_blocking(move || {
+        let history = ReadAt {
+            file: &file,
+            position: 0,
+        };
+        range(BufReader::new(history), from, to)
+    })
+    .await;
+    match samples {
+        Ok(Ok(samples)) => {
+            res.add_header("content-type", "application/json", true)
+                .ok();
+            res.body(samples);
+        }
+        _ => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MAX_SAMPLES, Requests, Sample, range};
+
+    #[test]
+    fn test_history_range() {
+        let sample = |time| Sample {
+            time,
+            synchronized: true,
+            max_error: 0.01,
+            est_error: 0.001,
+            reference_offset: None,
+            daemon: None,
+            degraded: false,
+            peers: Vec::new(),
+            requests: Requests {
+                http: 3,
+                ws: 2,
+                wt: 1,
+            },
+        };
+        let history: String = [100.0, 160.0, 220.0, 280.0]
+            .into_iter()
+            .map(|time| serde_json::to_string(&sample(time)).unwrap() + "\n")
+            .collect();
+
+        let range = |history: &str, from, to| range(history.as_bytes(), from, to).unwrap();
+        let samples: serde_json::Value =
+            serde_json::from_str(&range(&history, 150.0, 220.0)).unwrap();
+        let times: Vec<_> = samples
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|s| s["time"].as_f64().unwrap())
+            .collect();
+        assert_eq!(times, [160.0, 220.0]);
+        assert_eq!(samples[0]["requests"]["ws"], 2);
+        assert_eq!(range(&history, 300.0, 400.0), "[]");
+        // A line cut short by a crash is skipped.
+        assert_eq!(
+            range(&(history + "{\"time\":3"), 0.0, 400.0)
+                .matches("time")
+                .count(),
+            4
+        );
+
+        // Only the newest samples of a long range are served.
+        let history: String = (0..MAX_SAMPLES + 2)
+            .map(|time| serde_json::to_string(&sample(time as f64)).unwrap() + "\n")
+            .collect();
+        let samples: serde_json::Value =
+            serde_json::from_str(&range(&history, 0.0, f64::MAX)).unwrap();
+        let samples = samples.as_array().unwrap();
+        assert_eq!(samples.len(), MAX_SAMPLES);
+        assert_eq!(samples[0]["time"], 2.0);
+    }
+}
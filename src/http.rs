@@ -1,23 +1,68 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::UNIX_EPOCH;
 
 use salvo::prelude::*;
+use serde::Serialize;
+
+use crate::chaos::{self, Transport};
+use crate::{clock, daemon, federation, recording, signing, wait};
 
 const X_HTTPSTIME: &str = "x-httpstime";
+const X_HTTPSTIME_UNTIL: &str = "x-httpstime-until";
+const X_HTTPSTIME_NONCE: &str = "x-httpstime-nonce";
+const X_HTTPSTIME_SIGNATURE: &str = "x-httpstime-signature";
+const X_HTTPSTIME_IDENTITY: &str = "x-httpstime-identity";
+const X_HTTPSTIME_DAEMON: &str = "x-httpstime-daemon";
+const X_HTTPSTIME_DEGRADED: &str = "x-httpstime-degraded";
 
 fn add_common_cors_headers(res: &mut Response) {
     res.add_header("access-control-allow-origin", "*", true)
         .ok();
-    res.add_header("access-control-expose-headers", X_HTTPSTIME, true)
-        .ok();
+    res.add_header(
+        "access-control-expose-headers",
+        format!(
+            "{X_HTTPSTIME}, {X_HTTPSTIME_UNTIL}, {X_HTTPSTIME_SIGNATURE}, {X_HTTPSTIME_IDENTITY}, \
+             {X_HTTPSTIME_DAEMON}, {X_HTTPSTIME_DEGRADED}"
+        ),
+        true,
+    )
+    .ok();
+}
+
+fn add_degraded_header(res: &mut Response) {
+    if federation::degraded() {
+        res.add_header(X_HTTPSTIME_DEGRADED, "1", true).ok();
+    }
 }
 
 #[handler]
-pub(crate) async fn time(res: &mut Response) {
+pub(crate) async fn time(req: &mut Request, res: &mut Response) {
     add_common_cors_headers(res);
-    match SystemTime::now().duration_since(UNIX_EPOCH) {
+    let nonce = req.header::<String>(X_HTTPSTIME_NONCE).unwrap_or_default();
+    // Only signing puts a bound on the nonce.
+    if signing::signer().is_some() && nonce.len() > signing::MAX_NONCE_LEN {
+        res.status_code(StatusCode::BAD_REQUEST);
+        return;
+    }
+    let mut exchange =
+        recording::Session::http(recording::peer(req.remote_addr())).start(nonce.as_bytes());
+    chaos::before(Transport::Http).await;
+    match exchange.timestamp().await.duration_since(UNIX_EPOCH) {
         Ok(ts) => {
-            res.add_header(X_HTTPSTIME, ts.as_secs_f64().to_string(), true)
-                .ok();
+            let timestamp = ts.as_secs_f64().to_string();
+            if let Some(signer) = signing::signer() {
+                res.add_header(X_HTTPSTIME_SIGNATURE, signer.sign(&timestamp, &nonce), true)
+                    .ok();
+                res.add_header(X_HTTPSTIME_IDENTITY, signer.identity(), true)
+                    .ok();
+            }
+            res.add_header(X_HTTPSTIME, timestamp, true).ok();
+            add_degraded_header(res);
+            if let Some(status) = daemon::status() {
+                res.add_header(X_HTTPSTIME_DAEMON, status.header_value(), true)
+                    .ok();
+            }
+            chaos::after(Transport::Http).await;
+            exchange.finish().await;
         }
         Err(_) => {
             res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
@@ -30,15 +75,110 @@ pub(crate) async fn time_options(res: &mut Response) {
     add_common_cors_headers(res);
     res.add_header("access-control-allow-methods", "GET, HEAD", true)
         .ok();
+    res.add_header("access-control-allow-headers", X_HTTPSTIME_NONCE, true)
+        .ok();
+    res.status_code(StatusCode::NO_CONTENT);
+}
+
+/// Serves the base64-encoded Ed25519 public key used to sign time responses.
+#[handler]
+pub(crate) async fn time_key(res: &mut Response) {
+    add_common_cors_headers(res);
+    match signing::signer() {
+        Some(signer) => res.render(Text::Plain(signer.public_key())),
+        None => {
+            res.status_code(StatusCode::NOT_FOUND);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct TimeStatus {
+    synchronized: bool,
+    /// Seconds.
+    max_error: f64,
+    /// Seconds.
+    est_error: f64,
+    daemon: Option<daemon::DaemonStatus>,
+    degraded: bool,
+    peers: Vec<federation::PeerStatus>,
+}
+
+/// Serves the clock status, including the host time daemon's view, as JSON.
+#[handler]
+pub(crate) async fn time_status(res: &mut Response) {
+    add_common_cors_headers(res);
+    let clock = clock::status();
+    res.render(Json(TimeStatus {
+        synchronized: clock.synchronized,
+        max_error: clock.max_error.as_secs_f64(),
+        est_error: clock.est_error.as_secs_f64(),
+        daemon: daemon::status(),
+        degraded: federation::degraded(),
+        peers: federation::peers(),
+    }));
+}
+
+/// Responds 503 while the peer majority disagrees with our time.
+#[handler]
+pub(crate) async fn time_health(res: &mut Response) {
+    add_common_cors_headers(res);
+    if federation::degraded() {
+        res.status_code(StatusCode::SERVICE_UNAVAILABLE);
+        res.render(Text::Plain("Degraded"));
+    } else {
+        res.render(Text::Plain("OK"));
+    }
+}
+
+/// Holds the request open until the Unix time given by the `until` query
+/// parameter, then responds with the actual send time in `x-httpstime`.
+#[handler]
+pub(crate) async fn time_wait(req: &mut Request, res: &mut Response) {
+    add_common_cors_headers(res);
+    let Some(until) = req.query::<f64>("until") else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain("Missing or invalid until parameter"));
+        return;
+    };
+    match wait::until(until).await {
+        Ok(ts) => {
+            res.add_header(X_HTTPSTIME, ts.to_string(), true).ok();
+            res.add_header(X_HTTPSTIME_UNTIL, until.to_string(), true)
+                .ok();
+            add_degraded_header(res);
+        }
+        Err(e @ (wait::WaitError::Invalid | wait::WaitError::TooFar)) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(e.to_string()));
+        }
+        Err(wait::WaitError::Clock) => {
+            res.status_code(StatusCode::INTERNAL_SERVER_ERROR);
+        }
+    }
+}
+
+#[handler]
+pub(crate) async fn time_wait_options(res: &mut Response) {
+    add_common_cors_headers(res);
+    res.add_header("access-control-allow-methods", "GET", true)
+        .ok();
     res.status_code(StatusCode::NO_CONTENT);
 }
 
 #[cfg(test)]
 mod tests {
-    use salvo::test::TestClient;
+    use salvo::test::{ResponseExt, TestClient};
     use std::time::{SystemTime, UNIX_EPOCH};
 
-    use crate::{http::X_HTTPSTIME, router};
+    use base64::Engine;
+    use ring::signature::{ED25519, UnparsedPublicKey};
+
+    use crate::http::{
+        X_HTTPSTIME, X_HTTPSTIME_IDENTITY, X_HTTPSTIME_NONCE, X_HTTPSTIME_SIGNATURE,
+        X_HTTPSTIME_UNTIL,
+    };
+    use crate::{router, signing};
 
     #[tokio::test]
     async fn test_time() {
@@ -79,4 +219,130 @@ mod tests {
             "server time {server_time} is after t2 {t2}"
         );
     }
+
+    #[tokio::test]
+    async fn test_wait() {
+        let service = salvo::Service::new(router::router());
+
+        let until = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64()
+            + 0.25;
+
+        let response = TestClient::get(format!(
+            "http://localhost/.well-known/time/wait?until={until}"
+        ))
+        .send(&service)
+        .await;
+
+        let t2 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64();
+
+        assert_eq!(response.status_code, Some(salvo::http::StatusCode::OK));
+        let header = |name| -> f64 {
+            response
+                .headers()
+                .get(name)
+                .expect("response missing header")
+                .to_str()
+                .expect("header is not valid UTF-8")
+                .parse()
+                .expect("header is not a valid float")
+        };
+        let server_time = header(X_HTTPSTIME);
+        assert_eq!(header(X_HTTPSTIME_UNTIL), until);
+        assert!(
+            server_time >= until,
+            "server time {server_time} is before until {until}"
+        );
+        assert!(
+            server_time <= t2,
+            "server time {server_time} is after t2 {t2}"
+        );
+
+        let response = TestClient::get(format!(
+            "http://localhost/.well-known/time/wait?until={}",
+            until + 3600.0
+        ))
+        .send(&service)
+        .await;
+        assert_eq!(
+            response.status_code,
+            Some(salvo::http::StatusCode::BAD_REQUEST)
+        );
+    }
+
+    #[tokio::test]
+    async fn test_signed_time() {
+        let pkcs8 =
+            ring::signature::Ed25519KeyPair::generate_pkcs8(&ring::rand::SystemRandom::new())
+                .unwrap();
+        signing::set_signer(Some(
+            signing::Signer::from_pkcs8(pkcs8.as_ref(), "time.example.com".to_string()).unwrap(),
+        ));
+        let service = salvo::Service::new(router::router());
+
+        let mut response = TestClient::get("http://localhost/.well-known/time/key")
+            .send(&service)
+            .await;
+        assert_eq!(response.status_code, Some(salvo::http::StatusCode::OK));
+        let public_key = base64::engine::general_purpose::STANDARD
+            .decode(response.take_string().await.unwrap())
+            .unwrap();
+
+        let response = TestClient::get("http://localhost/.well-known/time")
+            .add_header(X_HTTPSTIME_NONCE, "client-nonce-1", true)
+            .send(&service)
+            .await;
+        assert_eq!(response.status_code, Some(salvo::http::StatusCode::OK));
+        let header = |name| {
+            response
+                .headers()
+                .get(name)
+                .expect("response missing header")
+                .to_str()
+                .expect("header is not valid UTF-8")
+                .to_string()
+        };
+        let identity = header(X_HTTPSTIME_IDENTITY);
+        assert_eq!(identity, "time.example.com");
+        let signature = base64::engine::general_purpose::STANDARD
+            .decode(header(X_HTTPSTIME_SIGNATURE))
+            .unwrap();
+
+        let key = UnparsedPublicKey::new(&ED25519, &public_key);
+        let timestamp = header(X_HTTPSTIME);
+        key.verify(
+            signing::message(&identity, "client-nonce-1", &timestamp).as_bytes(),
+            &signature,
+        )
+        .expect("signature does not verify");
+        assert!(
+            key.verify(
+                signing::message(&identity, "other-nonce", &timestamp).as_bytes(),
+                &signature,
+            )
+            .is_err(),
+            "signature verifies with the wrong nonce"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_time_status() {
+        let service = salvo::Service::new(router::router());
+        let mut response = TestClient::get("http://localhost/.well-known/time/status")
+            .send(&service)
+            .await;
+        assert_eq!(response.status_code, Some(salvo::http::StatusCode::OK));
+
+        let status: serde_json::Value =
+            serde_json::from_str(&response.take_string().await.unwrap()).unwrap();
+        assert!(status["synchronized"].is_boolean());
+        assert!(status["max_error"].as_f64().unwrap() >= 0.0);
+        assert!(status["est_error"].as_f64().unwrap() >= 0.0);
+        assert!(status["daemon"].is_null());
+    }
 }
@@ -0,0 +1,577 @@
+use std::io::Result as IoResult;
+use std::net::SocketAddr;
+use std::os::fd::OwnedFd;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use futures_util::future::select_all;
+use nix::sys::socket::{
+    AddressFamily, SockType, SockaddrLike, SockaddrStorage, getsockname, sockopt,
+};
+use salvo::conn::rustls::RustlsAcceptor;
+use salvo::conn::tcp::{TcpAcceptor, TcpCoupler};
+use salvo::conn::{Accepted, Acceptor, Holding, IntoConfigStream, JoinedAcceptor, StraightStream};
+use salvo::fuse::{ArcFuseFactory, FuseInfo, TransProto};
+use salvo::http::Version;
+use salvo::http::uri::Scheme;
+use salvo::prelude::*;
+use tokio::signal::unix::{SignalKind, signal};
+
+use crate::proxy::ProxyProtocolAcceptor;
+use crate::tls::{self, ReloadableCert, TlsConfig};
+use crate::{assets, self_signed};
+
+/// A PEM-encoded certificate chain and private key.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct TlsFiles {
+    pub(crate) cert: String,
+    pub(crate) key: String,
+}
+
+/// How often certificate files are checked for changes.
+const TLS_RELOAD_INTERVAL: Duration = Duration::from_secs(10);
+
+impl TlsFiles {
+    fn read(&self) -> anyhow::Result<(Vec<u8>, Vec<u8>)> {
+        let cert = std::fs::read(&self.cert).with_context(|| format!("Read {}", self.cert))?;
+        let key = std::fs::read(&self.key).with_context(|| format!("Read {}", self.key))?;
+        Ok((cert, key))
+    }
+
+    fn modified(&self) -> [Option<SystemTime>; 2] {
+        [&self.cert, &self.key].map(|path| std::fs::metadata(path).and_then(|m| m.modified()).ok())
+    }
+
+    /// Loads the certificate, then reloads it whenever either file changes or
+    /// on SIGHUP. A pair that fails to load keeps the previous certificate in
+    /// use, and is retried until it loads.
+    pub(crate) fn load(&self) -> anyhow::Result<TlsConfig> {
+        let mut loaded = self.modified();
+        let (cert, key) = self.read()?;
+        let reloadable = Arc::new(
+            ReloadableCert::from_pem(&cert, &key).with_context(|| format!("Load {}", self.cert))?,
+        );
+        let mut hangup = signal(SignalKind::hangup()).context("Install SIGHUP handler")?;
+        let files = self.clone();
+        let cert = reloadable.clone();
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(TLS_RELOAD_INTERVAL);
+            loop {
+                let forced = tokio::select! {
+                    _ = interval.tick() => false,
+                    _ = hangup.recv() => true,
+                };
+                let modified = files.modified();
+                if !forced && modified == loaded {
+                    continue;
+                }
+                match files.read().and_then(|(c, k)| tls::certified_key(&c, &k)) {
+                    Ok(key) => {
+                        cert.replace(key);
+                        loaded = modified;
+                        tracing::info!("Reloaded {}", files.cert);
+                    }
+                    Err(e) => tracing::error!("Failed to reload {}: {e:#}", files.cert),
+                }
+            }
+        });
+        Ok(TlsConfig::from_cert(reloadable))
+    }
+}
+
+/// Ownership and mode given to a Unix domain socket after creating it.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct UnixPermissions {
+    pub(crate) owner: Option<String>,
+    pub(crate) group: Option<String>,
+    pub(crate) mode: Option<u32>,
+}
+
+/// A socket to accept connections on, as given to `--listen`.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Listen {
+    Tcp(SocketAddr),
+    /// Uses the default certificate, from `--tls-cert` and `--tls-key` or
+    /// ACME, when `tls` is unset.
+    Tls {
+        addr: SocketAddr,
+        tls: Option<TlsFiles>,
+    },
+    /// Uses the default certificate when `tls` is unset, or else a
+    /// self-signed one.
+    Quic {
+        addr: SocketAddr,
+        tls: Option<TlsFiles>,
+    },
+    Unix {
+        path: String,
+        permissions: UnixPermissions,
+    },
+}
+
+/// Parses a listener such as `tcp://0.0.0.0:80`,
+/// `tls://[::]:443?cert=cert.pem&key=key.pem`,
+/// `unix:/run/foxtime.sock?group=www-data&mode=660` or `quic://[::]:443`.
+pub(crate) fn parse_listen(s: &str) -> Result<Listen, String> {
+    let parse = || -> anyhow::Result<Listen> {
+        let (scheme, rest) = s
+            .split_once(':')
+            .with_context(|| format!("Expected scheme:address: {s}"))?;
+        let (address, query) = rest.split_once('?').unwrap_or((rest, ""));
+        let mut options = Vec::new();
+        for pair in query.split('&').filter(|p| !p.is_empty()) {
+            let (key, value) = pair
+                .split_once('=')
+                .with_context(|| format!("Expected key=value: {pair}"))?;
+            options.push((key, value.to_string()));
+        }
+        let mut option = |name: &str| {
+            options
+                .iter()
+                .position(|(key, _)| *key == name)
+                .map(|i| options.remove(i).1)
+        };
+        let addr = || -> anyhow::Result<SocketAddr> {
+            let addr = address
+                .strip_prefix("//")
+                .with_context(|| format!("Expected {scheme}://address: {s}"))?;
+            addr.parse()
+                .with_context(|| format!("Invalid socket address: {addr}"))
+        };
+        let listen = match scheme {
+            "tcp" => Listen::Tcp(addr()?),
+            "tls" | "quic" => {
+                let tls = match (option("cert"), option("key")) {
+                    (Some(cert), Some(key)) => Some(TlsFiles { cert, key }),
+                    (None, None) => None,
+                    _ => anyhow::bail!("cert and key must be given together"),
+                };
+                if scheme == "tls" {
+                    Listen::Tls { addr: addr()?, tls }
+                } else {
+                    Listen::Quic { addr: addr()?, tls }
+                }
+            }
+            "unix" => {
+                if address.is_empty() {
+                    anyhow::bail!("Expected unix:path");
+                }
+                Listen::Unix {
+                    path: address.to_string(),
+                    permissions: UnixPermissions {
+                        owner: option("owner"),
+                        group: option("group"),
+                        mode: option("mode")
+                            .map(|mode| u32::from_str_radix(&mode, 8))
+                            .transpose()
+                            .context("Invalid mode")?,
+                    },
+                }
+            }
+            _ => anyhow::bail!("Unknown scheme: {scheme}"),
+        };
+        if let Some((key, _)) = options.first() {
+            anyhow::bail!("Unknown option for {scheme}: {key}");
+        }
+        Ok(listen)
+    };
+    parse().map_err(|e| format!("{e:#}"))
+}
+
+fn set_unix_permissions(path: &str, permissions: &UnixPermissions) -> anyhow::Result<()> {
+    if permissions.owner.is_some() || permissions.group.is_some() {
+        let user = permissions
+            .owner
+            .as_deref()
+            .map(|name| {
+                nix::unistd::User::from_name(name)
+                    .context("Look up user")
+                    .and_then(|u| u.ok_or_else(|| anyhow::anyhow!("User not found: {}", name)))
+            })
+            .transpose()?;
+        let group = permissions
+            .group
+            .as_deref()
+            .map(|name| {
+                nix::unistd::Group::from_name(name)
+                    .context("Look up group")
+                    .and_then(|g| g.ok_or_else(|| anyhow::anyhow!("Group not found: {}", name)))
+            })
+            .transpose()?;
+        nix::unistd::chown(path, user.map(|u| u.uid), group.map(|g| g.gid))?;
+    }
+    if let Some(mode) = permissions.mode {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+    }
+    Ok(())
+}
+
+/// Removes the socket at `path` left behind by a previous instance, refusing
+/// to touch anything else.
+fn remove_stale_socket(path: &str) -> anyhow::Result<()> {
+    if !std::path::Path::new(path).exists() {
+        return Ok(());
+    }
+    let meta = std::fs::metadata(path).with_context(|| format!("Stat {path}"))?;
+    if !std::os::unix::fs::FileTypeExt::is_socket(&meta.file_type()) {
+        anyhow::bail!("{path} exists and is not a Unix socket");
+    }
+    match std::os::unix::net::UnixStream::connect(path) {
+        Ok(_) => anyhow::bail!("{path} is already in use by another process"),
+        Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+            std::fs::remove_file(path).with_context(|| format!("Remove stale socket {path}"))
+        }
+        Err(e) => Err(anyhow::Error::from(e)).with_context(|| format!("Probe socket {path}")),
+    }
+}
+
+// QuinnListener::try_bind() only stores the socket address; the UDP socket is
+// not actually bound until the first QuinnAcceptor::accept() call during
+// serving — which happens after apply_privdrop(). For privileged ports this
+// fails. Work around it by polling accept() once with a zero timeout:
+// Endpoint::server() runs synchronously before the first await point in
+// accept(), so the UDP socket gets bound before the future is cancelled.
+#[allow(clippy::single_match)]
+async fn bind_quinn_listener(config: TlsConfig, addr: SocketAddr) -> anyhow::Result<impl Acceptor> {
+    let mut acceptor = QuinnListener::new(config.quic()?, addr).bind().await;
+    match tokio::time::timeout(std::time::Duration::ZERO, acceptor.accept(None)).await {
+        Ok(Err(e)) => {
+            return Err(anyhow::Error::from(e)).with_context(|| format!("Bind quic://{addr}"));
+        }
+        _ => {}
+    }
+    Ok(acceptor)
+}
+
+/// Accepts connections on a Unix domain socket. Unlike salvo's, it can be
+/// made from a socket that is already listening, such as an inherited one.
+pub(crate) struct UnixSocketAcceptor {
+    inner: tokio::net::UnixListener,
+    holdings: Vec<Holding>,
+    /// The socket file that we created, removed when the server stops.
+    path: Option<String>,
+}
+
+impl UnixSocketAcceptor {
+    fn new(inner: tokio::net::UnixListener, path: Option<String>) -> IoResult<Self> {
+        let holdings = vec![Holding {
+            local_addr: inner.local_addr()?.into(),
+            http_versions: vec![Version::HTTP_11],
+            http_scheme: Scheme::HTTP,
+        }];
+        Ok(UnixSocketAcceptor {
+            inner,
+            holdings,
+            path,
+        })
+    }
+}
+
+impl Drop for UnixSocketAcceptor {
+    fn drop(&mut self) {
+        if let Some(path) = &self.path
+            && let Err(e) = std::fs::remove_file(path)
+        {
+            tracing::warn!("Failed to remove {path}: {e}");
+        }
+    }
+}
+
+impl Acceptor for UnixSocketAcceptor {
+    type Coupler = TcpCoupler<Self::Stream>;
+    type Stream = StraightStream<tokio::net::UnixStream>;
+
+    fn holdings(&self) -> &[Holding] {
+        &self.holdings
+    }
+
+    async fn accept(
+        &mut self,
+        fuse_factory: Option<ArcFuseFactory>,
+    ) -> IoResult<Accepted<Self::Coupler, Self::Stream>> {
+        let (stream, remote_addr) = self.inner.accept().await?;
+        let local_addr = self.holdings[0].local_addr.clone();
+        let remote_addr: salvo::conn::SocketAddr = remote_addr.into();
+        let fusewire = fuse_factory.map(|f| {
+            f.create(FuseInfo {
+                trans_proto: TransProto::Tcp,
+                remote_addr: remote_addr.clone(),
+                local_addr: local_addr.clone(),
+            })
+        });
+        Ok(Accepted {
+            coupler: TcpCoupler::new(),
+            stream: StraightStream::new(stream, fusewire.clone()),
+            fusewire,
+            local_addr,
+            remote_addr,
+            http_scheme: Scheme::HTTP,
+        })
+    }
+}
+
+/// How a socket passed by the service manager is served.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum InheritedKind {
+    Tcp,
+    Tls,
+    Unix,
+}
+
+/// Serves an inherited socket as its `FileDescriptorName=` says (`tcp`, `tls`
+/// or `unix`), or else as its type implies. QUIC listeners bind their own
+/// sockets, so sockets named `quic` and UDP sockets are refused rather than
+/// left unserved.
+fn inherited_kind(name: &str, fd: &OwnedFd) -> anyhow::Result<InheritedKind> {
+    let sock_type = nix::sys::socket::getsockopt(fd, sockopt::SockType)?;
+    if name == "quic" || sock_type == SockType::Datagram {
+        anyhow::bail!("QUIC cannot be served from an inherited socket; use --listen quic://ADDR");
+    }
+    let family = getsockname::<SockaddrStorage>(std::os::fd::AsRawFd::as_raw_fd(fd))?.family();
+    let implied = match (sock_type, family) {
+        (SockType::Stream, Some(AddressFamily::Unix)) => InheritedKind::Unix,
+        (SockType::Stream, Some(AddressFamily::Inet | AddressFamily::Inet6)) => InheritedKind::Tcp,
+        _ => anyhow::bail!("Unsupported socket type {sock_type:?} ({family:?})"),
+    };
+    let kind = match name {
+        "tcp" => InheritedKind::Tcp,
+        "tls" => InheritedKind::Tls,
+        "unix" => InheritedKind::Unix,
+        _ => implied,
+    };
+    if (kind == InheritedKind::Tls && implied != InheritedKind::Tcp)
+        || (kind != InheritedKind::Tls && kind != implied)
+    {
+        anyhow::bail!("Cannot serve {kind:?} on a {implied:?} socket");
+    }
+    Ok(kind)
+}
+
+fn tcp_acceptor(fd: OwnedFd) -> anyhow::Result<TcpAcceptor> {
+    let listener = std::net::TcpListener::from(fd);
+    listener.set_nonblocking(true)?;
+    Ok(TcpAcceptor::try_from(tokio::net::TcpListener::from_std(
+        listener,
+    )?)?)
+}
+
+/// Accepts connections from any number of acceptors of the same kind.
+pub(crate) struct Acceptors<A> {
+    acceptors: Vec<A>,
+    holdings: Vec<Holding>,
+}
+
+impl<A: Acceptor> Acceptors<A> {
+    fn new(acceptors: Vec<A>) -> Self {
+        let holdings = acceptors
+            .iter()
+            .flat_map(|a| a.holdings())
+            .cloned()
+            .collect();
+        Acceptors {
+            acceptors,
+            holdings,
+        }
+    }
+}
+
+impl<A: Acceptor> Acceptor for Acceptors<A> {
+    type Coupler = A::Coupler;
+    type Stream = A::Stream;
+
+    fn holdings(&self) -> &[Holding] {
+        &self.holdings
+    }
+
+    async fn accept(
+        &mut self,
+        fuse_factory: Option<ArcFuseFactory>,
+    ) -> IoResult<Accepted<Self::Coupler, Self::Stream>> {
+        if self.acceptors.is_empty() {
+            return std::future::pending().await;
+        }
+        let accepts = self
+            .acceptors
+            .iter_mut()
+            .map(|a| Box::pin(a.accept(fuse_factory.clone())));
+        select_all(accepts).await.0
+    }
+}
+
+/// Binds every listener in `listens` and serves the `inherited` sockets,
+/// joining them into one acceptor, and returns what pages need to know to
+/// reach the first QUIC listener. Listeners without a certificate of their
+/// own use `default_tls`.
+pub(crate) async fn bind(
+    listens: &[Listen],
+    inherited: Vec<(String, OwnedFd)>,
+    default_tls: Option<TlsConfig>,
+) -> anyhow::Result<(impl Acceptor + use<>, Option<assets::QuicInfo>)> {
+    let config = |tls: &Option<TlsFiles>| match tls {
+        Some(tls) => tls.load().map(Some),
+        None => Ok(default_tls.clone()),
+    };
+    let mut self_signed: Option<TlsConfig> = None;
+    let mut quic_info = None;
+
+    let mut tcp = Vec::new();
+    let mut tls = Vec::new();
+    let mut unix = Vec::new();
+    let mut quic = Vec::new();
+    for listen in listens {
+        match listen {
+            Listen::Tcp(addr) => tcp.push(ProxyProtocolAcceptor::new(
+                TcpListener::new(*addr)
+                    .try_bind()
+                    .await
+                    .with_context(|| format!("Bind tcp://{addr}"))?,
+            )),
+            Listen::Tls { addr, tls: files } => {
+                let config =
+                    config(files)?.with_context(|| format!("tls://{addr} needs a certificate"))?;
+                // The PROXY header, if any, comes before the TLS handshake.
+                tls.push(RustlsAcceptor::new(
+                    config.into_stream().boxed(),
+                    ProxyProtocolAcceptor::new(
+                        TcpListener::new(*addr)
+                            .try_bind()
+                            .await
+                            .with_context(|| format!("Bind tls://{addr}"))?,
+                    ),
+                ));
+            }
+            Listen::Unix { path, permissions } => {
+                remove_stale_socket(path)?;
+                unix.push(ProxyProtocolAcceptor::new(UnixSocketAcceptor::new(
+                    tokio::net::UnixListener::bind(path)
+                        .with_context(|| format!("Bind unix:{path}"))?,
+                    Some(path.clone()),
+                )?));
+                set_unix_permissions(path, permissions)?;
+            }
+            Listen::Quic { addr, tls: files } => {
+                let config = match config(files)? {
+                    Some(config) => config,
+                    None => match &self_signed {
+                        Some(generated) => generated.clone(),
+                        None => self_signed.insert(self_signed::generate()?).clone(),
+                    },
+                };
+                quic_info.get_or_insert(assets::QuicInfo {
+                    port: addr.port(),
+                    tls: config.clone(),
+                });
+                quic.push(bind_quinn_listener(config, *addr).await?);
+            }
+        }
+    }
+    for (name, fd) in inherited {
+        match inherited_kind(&name, &fd).with_context(|| format!("Inherited socket {name}"))? {
+            InheritedKind::Tcp => tcp.push(ProxyProtocolAcceptor::new(tcp_acceptor(fd)?)),
+            InheritedKind::Tls => {
+                let config = default_tls
+                    .clone()
+                    .with_context(|| format!("Inherited socket {name} needs a certificate"))?;
+                tls.push(RustlsAcceptor::new(
+                    config.into_stream().boxed(),
+                    ProxyProtocolAcceptor::new(tcp_acceptor(fd)?),
+                ));
+            }
+            InheritedKind::Unix => {
+                let listener = std::os::unix::net::UnixListener::from(fd);
+                listener.set_nonblocking(true)?;
+                unix.push(ProxyProtocolAcceptor::new(UnixSocketAcceptor::new(
+                    tokio::net::UnixListener::from_std(listener)?,
+                    None,
+                )?));
+            }
+        }
+    }
+    let acceptor = JoinedAcceptor::new(
+        JoinedAcceptor::new(Acceptors::new(tcp), Acceptors::new(tls)),
+        JoinedAcceptor::new(Acceptors::new(unix), Acceptors::new(quic)),
+    );
+    Ok((acceptor, quic_info))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::fd::OwnedFd;
+
+    use super::{InheritedKind, Listen, TlsFiles, UnixPermissions, inherited_kind, parse_listen};
+
+    #[test]
+    fn test_parse_listen() {
+        assert_eq!(
+            parse_listen("tcp://0.0.0.0:80"),
+            Ok(Listen::Tcp(([0, 0, 0, 0], 80).into()))
+        );
+        assert_eq!(
+            parse_listen("tls://[::]:443?cert=/etc/cert.pem&key=/etc/key.pem"),
+            Ok(Listen::Tls {
+                addr: "[::]:443".parse().unwrap(),
+                tls: Some(TlsFiles {
+                    cert: "/etc/cert.pem".to_string(),
+                    key: "/etc/key.pem".to_string(),
+                }),
+            })
+        );
+        assert_eq!(
+            parse_listen("quic://[::1]:8123"),
+            Ok(Listen::Quic {
+                addr: "[::1]:8123".parse().unwrap(),
+                tls: None,
+            })
+        );
+        assert_eq!(
+            parse_listen("unix:/run/foxtime.sock?group=www-data&mode=660"),
+            Ok(Listen::Unix {
+                path: "/run/foxtime.sock".to_string(),
+                permissions: UnixPermissions {
+                    owner: None,
+                    group: Some("www-data".to_string()),
+                    mode: Some(0o660),
+                },
+            })
+        );
+
+        assert!(parse_listen("tcp://localhost:80").is_err());
+        assert!(parse_listen("tcp:0.0.0.0:80").is_err());
+        assert!(parse_listen("tls://[::]:443?cert=/etc/cert.pem").is_err());
+        assert!(parse_listen("unix:/run/foxtime.sock?mode=999").is_err());
+        assert_eq!(
+            parse_listen("tcp://[::]:80?mode=600"),
+            Err("Unknown option for tcp: mode".to_string())
+        );
+        assert_eq!(
+            parse_listen("udp://[::]:80"),
+            Err("Unknown scheme: udp".to_string())
+        );
+    }
+
+    #[test]
+    fn test_inherited_kind() {
+        let tcp = || OwnedFd::from(std::net::TcpListener::bind("127.0.0.1:0").unwrap());
+        assert_eq!(
+            inherited_kind("unknown", &tcp()).unwrap(),
+            InheritedKind::Tcp
+        );
+        assert_eq!(inherited_kind("tls", &tcp()).unwrap(), InheritedKind::Tls);
+
+        let udp = OwnedFd::from(std::net::UdpSocket::bind("127.0.0.1:0").unwrap());
+        let err = inherited_kind("unknown", &udp).unwrap_err();
+        assert!(err.to_string().starts_with("QUIC cannot be served"));
+        assert!(inherited_kind("tls", &udp).is_err());
+        assert!(inherited_kind("quic", &tcp()).is_err());
+
+        let path =
+            std::env::temp_dir().join(format!("foxtime-inherited-{}.sock", std::process::id()));
+        let unix = OwnedFd::from(std::os::unix::net::UnixListener::bind(&path).unwrap());
+        assert_eq!(inherited_kind("web", &unix).unwrap(), InheritedKind::Unix);
+        assert!(inherited_kind("tcp", &unix).is_err());
+        std::fs::remove_file(path).unwrap();
+    }
+}
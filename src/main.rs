@@ -1,25 +1,52 @@
 use anyhow::Context;
 use clap::Parser;
 use privdrop::PrivDrop;
-use salvo::conn::rustls::{Keycert, RustlsConfig};
-use salvo::conn::{Acceptor, JoinedAcceptor};
 use salvo::prelude::*;
 
+mod acme;
 mod assets;
+mod chaos;
+mod clock;
+mod config;
+mod daemon;
+mod dns;
+mod fake;
+mod federation;
+mod gps;
+mod history;
 mod http;
+mod listen;
+mod ntp;
+mod probe;
+mod proxy;
+mod ptp;
+mod recording;
 mod router;
 mod self_signed;
+mod shutdown;
+mod signing;
+mod systemd;
+mod telemetry;
+mod tls;
+mod tsa;
+mod wait;
 mod websocket;
 mod webtransport;
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    #[arg(long)]
+    config: Option<String>,
+
+    #[arg(long, value_parser = listen::parse_listen, conflicts_with_all = ["listen_any", "port", "unix", "quic", "quic_port"])]
+    listen: Vec<listen::Listen>,
+
     #[arg(long, default_value_t = false)]
     listen_any: bool,
 
-    #[arg(long, conflicts_with = "unix", default_value_t = 8123)]
-    port: u16,
+    #[arg(long, conflicts_with = "unix")]
+    port: Option<u16>,
 
     #[arg(long)]
     unix: Option<String>,
@@ -39,12 +66,157 @@ struct Args {
     #[arg(long, requires = "tls_cert")]
     tls_key: Option<String>,
 
+    #[arg(long, conflicts_with_all = ["tls_cert", "chroot"])]
+    acme_domain: Vec<String>,
+
+    #[arg(long, requires = "acme_domain", default_value = "acme")]
+    acme_dir: String,
+
+    #[arg(long, requires = "acme_domain", default_value = salvo::acme::LETS_ENCRYPT_PRODUCTION)]
+    acme_directory: String,
+
+    #[arg(long, requires = "acme_domain")]
+    acme_contact: Option<String>,
+
+    #[arg(long, requires = "acme_domain", value_enum, default_value_t = acme::Challenge::TlsAlpn01)]
+    acme_challenge: acme::Challenge,
+
     #[arg(long, default_value_t = false)]
     quic: bool,
 
     #[arg(long, default_value_t = 8123)]
     quic_port: u16,
 
+    #[arg(long, conflicts_with = "chroot")]
+    self_signed_dir: Option<String>,
+
+    #[arg(long)]
+    self_signed_name: Vec<String>,
+
+    #[arg(long)]
+    dns_name: Option<String>,
+
+    #[arg(long, requires = "dns_name", default_value_t = 53)]
+    dns_port: u16,
+
+    #[arg(long, value_enum)]
+    ptp: Vec<ptp::PtpTransport>,
+
+    #[arg(long, requires = "ptp", default_value_t = 0)]
+    ptp_domain: u8,
+
+    // currentUtcOffset is a signed 16-bit field, but TAI is ahead of UTC.
+    #[arg(long, requires = "ptp", default_value_t = 37, value_parser = clap::value_parser!(u16).range(..=i16::MAX as i64))]
+    ptp_utc_offset: u16,
+
+    #[arg(long, requires = "ptp")]
+    ptp_interface: Option<String>,
+
+    #[arg(long)]
+    gps_device: Option<String>,
+
+    #[arg(long, requires = "gps_device", default_value_t = 9600)]
+    gps_baud: u32,
+
+    #[arg(long, requires = "gps_device", default_value_t = 0.0)]
+    gps_delay: f64,
+
+    #[arg(long, requires = "gps_device")]
+    gps_pps: Option<String>,
+
+    #[arg(long, requires = "gps_device", conflicts_with = "gps_pps", value_parser = gps::parse_gpio_line)]
+    gps_pps_gpio: Option<gps::PpsSource>,
+
+    #[arg(long, conflicts_with = "gps_device")]
+    ntp_server: Vec<String>,
+
+    #[arg(long, requires = "ntp_server", default_value_t = 64, value_parser = clap::value_parser!(u64).range(1..))]
+    ntp_poll: u64,
+
+    #[arg(long, num_args = 0..=1, default_missing_value = daemon::DEFAULT_CHRONY_SOCKET)]
+    chrony: Option<String>,
+
+    #[arg(long, num_args = 0..=1, default_missing_value = daemon::DEFAULT_NTPD_ADDR, conflicts_with = "chrony")]
+    ntpd: Option<String>,
+
+    #[arg(long)]
+    peer: Vec<String>,
+
+    #[arg(long, requires = "peer", default_value_t = 0.05, value_parser = federation::parse_threshold)]
+    peer_threshold: f64,
+
+    #[arg(long, requires = "peer", default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+    peer_interval: u64,
+
+    #[arg(long, value_parser = chaos::parse_chaos)]
+    chaos_http: Option<chaos::Chaos>,
+
+    #[arg(long, value_parser = chaos::parse_chaos)]
+    chaos_ws: Option<chaos::Chaos>,
+
+    #[arg(long, value_parser = chaos::parse_chaos)]
+    chaos_wt: Option<chaos::Chaos>,
+
+    #[arg(long)]
+    history: Option<String>,
+
+    #[arg(long, requires = "history", default_value_t = 60, value_parser = clap::value_parser!(u64).range(1..))]
+    history_interval: u64,
+
+    #[arg(long, default_value_t = false)]
+    telemetry: bool,
+
+    #[arg(long, requires = "admin_token_file", value_parser = clap::value_parser!(u64).range(1..))]
+    probe_interval: Option<u64>,
+
+    #[arg(long)]
+    admin_token_file: Option<String>,
+
+    #[arg(long)]
+    record: Option<String>,
+
+    #[arg(long)]
+    replay: Option<String>,
+
+    #[arg(long, requires = "replay")]
+    replay_session: Option<u64>,
+
+    #[arg(long, allow_negative_numbers = true, value_parser = fake::parse_offset)]
+    fake_offset: Option<f64>,
+
+    #[arg(long, allow_negative_numbers = true, value_parser = fake::parse_skew_ppm)]
+    fake_skew_ppm: Option<f64>,
+
+    #[arg(long)]
+    fake_script: Option<String>,
+
+    #[arg(long)]
+    signing_key: Option<String>,
+
+    #[arg(long, requires = "signing_key")]
+    signing_identity: Option<String>,
+
+    #[arg(long, requires_all = ["tsa_key", "tsa_policy"])]
+    tsa_cert: Option<String>,
+
+    #[arg(long, requires = "tsa_cert")]
+    tsa_key: Option<String>,
+
+    #[arg(long, requires = "tsa_cert")]
+    tsa_policy: Option<String>,
+
+    #[arg(long, value_parser = proxy::parse_source)]
+    proxy_protocol: Vec<proxy::Source>,
+
+    #[arg(long, value_parser = proxy::parse_source)]
+    trusted_proxy: Vec<proxy::Source>,
+
+    #[arg(long, value_parser = router::parse_base_path)]
+    base_path: Option<String>,
+
+    #[arg(long, default_value_t = 10)]
+    drain_timeout: u64,
+
     #[arg(long)]
     user: Option<String>,
 
@@ -59,35 +231,6 @@ fn parse_octal(s: &str) -> Result<u32, String> {
     u32::from_str_radix(s, 8).map_err(|e| e.to_string())
 }
 
-fn set_unix_permissions(unix: &str, args: &Args) -> anyhow::Result<()> {
-    if args.unix_owner.is_some() || args.unix_group.is_some() {
-        let user = args
-            .unix_owner
-            .as_deref()
-            .map(|name| {
-                nix::unistd::User::from_name(name)
-                    .context("Look up user")
-                    .and_then(|u| u.ok_or_else(|| anyhow::anyhow!("User not found: {}", name)))
-            })
-            .transpose()?;
-        let group = args
-            .unix_group
-            .as_deref()
-            .map(|name| {
-                nix::unistd::Group::from_name(name)
-                    .context("Look up group")
-                    .and_then(|g| g.ok_or_else(|| anyhow::anyhow!("Group not found: {}", name)))
-            })
-            .transpose()?;
-        nix::unistd::chown(unix, user.map(|u| u.uid), group.map(|g| g.gid))?;
-    }
-    if let Some(mode) = args.unix_mode {
-        use std::os::unix::fs::PermissionsExt;
-        std::fs::set_permissions(unix, std::fs::Permissions::from_mode(mode))?;
-    }
-    Ok(())
-}
-
 fn apply_privdrop(args: &Args) -> anyhow::Result<()> {
     if args.user.is_some() || args.group.is_some() || args.chroot.is_some() {
         let mut pd = PrivDrop::default();
@@ -105,162 +248,51 @@ fn apply_privdrop(args: &Args) -> anyhow::Result<()> {
     Ok(())
 }
 
-// QuinnListener::try_bind() only stores the socket address; the UDP socket is
-// not actually bound until the first QuinnAcceptor::accept() call during
-// serving — which happens after apply_privdrop(). For privileged ports this
-// fails. Work around it by polling accept() once with a zero timeout:
-// Endpoint::server() runs synchronously before the first await point in
-// accept(), so the UDP socket gets bound before the future is cancelled.
-async fn bind_quinn_listener(
-    config: RustlsConfig,
-    addr: std::net::SocketAddr,
-) -> anyhow::Result<impl Acceptor> {
-    let mut acceptor = QuinnListener::new(config, addr).bind().await;
-    match tokio::time::timeout(std::time::Duration::ZERO, acceptor.accept(None)).await {
-        Ok(Err(e)) => return Err(e.into()),
-        _ => {}
+/// The listeners given to `--listen`, or else the equivalent of `--port`,
+/// `--listen-any`, `--unix` and `--quic`.
+fn listens(args: &Args) -> Vec<listen::Listen> {
+    if !args.listen.is_empty() {
+        return args.listen.clone();
     }
-    Ok(acceptor)
-}
-
-async fn bind_quinn_localhost(config: RustlsConfig, port: u16) -> anyhow::Result<impl Acceptor> {
-    let quic_v4 =
-        bind_quinn_listener(config.clone(), (std::net::Ipv4Addr::LOCALHOST, port).into()).await?;
-    let quic_v6 = bind_quinn_listener(config, (std::net::Ipv6Addr::LOCALHOST, port).into()).await?;
-    Ok(JoinedAcceptor::new(quic_v4, quic_v6))
-}
-
-async fn serve_unix(
-    unix_path: String,
-    quic_rustls_config: Option<RustlsConfig>,
-    args: &Args,
-    router: Router,
-) -> anyhow::Result<()> {
-    if std::path::Path::new(&unix_path).exists() {
-        let meta = std::fs::metadata(&unix_path).with_context(|| format!("Stat {unix_path}"))?;
-        if !std::os::unix::fs::FileTypeExt::is_socket(&meta.file_type()) {
-            anyhow::bail!("{unix_path} exists and is not a Unix socket");
-        }
-        match std::os::unix::net::UnixStream::connect(&unix_path) {
-            Ok(_) => anyhow::bail!("{unix_path} is already in use by another process"),
-            Err(e) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
-                std::fs::remove_file(&unix_path)
-                    .with_context(|| format!("Remove stale socket {unix_path}"))?;
-            }
-            Err(e) => {
-                return Err(anyhow::Error::from(e))
-                    .with_context(|| format!("Probe socket {unix_path}"));
-            }
-        }
-    }
-    let unix_acceptor = UnixListener::new(unix_path.clone()).bind().await;
-    set_unix_permissions(&unix_path, args)?;
-    if let Some(config) = quic_rustls_config {
-        if args.listen_any {
-            let quic = bind_quinn_listener(
-                config,
-                (std::net::Ipv6Addr::UNSPECIFIED, args.quic_port).into(),
-            )
-            .await?;
-            apply_privdrop(args)?;
-            Server::new(JoinedAcceptor::new(unix_acceptor, quic))
-                .serve(router)
-                .await;
-        } else {
-            let quic = bind_quinn_localhost(config, args.quic_port).await?;
-            apply_privdrop(args)?;
-            Server::new(JoinedAcceptor::new(unix_acceptor, quic))
-                .serve(router)
-                .await;
-        }
-    } else {
-        apply_privdrop(args)?;
-        Server::new(unix_acceptor).serve(router).await;
-    }
-    Ok(())
-}
-
-async fn serve_any(
-    rustls_config: Option<RustlsConfig>,
-    quic_rustls_config: Option<RustlsConfig>,
-    args: &Args,
-    router: Router,
-) -> anyhow::Result<()> {
     // Bind to the IPv6 wildcard (::) which is dual-stack by default on Linux and macOS,
     // covering both IPv4 and IPv6 clients with a single socket.
-    let http_addr = (std::net::Ipv6Addr::UNSPECIFIED, args.port);
-    let quic_addr = (std::net::Ipv6Addr::UNSPECIFIED, args.quic_port);
-    let quic = if let Some(config) = quic_rustls_config {
-        Some(bind_quinn_listener(config, quic_addr.into()).await?)
+    let hosts: Vec<std::net::IpAddr> = if args.listen_any {
+        vec![std::net::Ipv6Addr::UNSPECIFIED.into()]
     } else {
-        None
+        vec![
+            std::net::Ipv4Addr::LOCALHOST.into(),
+            std::net::Ipv6Addr::LOCALHOST.into(),
+        ]
     };
-    if let Some(config) = rustls_config {
-        let tcp = TcpListener::new(http_addr).rustls(config).bind().await;
-        apply_privdrop(args)?;
-        if let Some(quic) = quic {
-            Server::new(JoinedAcceptor::new(tcp, quic))
-                .serve(router)
-                .await;
-        } else {
-            Server::new(tcp).serve(router).await;
-        }
+    let mut listens = Vec::new();
+    if let Some(path) = &args.unix {
+        listens.push(listen::Listen::Unix {
+            path: path.clone(),
+            permissions: listen::UnixPermissions {
+                owner: args.unix_owner.clone(),
+                group: args.unix_group.clone(),
+                mode: args.unix_mode,
+            },
+        });
     } else {
-        let tcp = TcpListener::new(http_addr).bind().await;
-        apply_privdrop(args)?;
-        if let Some(quic) = quic {
-            Server::new(JoinedAcceptor::new(tcp, quic))
-                .serve(router)
-                .await;
-        } else {
-            Server::new(tcp).serve(router).await;
+        for &host in &hosts {
+            let addr = (host, args.port.unwrap_or(8123)).into();
+            listens.push(if args.tls_cert.is_some() || !args.acme_domain.is_empty() {
+                listen::Listen::Tls { addr, tls: None }
+            } else {
+                listen::Listen::Tcp(addr)
+            });
         }
     }
-    Ok(())
-}
-
-async fn serve_localhost(
-    rustls_config: Option<RustlsConfig>,
-    quic_rustls_config: Option<RustlsConfig>,
-    args: &Args,
-    router: Router,
-) -> anyhow::Result<()> {
-    let http_v4 = (std::net::Ipv4Addr::LOCALHOST, args.port);
-    let http_v6 = (std::net::Ipv6Addr::LOCALHOST, args.port);
-    let quic = if let Some(config) = quic_rustls_config {
-        Some(bind_quinn_localhost(config, args.quic_port).await?)
-    } else {
-        None
-    };
-    if let Some(config) = rustls_config {
-        let tcp = TcpListener::new(http_v4)
-            .rustls(config.clone())
-            .join(TcpListener::new(http_v6).rustls(config))
-            .bind()
-            .await;
-        apply_privdrop(args)?;
-        if let Some(quic) = quic {
-            Server::new(JoinedAcceptor::new(tcp, quic))
-                .serve(router)
-                .await;
-        } else {
-            Server::new(tcp).serve(router).await;
-        }
-    } else {
-        let tcp = TcpListener::new(http_v4)
-            .join(TcpListener::new(http_v6))
-            .bind()
-            .await;
-        apply_privdrop(args)?;
-        if let Some(quic) = quic {
-            Server::new(JoinedAcceptor::new(tcp, quic))
-                .serve(router)
-                .await;
-        } else {
-            Server::new(tcp).serve(router).await;
+    if args.quic {
+        for &host in &hosts {
+            listens.push(listen::Listen::Quic {
+                addr: (host, args.quic_port).into(),
+                tls: None,
+            });
         }
     }
-    Ok(())
+    listens
 }
 
 #[tokio::main]
@@ -273,49 +305,226 @@ async fn main() -> anyhow::Result<()> {
         .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
         .init();
 
-    let args = Args::parse();
+    let args: Args = config::parse()?;
 
-    let rustls_config = if let (Some(cert_path), Some(key_path)) = (&args.tls_cert, &args.tls_key) {
-        let cert_pem = std::fs::read_to_string(cert_path)?;
-        let key_pem = std::fs::read_to_string(key_path)?;
-        Some(RustlsConfig::new(
-            Keycert::new()
-                .cert(cert_pem.as_bytes())
-                .key(key_pem.as_bytes()),
-        ))
-    } else {
-        None
-    };
+    if args.fake_offset.is_some() || args.fake_skew_ppm.is_some() || args.fake_script.is_some() {
+        let steps = match &args.fake_script {
+            Some(path) => fake::parse_script(
+                &std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?,
+            )
+            .with_context(|| format!("Invalid fake clock script {path}"))?,
+            None => Vec::new(),
+        };
+        tracing::warn!("Serving fake time");
+        fake::set_fake_clock(fake::FakeClock {
+            offset: args.fake_offset.unwrap_or(0.0),
+            skew_ppm: args.fake_skew_ppm.unwrap_or(0.0),
+            steps,
+        });
+    }
+
+    if args
+        .chaos_http
+        .is_some_and(|c| c.drop > 0.0 || c.duplicate > 0.0)
+    {
+        anyhow::bail!("--chaos-http supports delays only");
+    }
+    if args.chaos_http.is_some() || args.chaos_ws.is_some() || args.chaos_wt.is_some() {
+        tracing::warn!("Injecting network chaos into time responses");
+        chaos::set_chaos(args.chaos_http, args.chaos_ws, args.chaos_wt);
+    }
+
+    if let Some(path) = &args.history {
+        history::spawn(path, std::time::Duration::from_secs(args.history_interval))?;
+    }
+
+    if args.telemetry {
+        telemetry::enable();
+    }
+
+    if let Some(path) = &args.admin_token_file {
+        probe::set_admin_token_file(path)?;
+    }
+    if let Some(interval) = args.probe_interval {
+        probe::set_interval(std::time::Duration::from_secs(interval));
+    }
 
-    let (quic_rustls_config, quic_cert_hash) = if args.quic {
-        if let Some(config) = &rustls_config {
-            (Some(config.clone()), String::new())
+    if let Some(path) = &args.record {
+        recording::set_recorder(path)?;
+    }
+    if let Some(path) = &args.replay {
+        let records = recording::load_session(
+            &std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?,
+            args.replay_session,
+        )
+        .with_context(|| format!("Invalid recording {path}"))?;
+        tracing::warn!(
+            "Replaying session {} ({} exchanges)",
+            records[0].session,
+            records.len()
+        );
+        recording::set_replay(records);
+    }
+
+    if let Some(dns_name) = &args.dns_name {
+        let zone = std::sync::Arc::new(dns::Zone::new(dns_name)?);
+        if args.listen_any {
+            dns::spawn(
+                zone,
+                (std::net::Ipv6Addr::UNSPECIFIED, args.dns_port).into(),
+            )
+            .await?;
         } else {
-            let (config, cert_hash) = self_signed::generate()?;
-            (Some(config), cert_hash)
+            dns::spawn(
+                zone.clone(),
+                (std::net::Ipv4Addr::LOCALHOST, args.dns_port).into(),
+            )
+            .await?;
+            dns::spawn(zone, (std::net::Ipv6Addr::LOCALHOST, args.dns_port).into()).await?;
         }
-    } else {
-        (None, String::new())
-    };
+    }
 
-    assets::set_quic_info(if args.quic {
-        Some(assets::QuicInfo {
-            port: args.quic_port,
-            cert_hash: quic_cert_hash,
-        })
-    } else {
-        None
-    });
+    if !args.ptp.is_empty() {
+        let config = ptp::PtpConfig {
+            domain: args.ptp_domain,
+            utc_offset: args.ptp_utc_offset,
+            interface: args.ptp_interface.clone(),
+        };
+        for transport in &args.ptp {
+            ptp::PtpPort::bind(&config, *transport)?.spawn();
+        }
+    }
 
-    let router = router::router();
+    if let Some(device) = &args.gps_device {
+        gps::spawn(
+            gps::GpsConfig {
+                device: device.clone(),
+                baud: args.gps_baud,
+                delay: args.gps_delay,
+                pps: args
+                    .gps_pps
+                    .clone()
+                    .map(gps::PpsSource::Device)
+                    .or(args.gps_pps_gpio.clone()),
+            },
+            clock::set_reference,
+        )?;
+    }
 
-    if let Some(unix_path) = args.unix.clone() {
-        serve_unix(unix_path, quic_rustls_config, &args, router).await?;
-    } else if args.listen_any {
-        serve_any(rustls_config, quic_rustls_config, &args, router).await?;
-    } else {
-        serve_localhost(rustls_config, quic_rustls_config, &args, router).await?;
+    if !args.ntp_server.is_empty() {
+        ntp::spawn(
+            ntp::NtpConfig {
+                servers: args.ntp_server.clone(),
+                poll: std::time::Duration::from_secs(args.ntp_poll),
+            },
+            clock::set_reference,
+        );
+    }
+
+    if let Some(addr) = &args.chrony {
+        daemon::spawn(daemon::Daemon::chrony(addr).await?);
+    } else if let Some(addr) = &args.ntpd {
+        daemon::spawn(daemon::Daemon::ntpd(addr).await?);
+    }
+
+    if !args.peer.is_empty() {
+        federation::spawn(federation::FederationConfig {
+            peers: args.peer.clone(),
+            threshold: args.peer_threshold,
+            interval: std::time::Duration::from_secs(args.peer_interval),
+        });
+    }
+
+    signing::set_signer(
+        args.signing_key
+            .as_deref()
+            .map(|path| {
+                let identity = match &args.signing_identity {
+                    Some(identity) => identity.clone(),
+                    None => nix::unistd::gethostname()?.to_string_lossy().into_owned(),
+                };
+                signing::Signer::from_pem_file(path, identity)
+            })
+            .transpose()?,
+    );
+
+    tsa::set_authority(
+        if let (Some(cert_path), Some(key_path), Some(policy)) =
+            (&args.tsa_cert, &args.tsa_key, &args.tsa_policy)
+        {
+            let cert_pem = std::fs::read_to_string(cert_path)?;
+            let key_pem = std::fs::read_to_string(key_path)?;
+            Some(tsa::Authority::new(
+                cert_pem.as_bytes(),
+                key_pem.as_bytes(),
+                policy,
+            )?)
+        } else {
+            None
+        },
+    );
+
+    if let Some(dir) = &args.self_signed_dir {
+        self_signed::set_dir(dir)?;
     }
+    if !args.self_signed_name.is_empty() {
+        self_signed::set_names(args.self_signed_name.clone());
+    }
+
+    if !args.proxy_protocol.is_empty() {
+        proxy::set_proxy_protocol(args.proxy_protocol.clone());
+    }
+    if !args.trusted_proxy.is_empty() {
+        proxy::set_trusted_proxies(args.trusted_proxy.clone());
+    }
+    if let Some(path) = &args.base_path {
+        router::set_base_path(path.clone());
+    }
+
+    let tls = match (&args.tls_cert, &args.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            listen::TlsFiles {
+                cert: cert.clone(),
+                key: key.clone(),
+            }
+            .load()?,
+        ),
+        _ if !args.acme_domain.is_empty() => Some(
+            acme::start(acme::AcmeConfig {
+                domains: args.acme_domain.clone(),
+                dir: args.acme_dir.clone(),
+                directory: args.acme_directory.clone(),
+                contact: args.acme_contact.clone(),
+                challenge: args.acme_challenge,
+            })
+            .await?,
+        ),
+        _ => None,
+    };
+    // Sockets passed by the service manager replace the default ones.
+    let inherited = systemd::listen_fds()?;
+    let listens = if inherited.is_empty() || !args.listen.is_empty() {
+        listens(&args)
+    } else if args.quic || args.port.is_some() {
+        // Inherited sockets replace the default listeners, and QUIC cannot be
+        // served from an inherited socket, so these would be ignored.
+        anyhow::bail!(
+            "--quic and --port cannot be used with inherited sockets; use --listen quic://ADDR"
+        );
+    } else {
+        Vec::new()
+    };
+    let (acceptor, quic_info) = listen::bind(&listens, inherited, tls).await?;
+    assets::set_quic_info(quic_info);
+    apply_privdrop(&args)?;
+    let server = Server::new(systemd::Heartbeat::new(acceptor));
+    shutdown::spawn(
+        server.handle(),
+        std::time::Duration::from_secs(args.drain_timeout),
+    )?;
+    systemd::notify("READY=1");
+    systemd::spawn_watchdog();
+    server.serve(router::router()).await;
 
     Ok(())
 }
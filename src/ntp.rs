@@ -0,0 +1,436 @@
+use std::net::SocketAddr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use futures_util::future::join_all;
+use tokio::net::UdpSocket;
+
+const NTP_PORT: u16 = 123;
+const PACKET_LEN: usize = 48;
+
+/// Seconds from the NTP era 0 epoch (1900) to the Unix epoch.
+const UNIX_OFFSET: u64 = 2_208_988_800;
+
+/// Frequency tolerance (15 PPM).
+const PHI: f64 = 15e-6;
+/// Our clock precision, about a microsecond.
+const PRECISION: f64 = 1e-6;
+/// Number of clock filter stages.
+const NSTAGE: usize = 8;
+/// Dispersion of an empty filter stage, in seconds.
+const MAXDISP: f64 = 16.0;
+/// Minimum root distance increment, in seconds.
+const MINDISP: f64 = 0.01;
+/// Root distance beyond which a server is not a candidate, in seconds.
+const MAXDIST: f64 = 1.5;
+/// Number of survivors the cluster algorithm keeps at least.
+const NMIN: usize = 3;
+const MAXSTRAT: u8 = 16;
+
+const QUERY_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Poll interval while filling the clock filters at startup, as with ntpd's
+/// iburst, so that the root distance soon drops below MAXDIST.
+const BURST_INTERVAL: Duration = Duration::from_secs(2);
+
+const LEAP_UNSYNCHRONIZED: u8 = 3;
+const MODE_CLIENT: u8 = 3;
+const MODE_SERVER: u8 = 4;
+const VERSION: u8 = 4;
+
+#[derive(Clone, Debug)]
+pub(crate) struct NtpConfig {
+    /// Servers as `HOST` or `HOST:PORT`.
+    pub(crate) servers: Vec<String>,
+    pub(crate) poll: Duration,
+}
+
+fn unix_seconds(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+/// Converts Unix seconds to an NTP timestamp.
+fn to_timestamp(unix: f64) -> u64 {
+    let ntp = unix + UNIX_OFFSET as f64;
+    let secs = ntp.floor();
+    ((secs as u64) << 32) | ((ntp - secs) * 4_294_967_296.0) as u64
+}
+
+/// Converts an NTP timestamp to Unix seconds, assuming era 0.
+fn from_timestamp(ts: u64) -> f64 {
+    (ts >> 32) as f64 - UNIX_OFFSET as f64 + (ts & 0xffff_ffff) as f64 / 4_294_967_296.0
+}
+
+/// Converts an NTP short format (16.16) value to seconds.
+fn from_short(bytes: &[u8]) -> f64 {
+    u32::from_be_bytes(bytes.try_into().unwrap()) as f64 / 65536.0
+}
+
+/// One measurement of a server's offset from the system clock.
+#[derive(Clone, Copy, Debug)]
+struct Sample {
+    offset: f64,
+    delay: f64,
+    dispersion: f64,
+    root_delay: f64,
+    root_dispersion: f64,
+    stratum: u8,
+    time: Instant,
+}
+
+async fn resolve(server: &str) -> anyhow::Result<SocketAddr> {
+    if let Ok(ip) = server.parse::<std::net::IpAddr>() {
+        return Ok((ip, NTP_PORT).into());
+    }
+    if let Ok(addr) = server.parse::<SocketAddr>() {
+        return Ok(addr);
+    }
+    let host = if server.contains(':') {
+        server.to_string()
+    } else {
+        format!("{server}:{NTP_PORT}")
+    };
+    tokio::net::lookup_host(&host)
+        .await?
+        .next()
+        .with_context(|| format!("No addresses for {server}"))
+}
+
+/// Sends a client mode request to `server` and measures its offset.
+async fn query(server: &str) -> anyhow::Result<Sample> {
+    let addr = resolve(server).await?;
+    let socket = if addr.is_ipv4() {
+        UdpSocket::bind((std::net::Ipv4Addr::UNSPECIFIED, 0)).await?
+    } else {
+        UdpSocket::bind((std::net::Ipv6Addr::UNSPECIFIED, 0)).await?
+    };
+    socket.connect(addr).await?;
+
+    let mut request = [0u8; PACKET_LEN];
+    request[0] = (VERSION << 3) | MODE_CLIENT;
+    let t1 = unix_seconds(SystemTime::now());
+    let origin = to_timestamp(t1).to_be_bytes();
+    request[40..48].copy_from_slice(&origin);
+    socket.send(&request).await?;
+
+    let mut response = [0u8; 1024];
+    let len = loop {
+        let len = tokio::time::timeout(QUERY_TIMEOUT, socket.recv(&mut response))
+            .await
+            .context("No response")??;
+        // Ignore stray packets that do not answer this request.
+        if len >= PACKET_LEN && response[24..32] == origin {
+            break len;
+        }
+    };
+    let t4 = unix_seconds(SystemTime::now());
+    let response = &response[..len];
+
+    let leap = response[0] >> 6;
+    let mode = response[0] & 0x07;
+    let stratum = response[1];
+    if mode != MODE_SERVER {
+        anyhow::bail!("Unexpected mode {mode}");
+    }
+    if stratum == 0 {
+        anyhow::bail!(
+            "Kiss-o'-Death {}",
+            String::from_utf8_lossy(&response[12..16])
+        );
+    }
+    if leap == LEAP_UNSYNCHRONIZED || stratum >= MAXSTRAT {
+        anyhow::bail!("Server is unsynchronized");
+    }
+    let precision = 2f64.powi(response[3] as i8 as i32);
+    let t2 = from_timestamp(u64::from_be_bytes(response[32..40].try_into().unwrap()));
+    let t3 = from_timestamp(u64::from_be_bytes(response[40..48].try_into().unwrap()));
+
+    Ok(Sample {
+        offset: ((t2 - t1) + (t3 - t4)) / 2.0,
+        delay: ((t4 - t1) - (t3 - t2)).max(PRECISION),
+        dispersion: precision + PRECISION + PHI * (t4 - t1),
+        root_delay: from_short(&response[4..8]),
+        root_dispersion: from_short(&response[8..12]),
+        stratum,
+        time: Instant::now(),
+    })
+}
+
+/// A server and its clock filter (RFC 5905 section 10).
+struct Peer {
+    server: String,
+    /// Filter stages, newest first.
+    stages: [Option<Sample>; NSTAGE],
+    /// Reachability shift register, one bit per poll.
+    reach: u8,
+    /// The sample chosen by the filter, with the peer dispersion and jitter.
+    chosen: Option<(Sample, f64, f64)>,
+}
+
+impl Peer {
+    fn new(server: String) -> Self {
+        Self {
+            server,
+            stages: [None; NSTAGE],
+            reach: 0,
+            chosen: None,
+        }
+    }
+
+    fn update(&mut self, sample: Option<Sample>) {
+        self.reach <<= 1;
+        if sample.is_some() {
+            self.reach |= 1;
+        }
+        self.stages.rotate_right(1);
+        self.stages[0] = sample;
+
+        let now = Instant::now();
+        let mut stages: Vec<(f64, f64, Option<&Sample>)> = self
+            .stages
+            .iter()
+            .map(|s| match s {
+                Some(s) => (
+                    s.delay,
+                    s.dispersion + PHI * now.duration_since(s.time).as_secs_f64(),
+                    Some(s),
+                ),
+                None => (MAXDISP, MAXDISP, None),
+            })
+            .collect();
+        stages.sort_by(|a, b| a.0.total_cmp(&b.0));
+
+        let Some(best) = stages[0].2.copied() else {
+            self.chosen = None;
+            return;
+        };
+        let dispersion = stages
+            .iter()
+            .enumerate()
+            .map(|(i, s)| s.1 / 2f64.powi(i as i32 + 1))
+            .sum();
+        let offsets: Vec<f64> = stages
+            .iter()
+            .filter_map(|s| s.2.map(|s| s.offset))
+            .collect();
+        let jitter = if offsets.len() > 1 {
+            (offsets[1..]
+                .iter()
+                .map(|o| (o - best.offset).powi(2))
+                .sum::<f64>()
+                / (offsets.len() - 1) as f64)
+                .sqrt()
+        } else {
+            0.0
+        }
+        .max(PRECISION);
+        self.chosen = Some((best, dispersion, jitter));
+    }
+
+    /// A candidate for selection: (offset, root distance, jitter).
+    fn candidate(&self) -> Option<(f64, f64, f64)> {
+        let (sample, dispersion, jitter) = self.chosen?;
+        if self.reach == 0 {
+            return None;
+        }
+        let distance = (sample.root_delay + sample.delay).max(MINDISP) / 2.0
+            + sample.root_dispersion
+            + dispersion
+            + PHI * sample.time.elapsed().as_secs_f64()
+            + jitter;
+        (distance < MAXDIST && sample.stratum < MAXSTRAT).then_some((
+            sample.offset,
+            distance,
+            jitter,
+        ))
+    }
+}
+
+/// Runs the selection, cluster and combine algorithms (RFC 5905 section 11.2)
+/// and returns the system offset and root distance, in seconds.
+fn select(candidates: &[(f64, f64, f64)]) -> Option<(f64, f64)> {
+    let n = candidates.len();
+    if n == 0 {
+        return None;
+    }
+
+    // Find the intersection of the correctness intervals of a majority.
+    let mut endpoints: Vec<(f64, i32)> = candidates
+        .iter()
+        .flat_map(|&(offset, distance, _)| {
+            [(offset - distance, 1), (offset, 0), (offset + distance, -1)]
+        })
+        .collect();
+    endpoints.sort_by(|a, b| a.0.total_cmp(&b.0));
+    let (low, high) = (0..n.div_ceil(2)).find_map(|allow| {
+        let needed = (n - allow) as i32;
+        let mut found = 0;
+        let (mut chime, mut low) = (0, None);
+        for &(value, kind) in &endpoints {
+            chime += kind;
+            if chime >= needed {
+                low = Some(value);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+        let (mut chime, mut high) = (0, None);
+        for &(value, kind) in endpoints.iter().rev() {
+            chime -= kind;
+            if chime >= needed {
+                high = Some(value);
+                break;
+            }
+            if kind == 0 {
+                found += 1;
+            }
+        }
+        match (low, high) {
+            (Some(low), Some(high)) if found <= allow && low < high => Some((low, high)),
+            _ => None,
+        }
+    })?;
+
+    // Truechimers, by increasing root distance.
+    let mut survivors: Vec<(f64, f64, f64)> = candidates
+        .iter()
+        .copied()
+        .filter(|&(offset, _, _)| offset >= low && offset <= high)
+        .collect();
+    survivors.sort_by(|a, b| a.1.total_cmp(&b.1));
+
+    // Cast off outliers while that reduces the selection jitter.
+    while survivors.len() > NMIN {
+        let selection_jitter = |i: usize| {
+            (survivors
+                .iter()
+                .map(|s| (s.0 - survivors[i].0).powi(2))
+                .sum::<f64>()
+                / (survivors.len() - 1) as f64)
+                .sqrt()
+        };
+        let (worst, max_jitter) = (0..survivors.len())
+            .map(|i| (i, selection_jitter(i)))
+            .max_by(|a, b| a.1.total_cmp(&b.1))?;
+        let min_peer_jitter = survivors
+            .iter()
+            .map(|s| s.2)
+            .min_by(|a, b| a.total_cmp(b))?;
+        if max_jitter < min_peer_jitter {
+            break;
+        }
+        survivors.remove(worst);
+    }
+
+    let weight: f64 = survivors.iter().map(|s| 1.0 / s.1).sum();
+    let offset = survivors.iter().map(|s| s.0 / s.1).sum::<f64>() / weight;
+    Some((offset, survivors[0].1))
+}
+
+/// Polls `config.servers` every `config.poll` (more often at first), calling `on_sample` with the
+/// combined offset of upstream time from the system clock in nanoseconds and
+/// its root distance whenever a majority of servers agree.
+pub(crate) fn spawn(config: NtpConfig, on_sample: impl Fn(i64, Duration) + Send + 'static) {
+    tokio::spawn(async move {
+        let mut peers: Vec<Peer> = config.servers.into_iter().map(Peer::new).collect();
+        for round in 0.. {
+            if round > 0 {
+                tokio::time::sleep(if round < NSTAGE {
+                    BURST_INTERVAL.min(config.poll)
+                } else {
+                    config.poll
+                })
+                .await;
+            }
+            let samples = join_all(peers.iter().map(|peer| query(&peer.server))).await;
+            for (peer, sample) in peers.iter_mut().zip(samples) {
+                if let Err(e) = &sample {
+                    tracing::debug!("NTP server {} failed: {e:#}", peer.server);
+                }
+                peer.update(sample.ok());
+            }
+            let candidates: Vec<_> = peers.iter().filter_map(Peer::candidate).collect();
+            match select(&candidates) {
+                Some((offset, distance)) => {
+                    tracing::debug!("NTP offset {offset:+.6}s, root distance {distance:.6}s");
+                    on_sample(
+                        (offset * 1e9).round() as i64,
+                        Duration::from_secs_f64(distance),
+                    );
+                }
+                None => tracing::warn!("No majority of NTP servers agree on the time"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime};
+
+    use tokio::net::UdpSocket;
+
+    use super::{NtpConfig, PACKET_LEN, to_timestamp, unix_seconds};
+
+    /// Answers NTP requests with the system time shifted by `offset` seconds.
+    async fn responder(offset: f64) -> String {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = socket.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut buf = [0u8; PACKET_LEN];
+            loop {
+                let (_, peer) = socket.recv_from(&mut buf).await.unwrap();
+                let received = unix_seconds(SystemTime::now()) + offset;
+                let mut response = [0u8; PACKET_LEN];
+                response[0] = (4 << 3) | 4;
+                response[1] = 1;
+                response[3] = -20i8 as u8;
+                response[12..16].copy_from_slice(b"GPS\0");
+                response[24..32].copy_from_slice(&buf[40..48]);
+                response[32..40].copy_from_slice(&to_timestamp(received).to_be_bytes());
+                let transmit = unix_seconds(SystemTime::now()) + offset;
+                response[40..48].copy_from_slice(&to_timestamp(transmit).to_be_bytes());
+                socket.send_to(&response, peer).await.unwrap();
+            }
+        });
+        addr.to_string()
+    }
+
+    #[tokio::test]
+    async fn test_ntp_client() {
+        let servers = vec![
+            responder(5.0).await,
+            responder(5.001).await,
+            responder(-100.0).await,
+        ];
+        let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+        super::spawn(
+            NtpConfig {
+                servers,
+                poll: Duration::from_millis(200),
+            },
+            move |offset, error| tx.send((offset, error)).unwrap(),
+        );
+
+        // The filters are full, and the root distance small, after 8 polls.
+        for round in 0..8 {
+            let (offset, error) = tokio::time::timeout(Duration::from_secs(5), rx.recv())
+                .await
+                .expect("no NTP offset")
+                .unwrap();
+            // The falseticker is voted out and the others are averaged.
+            assert!(
+                (offset - 5_000_500_000).abs() < 2_000_000,
+                "offset {offset} is not near 5.0005s"
+            );
+            if round == 7 {
+                assert!(error < Duration::from_millis(50), "error {error:?}");
+            }
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use std::collections::BTreeMap;
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::Context;
+use bytes::{Bytes, BytesMut};
+use salvo::http::header::AUTHORIZATION;
+use salvo::prelude::*;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+use tokio::time::{Interval, MissedTickBehavior};
+
+use crate::chaos::Transport;
+use crate::clock;
+
+/// Server-initiated clock probe on WS and WT sessions: these two bytes and the
+/// server time as a little-endian f64 Unix time.
+pub(crate) const PROBE_REQUEST: [u8; 2] = *b"P?";
+/// Length of a probe. Time responses are 9 (WS) or 17 (WT) bytes long, so
+/// clients can't take one for a probe.
+pub(crate) const PROBE_REQUEST_LEN: usize = PROBE_REQUEST.len() + 8;
+/// The client's answer: these two bytes, the echoed server time and the
+/// client's wall clock time, both as little-endian f64 Unix times.
+pub(crate) const PROBE_REPLY: [u8; 2] = *b"P!";
+/// Length of a probe reply. Plain time requests are 8 bytes long, so they
+/// can't be taken for one.
+const PROBE_REPLY_LEN: usize = PROBE_REPLY.len() + 16;
+
+/// What the last probe found out about a connected client.
+#[derive(Clone, Debug, Serialize)]
+pub(crate) struct ClientStatus {
+    pub(crate) transport: Transport,
+    pub(crate) peer: String,
+    pub(crate) user_agent: Option<String>,
+    /// When the session started, as a Unix time.
+    pub(crate) connected: f64,
+    /// Client clock minus server clock, in seconds.
+    pub(crate) offset: Option<f64>,
+    /// Round-trip time of the last probe, in seconds.
+    pub(crate) delay: Option<f64>,
+    /// When the last probe was answered, as a Unix time.
+    pub(crate) probed: Option<f64>,
+}
+
+static INTERVAL: OnceLock<Duration> = OnceLock::new();
+static ADMIN_TOKEN: OnceLock<[u8; 32]> = OnceLock::new();
+static CLIENTS: Mutex<BTreeMap<u64, ClientStatus>> = Mutex::new(BTreeMap::new());
+
+/// Probes every WS and WT client every `interval`.
+pub(crate) fn set_interval(interval: Duration) {
+    INTERVAL.set(interval).ok();
+}
+
+/// Reads the bearer token that grants access to the client list.
+pub(crate) fn set_admin_token_file(path: &str) -> anyhow::Result<()> {
+    let token = std::fs::read_to_string(path).with_context(|| format!("Failed to read {path}"))?;
+    let token = token.trim();
+    if token.is_empty() {
+        anyhow::bail!("{path} is empty");
+    }
+    ADMIN_TOKEN.set(Sha256::digest(token).into()).ok();
+    Ok(())
+}
+
+fn now() -> f64 {
+    clock::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0.0, |ts| ts.as_secs_f64())
+}
+
+/// Whether a message from the server is a probe rather than a time response.
+pub(crate) fn is_request(payload: &[u8]) -> bool {
+    payload.len() == PROBE_REQUEST_LEN && payload.starts_with(&PROBE_REQUEST)
+}
+
+/// Offset and round-trip time from a probe reply received at `received`.
+fn measure(reply: &[u8], received: f64) -> Option<(f64, f64)> {
+    let (sent, client) = reply.strip_prefix(&PROBE_REPLY)?.split_at_checked(8)?;
+    let sent = f64::from_le_bytes(sent.try_into().ok()?);
+    let client = f64::from_le_bytes(client.try_into().ok()?);
+    let delay = received - sent;
+    if !client.is_finite() || !(0.0..60.0).contains(&delay) {
+        return None;
+    }
+    Some((client - (sent + received) / 2.0, delay))
+}
+
+/// Probes the client of one WS or WT session, listing it while it lasts.
+pub(crate) struct Prober {
+    id: u64,
+    interval: Option<Interval>,
+}
+
+impl Prober {
+    pub(crate) fn new(transport: Transport, peer: String, user_agent: Option<String>) -> Self {
+        let Some(&period) = INTERVAL.get() else {
+            return Prober {
+                id: 0,
+                interval: None,
+            };
+        };
+        let id = fastrand::u64(..);
+        CLIENTS.lock().unwrap().insert(
+            id,
+            ClientStatus {
+                transport,
+                peer,
+                user_agent,
+                connected: now(),
+                offset: None,
+                delay: None,
+                probed: None,
+            },
+        );
+        // The first probe waits a full period, so that clients that only take
+        // a few measurements never see one.
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        Prober {
+            id,
+            interval: Some(interval),
+        }
+    }
+
+    /// Waits until the next probe is due, forever when probing is disabled.
+    pub(crate) async fn tick(&mut self) {
+        match &mut self.interval {
+            Some(interval) => {
+                interval.tick().await;
+            }
+            None => std::future::pending().await,
+        }
+    }
+
+    /// A probe to send to the client.
+    pub(crate) fn request(&self) -> Bytes {
+        let mut request = BytesMut::with_capacity(PROBE_REQUEST_LEN);
+        request.extend_from_slice(&PROBE_REQUEST);
+        request.extend_from_slice(&now().to_le_bytes());
+        request.freeze()
+    }
+
+    /// Handles a message from the client if it is a probe reply. Only
+    /// clients that are being probed send them.
+    pub(crate) fn reply(&self, payload: &[u8]) -> bool {
+        if self.interval.is_none()
+            || payload.len() != PROBE_REPLY_LEN
+            || !payload.starts_with(&PROBE_REPLY)
+        {
+            return false;
+        }
+        let received = now();
+        if let Some((offset, delay)) = measure(payload, received)
+            && let Some(client) = CLIENTS.lock().unwrap().get_mut(&self.id)
+        {
+            client.offset = Some(offset);
+            client.delay = Some(delay);
+            client.probed = Some(received);
+        }
+        true
+    }
+}
+
+impl Drop for Prober {
+    fn drop(&mut self) {
+        if self.interval.is_some() {
+            CLIENTS.lock().unwrap().remove(&self.id);
+        }
+    }
+}
+
+/// Lists the connected WS and WT clients with their measured clock offsets,
+/// for holders of the admin token.
+#[handler]
+pub(crate) async fn clients(req: &mut Request, res: &mut Response) {
+    let Some(token) = ADMIN_TOKEN.get() else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let authorized = req
+        .header::<&str>(AUTHORIZATION)
+        .and_then(|h| h.strip_prefix("Bearer "))
+        .is_some_and(|t| Sha256::digest(t.trim()).as_slice() == token);
+    if !authorized {
+        res.status_code(StatusCode::UNAUTHORIZED);
+        res.add_header("www-authenticate", "Bearer", true).ok();
+        return;
+    }
+    let statuses: Vec<_> = CLIENTS.lock().unwrap().values().cloned().collect();
+    res.render(Json(statuses));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Prober, measure};
+    use crate::chaos::Transport;
+
+    #[test]
+    fn test_measure() {
+        let reply = |sent: f64, client: f64| {
+            let mut reply = b"P!".to_vec();
+            reply.extend_from_slice(&sent.to_le_bytes());
+            reply.extend_from_slice(&client.to_le_bytes());
+            reply
+        };
+        // The client is 2.5 seconds ahead, with a 100 ms round trip.
+        let (offset, delay) = measure(&reply(1000.0, 1002.55), 1000.1).unwrap();
+        assert!((offset - 2.5).abs() < 1e-9, "offset {offset}");
+        assert!((delay - 0.1).abs() < 1e-9, "delay {delay}");
+
+        assert_eq!(measure(&reply(1000.0, 1000.0)[..17], 1000.1), None);
+        assert_eq!(measure(&reply(1000.0, f64::NAN), 1000.1), None);
+        assert_eq!(measure(&reply(1000.0, 1000.0), 999.0), None);
+        assert_eq!(measure(b"W", 1000.0), None);
+    }
+
+    #[test]
+    fn test_reply_unprobed() {
+        // Without --probe-interval, anything is a time request.
+        let prober = Prober::new(Transport::Ws, "127.0.0.1:1234".to_string(), None);
+        let mut reply = b"P!".to_vec();
+        reply.extend_from_slice(&[0; 16]);
+        assert!(!prober.reply(&reply));
+        assert!(!prober.reply(b"P!123456"));
+    }
+}
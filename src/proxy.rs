@@ -0,0 +1,459 @@
+use std::future::Future;
+use std::io::Result as IoResult;
+use std::net::{IpAddr, SocketAddr};
+use std::pin::Pin;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use anyhow::Context;
+use futures_util::StreamExt;
+use futures_util::stream::FuturesUnordered;
+use salvo::conn::{Accepted, Acceptor, Coupler, Holding};
+use salvo::fuse::ArcFuseFactory;
+use salvo::http::HeaderMap;
+use salvo::prelude::*;
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// How long a trusted peer has to send its PROXY protocol header.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Longest PROXY protocol version 1 header, including the CRLF.
+const V1_MAX_LEN: usize = 107;
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+/// A range of addresses, such as `10.0.0.0/8` or `::1`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Cidr {
+    addr: IpAddr,
+    prefix: u8,
+}
+
+impl Cidr {
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, ip.to_canonical()) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - u32::from(self.prefix))
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+pub(crate) fn parse_cidr(s: &str) -> anyhow::Result<Cidr> {
+    let (addr, prefix) = s.split_once('/').map_or((s, None), |(a, p)| (a, Some(p)));
+    let addr = addr
+        .parse::<IpAddr>()
+        .with_context(|| format!("Invalid address: {addr}"))?
+        .to_canonical();
+    let max = if addr.is_ipv4() { 32 } else { 128 };
+    let prefix = match prefix {
+        Some(prefix) => prefix
+            .parse()
+            .ok()
+            .filter(|&prefix| prefix <= max)
+            .with_context(|| format!("Invalid prefix length: {prefix}"))?,
+        None => max,
+    };
+    Ok(Cidr { addr, prefix })
+}
+
+/// Peers trusted to report their clients' addresses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Source {
+    Net(Cidr),
+    /// Any peer on a Unix domain socket.
+    Unix,
+}
+
+/// Parses a trusted source: a CIDR range or `unix`.
+pub(crate) fn parse_source(s: &str) -> Result<Source, String> {
+    if s == "unix" {
+        return Ok(Source::Unix);
+    }
+    parse_cidr(s).map(Source::Net).map_err(|e| format!("{e:#}"))
+}
+
+static PROXY_PROTOCOL: OnceLock<Vec<Source>> = OnceLock::new();
+static TRUSTED_PROXIES: OnceLock<Vec<Source>> = OnceLock::new();
+
+/// Expects a PROXY protocol header from connections coming from `sources`.
+pub(crate) fn set_proxy_protocol(sources: Vec<Source>) {
+    PROXY_PROTOCOL.set(sources).ok();
+}
+
+/// Honors the `Forwarded` and `X-Forwarded-For` headers of requests coming
+/// from `sources`.
+pub(crate) fn set_trusted_proxies(sources: Vec<Source>) {
+    TRUSTED_PROXIES.set(sources).ok();
+}
+
+fn trusts_ip(sources: &[Source], ip: IpAddr) -> bool {
+    sources.iter().any(|source| match source {
+        Source::Net(cidr) => cidr.contains(ip),
+        Source::Unix => false,
+    })
+}
+
+fn trusts(sources: &[Source], addr: &salvo::conn::SocketAddr) -> bool {
+    match addr {
+        salvo::conn::SocketAddr::Unix(_) => sources.contains(&Source::Unix),
+        _ => addr
+            .clone()
+            .into_std()
+            .is_some_and(|addr| trusts_ip(sources, addr.ip())),
+    }
+}
+
+/// Parses a hop of `Forwarded` or `X-Forwarded-For`, such as `192.0.2.1`,
+/// `192.0.2.1:4711` or `[2001:db8::1]:4711`. Obfuscated and unknown hops
+/// have no address.
+fn parse_hop(hop: &str) -> Option<SocketAddr> {
+    hop.parse().ok().or_else(|| {
+        let ip: IpAddr = hop
+            .trim_start_matches('[')
+            .trim_end_matches(']')
+            .parse()
+            .ok()?;
+        Some((ip, 0).into())
+    })
+}
+
+/// The hops that a request went through, from the client to the last proxy,
+/// according to `Forwarded` or else `X-Forwarded-For`.
+fn forwarded_hops(headers: &HeaderMap) -> Vec<&str> {
+    let values = |name: &str| {
+        headers
+            .get_all(name)
+            .into_iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+    };
+    if headers.contains_key("forwarded") {
+        values("forwarded")
+            .map(|element| {
+                element
+                    .split(';')
+                    .find_map(|pair| {
+                        let (key, value) = pair.split_once('=')?;
+                        key.trim()
+                            .eq_ignore_ascii_case("for")
+                            .then(|| value.trim().trim_matches('"'))
+                    })
+                    .unwrap_or_default()
+            })
+            .collect()
+    } else {
+        values("x-forwarded-for").map(str::trim).collect()
+    }
+}
+
+/// The address of the client that a trusted proxy forwarded the request for:
+/// the last hop that is not itself a trusted proxy.
+fn forwarded_client(
+    sources: &[Source],
+    peer: &salvo::conn::SocketAddr,
+    headers: &HeaderMap,
+) -> Option<SocketAddr> {
+    if !trusts(sources, peer) {
+        return None;
+    }
+    let mut client = None;
+    for hop in forwarded_hops(headers).into_iter().rev() {
+        let Some(addr) = parse_hop(hop) else {
+            break;
+        };
+        client = Some(addr);
+        if !trusts_ip(sources, addr.ip()) {
+            break;
+        }
+    }
+    client
+}
+
+/// Replaces the address of requests from trusted proxies with their client's,
+/// for logging and handlers alike.
+#[handler]
+pub(crate) async fn forwarded(req: &mut Request) {
+    let sources = TRUSTED_PROXIES.get().map_or(&[][..], Vec::as_slice);
+    if let Some(addr) = forwarded_client(sources, req.remote_addr(), req.headers()) {
+        *req.remote_addr_mut() = addr.into();
+    }
+}
+
+/// Reads a PROXY protocol header (version 1 or 2), leaving the rest of the
+/// stream untouched. Returns the client's address, or `None` if the proxy
+/// connected on its own behalf or did not know it.
+pub(crate) async fn read_header<S: AsyncRead + Unpin>(
+    stream: &mut S,
+) -> anyhow::Result<Option<SocketAddr>> {
+    let mut start = [0; 6];
+    stream.read_exact(&mut start).await?;
+    if &start == b"PROXY " {
+        let mut line = start.to_vec();
+        // Read byte by byte, not to consume any of the request.
+        while !line.ends_with(b"\r\n") {
+            if line.len() == V1_MAX_LEN {
+                anyhow::bail!("PROXY header too long");
+            }
+            line.push(stream.read_u8().await?);
+        }
+        return parse_v1(std::str::from_utf8(&line)?.trim_end());
+    }
+    if start != V2_SIGNATURE[..6] {
+        anyhow::bail!("Missing PROXY header");
+    }
+
+    let mut header = [0; 10];
+    stream.read_exact(&mut header).await?;
+    if header[..6] != V2_SIGNATURE[6..] {
+        anyhow::bail!("Missing PROXY header");
+    }
+    let (version_command, family) = (header[6], header[7]);
+    let mut addresses = vec![0; usize::from(u16::from_be_bytes([header[8], header[9]]))];
+    stream.read_exact(&mut addresses).await?;
+    match version_command {
+        0x20 => return Ok(None),
+        0x21 => {}
+        _ => anyhow::bail!("Unsupported PROXY version or command: {version_command:#x}"),
+    }
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match family >> 4 {
+        0x1 if addresses.len() >= 12 => {
+            let ip = <[u8; 4]>::try_from(&addresses[..4])?;
+            Ok(Some((ip, port(8)).into()))
+        }
+        0x2 if addresses.len() >= 36 => {
+            let ip = <[u8; 16]>::try_from(&addresses[..16])?;
+            Ok(Some((ip, port(32)).into()))
+        }
+        0x1 | 0x2 => anyhow::bail!("PROXY addresses too short"),
+        // Unspecified or Unix domain sockets: nothing to report.
+        _ => Ok(None),
+    }
+}
+
+fn parse_v1(line: &str) -> anyhow::Result<Option<SocketAddr>> {
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", source, _, port, _] => {
+            let ip: IpAddr = source
+                .parse()
+                .with_context(|| format!("Invalid PROXY address: {source}"))?;
+            let port = port
+                .parse()
+                .with_context(|| format!("Invalid PROXY port: {port}"))?;
+            Ok(Some((ip, port).into()))
+        }
+        _ => anyhow::bail!("Invalid PROXY header: {line}"),
+    }
+}
+
+/// Replaces the address of a connection from a trusted peer with the one in
+/// its PROXY header. Connections without a valid header are dropped.
+async fn handshake<C, S>(mut accepted: Accepted<C, S>) -> Option<Accepted<C, S>>
+where
+    C: Coupler<Stream = S>,
+    S: AsyncRead + Unpin + Send + 'static,
+{
+    match tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut accepted.stream)).await {
+        Ok(Ok(Some(addr))) => accepted.remote_addr = addr.into(),
+        Ok(Ok(None)) => {}
+        Ok(Err(e)) => {
+            tracing::debug!("Dropping connection from {}: {e:#}", accepted.remote_addr);
+            return None;
+        }
+        Err(_) => {
+            tracing::debug!(
+                "Dropping connection from {}: no PROXY header",
+                accepted.remote_addr
+            );
+            return None;
+        }
+    }
+    Some(accepted)
+}
+
+type Handshake<A> = Pin<
+    Box<
+        dyn Future<Output = Option<Accepted<<A as Acceptor>::Coupler, <A as Acceptor>::Stream>>>
+            + Send,
+    >,
+>;
+
+/// Takes the client's address from the PROXY protocol header sent by trusted
+/// peers, set with [`set_proxy_protocol`]. Connections from other peers are
+/// passed through as they are.
+pub(crate) struct ProxyProtocolAcceptor<A: Acceptor> {
+    inner: A,
+    /// Connections whose header is still being read, so that slow ones do not
+    /// hold up the others.
+    pending: FuturesUnordered<Handshake<A>>,
+}
+
+impl<A: Acceptor> ProxyProtocolAcceptor<A> {
+    pub(crate) fn new(inner: A) -> Self {
+        ProxyProtocolAcceptor {
+            inner,
+            pending: FuturesUnordered::new(),
+        }
+    }
+}
+
+impl<A> Acceptor for ProxyProtocolAcceptor<A>
+where
+    A: Acceptor,
+    A::Stream: AsyncRead,
+{
+    type Coupler = A::Coupler;
+    type Stream = A::Stream;
+
+    fn holdings(&self) -> &[Holding] {
+        self.inner.holdings()
+    }
+
+    async fn accept(
+        &mut self,
+        fuse_factory: Option<ArcFuseFactory>,
+    ) -> IoResult<Accepted<Self::Coupler, Self::Stream>> {
+        loop {
+            tokio::select! {
+                accepted = self.inner.accept(fuse_factory.clone()) => {
+                    let accepted = accepted?;
+                    let sources = PROXY_PROTOCOL.get().map_or(&[][..], Vec::as_slice);
+                    if !trusts(sources, &accepted.remote_addr) {
+                        return Ok(accepted);
+                    }
+                    self.pending.push(Box::pin(handshake(accepted)));
+                }
+                Some(accepted) = self.pending.next() => {
+                    if let Some(accepted) = accepted {
+                        return Ok(accepted);
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use salvo::http::{HeaderMap, HeaderValue};
+
+    use super::{Source, forwarded_client, parse_cidr, parse_source, read_header};
+
+    #[test]
+    fn test_parse_source() {
+        let cidr = parse_cidr("10.1.0.0/16").unwrap();
+        assert!(cidr.contains([10, 1, 2, 3].into()));
+        assert!(!cidr.contains([10, 2, 0, 1].into()));
+        // IPv4 clients of a dual-stack socket.
+        assert!(cidr.contains("::ffff:10.1.2.3".parse().unwrap()));
+        let host = parse_cidr("::1").unwrap();
+        assert!(host.contains("::1".parse().unwrap()));
+        assert!(!host.contains("::2".parse().unwrap()));
+        assert!(
+            parse_cidr("0.0.0.0/0")
+                .unwrap()
+                .contains([1, 2, 3, 4].into())
+        );
+
+        assert_eq!(parse_source("unix"), Ok(Source::Unix));
+        assert!(parse_source("10.0.0.0/33").is_err());
+        assert!(parse_source("localhost").is_err());
+    }
+
+    #[test]
+    fn test_forwarded_client() {
+        let sources = [parse_source("10.0.0.0/8").unwrap(), Source::Unix];
+        let proxy: salvo::conn::SocketAddr = "10.0.0.2:80"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        let headers = |name, value: &str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(name, HeaderValue::from_str(value).unwrap());
+            headers
+        };
+
+        let xff = headers("x-forwarded-for", "198.51.100.1, 192.0.2.1, 10.0.0.1");
+        assert_eq!(
+            forwarded_client(&sources, &proxy, &xff),
+            Some("192.0.2.1:0".parse().unwrap())
+        );
+        // Untrusted peers cannot claim to speak for anyone.
+        let client: salvo::conn::SocketAddr = "192.0.2.9:80"
+            .parse::<std::net::SocketAddr>()
+            .unwrap()
+            .into();
+        assert_eq!(forwarded_client(&sources, &client, &xff), None);
+
+        let forwarded = headers(
+            "forwarded",
+            r#"for=192.0.2.1, for="[2001:db8::1]:4711";proto=https, for=10.0.0.1"#,
+        );
+        assert_eq!(
+            forwarded_client(&sources, &proxy, &forwarded),
+            Some("[2001:db8::1]:4711".parse().unwrap())
+        );
+        let hidden = headers("forwarded", "for=_hidden, for=10.0.0.1");
+        assert_eq!(
+            forwarded_client(&sources, &proxy, &hidden),
+            Some("10.0.0.1:0".parse().unwrap())
+        );
+        assert_eq!(forwarded_client(&sources, &proxy, &HeaderMap::new()), None);
+    }
+
+    #[tokio::test]
+    async fn test_read_header() {
+        let mut stream: &[u8] =
+            b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET / HTTP/1.1\r\n";
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("192.0.2.1:56324".parse().unwrap())
+        );
+        let mut rest = String::new();
+        stream.read_to_string(&mut rest).await.unwrap();
+        assert_eq!(rest, "GET / HTTP/1.1\r\n");
+
+        let mut stream: &[u8] = b"PROXY UNKNOWN\r\n";
+        assert_eq!(read_header(&mut stream).await.unwrap(), None);
+
+        let mut v2 = b"\r\n\r\n\0\r\nQUIT\n\x21\x21\x00\x24".to_vec();
+        v2.extend_from_slice(
+            &"2001:db8::1"
+                .parse::<std::net::Ipv6Addr>()
+                .unwrap()
+                .octets(),
+        );
+        v2.extend_from_slice(&[0; 16]);
+        v2.extend_from_slice(&[0x1f, 0x90, 0x01, 0xbb]);
+        v2.extend_from_slice(b"GET");
+        let mut stream = &v2[..];
+        assert_eq!(
+            read_header(&mut stream).await.unwrap(),
+            Some("[2001:db8::1]:8080".parse().unwrap())
+        );
+        assert_eq!(stream, b"GET");
+
+        let mut local: &[u8] = b"\r\n\r\n\0\r\nQUIT\n\x20\x00\x00\x00";
+        assert_eq!(read_header(&mut local).await.unwrap(), None);
+
+        let mut stream: &[u8] = b"GET / HTTP/1.1\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+        let mut stream: &[u8] = b"PROXY TCP4 192.0.2.1\r\n";
+        assert!(read_header(&mut stream).await.is_err());
+    }
+}
@@ -0,0 +1,522 @@
+use std::io::IoSliceMut;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use nix::sys::socket::{
+    ControlMessageOwned, MsgFlags, SockaddrStorage, TimestampingFlag, recvmsg, setsockopt, sockopt,
+};
+use sha2::{Digest, Sha256};
+use socket2::{Domain, InterfaceIndexOrAddress, Protocol, Socket, Type};
+use tokio::io::Interest;
+use tokio::net::UdpSocket;
+
+const EVENT_PORT: u16 = 319;
+const GENERAL_PORT: u16 = 320;
+
+const PTP_PRIMARY_V4: Ipv4Addr = Ipv4Addr::new(224, 0, 1, 129);
+const PTP_PRIMARY_V6: Ipv6Addr = Ipv6Addr::new(0xff0e, 0, 0, 0, 0, 0, 0, 0x181);
+
+const MSG_SYNC: u8 = 0x0;
+const MSG_DELAY_REQ: u8 = 0x1;
+const MSG_FOLLOW_UP: u8 = 0x8;
+const MSG_DELAY_RESP: u8 = 0x9;
+const MSG_ANNOUNCE: u8 = 0xb;
+
+const SYNC_LEN: usize = 44;
+const DELAY_RESP_LEN: usize = 54;
+const ANNOUNCE_LEN: usize = 64;
+
+const LOG_SYNC_INTERVAL: i8 = 0;
+const LOG_ANNOUNCE_INTERVAL: i8 = 1;
+const LOG_MIN_DELAY_REQ_INTERVAL: i8 = 0;
+
+// flagField bits, octet 0 then octet 1.
+const FLAG_TWO_STEP: u16 = 0x0200;
+const FLAG_UNICAST: u16 = 0x0400;
+const FLAG_CURRENT_UTC_OFFSET_VALID: u16 = 0x0004;
+const FLAG_PTP_TIMESCALE: u16 = 0x0008;
+
+// Default dataset of a clock with no external reference.
+const CLOCK_CLASS_DEFAULT: u8 = 248;
+const CLOCK_ACCURACY_UNKNOWN: u8 = 0xfe;
+const TIME_SOURCE_INTERNAL_OSCILLATOR: u8 = 0xa0;
+const PRIORITY_DEFAULT: u8 = 128;
+
+const TX_TIMESTAMP_TIMEOUT: Duration = Duration::from_millis(100);
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum PtpTransport {
+    Ipv4,
+    Ipv6,
+}
+
+#[derive(Debug)]
+pub(crate) struct PtpConfig {
+    pub(crate) domain: u8,
+    pub(crate) utc_offset: u16,
+    pub(crate) interface: Option<String>,
+}
+
+/// One PTP port of the grandmaster: an event socket (Sync, Delay_Req) with
+/// software timestamping enabled and a general socket (Announce, Follow_Up,
+/// Delay_Resp), plus where to send the periodic messages.
+pub(crate) struct PtpPort {
+    domain: u8,
+    utc_offset: u16,
+    port_identity: [u8; 10],
+    event: UdpSocket,
+    general: UdpSocket,
+    event_dest: SocketAddr,
+    general_dest: SocketAddr,
+}
+
+impl PtpPort {
+    /// Binds the standard PTP ports for `transport` and joins the primary
+    /// multicast group, on `config.interface` if given.
+    pub(crate) fn bind(config: &PtpConfig, transport: PtpTransport) -> anyhow::Result<Self> {
+        let interface_index = config
+            .interface
+            .as_deref()
+            .map(|name| {
+                nix::net::if_::if_nametoindex(name)
+                    .with_context(|| format!("Look up interface {name}"))
+            })
+            .transpose()?;
+        let (event, general, group) = match transport {
+            PtpTransport::Ipv4 => {
+                let interface_addr = config
+                    .interface
+                    .as_deref()
+                    .map(interface_ipv4)
+                    .transpose()?;
+                let group = IpAddr::V4(PTP_PRIMARY_V4);
+                let bind = |port| -> anyhow::Result<UdpSocket> {
+                    let socket = bind_socket((Ipv4Addr::UNSPECIFIED, port).into())?;
+                    let interface = match interface_index {
+                        Some(index) => InterfaceIndexOrAddress::Index(index),
+                        None => InterfaceIndexOrAddress::Address(Ipv4Addr::UNSPECIFIED),
+                    };
+                    socket.join_multicast_v4_n(&PTP_PRIMARY_V4, &interface)?;
+                    if let Some(addr) = interface_addr {
+                        socket.set_multicast_if_v4(&addr)?;
+                    }
+                    into_tokio(socket)
+                };
+                (bind(EVENT_PORT)?, bind(GENERAL_PORT)?, group)
+            }
+            PtpTransport::Ipv6 => {
+                let group = IpAddr::V6(PTP_PRIMARY_V6);
+                let bind = |port| -> anyhow::Result<UdpSocket> {
+                    let socket = bind_socket((Ipv6Addr::UNSPECIFIED, port).into())?;
+                    socket.join_multicast_v6(&PTP_PRIMARY_V6, interface_index.unwrap_or(0))?;
+                    if let Some(index) = interface_index {
+                        socket.set_multicast_if_v6(index)?;
+                    }
+                    into_tokio(socket)
+                };
+                (bind(EVENT_PORT)?, bind(GENERAL_PORT)?, group)
+            }
+        };
+        let port_number = match transport {
+            PtpTransport::Ipv4 => 1,
+            PtpTransport::Ipv6 => 2,
+        };
+        Self::new(
+            config,
+            port_number,
+            event,
+            general,
+            (group, EVENT_PORT).into(),
+            (group, GENERAL_PORT).into(),
+        )
+    }
+
+    fn new(
+        config: &PtpConfig,
+        port_number: u16,
+        event: UdpSocket,
+        general: UdpSocket,
+        event_dest: SocketAddr,
+        general_dest: SocketAddr,
+    ) -> anyhow::Result<Self> {
+        setsockopt(
+            &event,
+            sockopt::Timestamping,
+            &(TimestampingFlag::SOF_TIMESTAMPING_SOFTWARE
+                | TimestampingFlag::SOF_TIMESTAMPING_TX_SOFTWARE
+                | TimestampingFlag::SOF_TIMESTAMPING_RX_SOFTWARE
+                | TimestampingFlag::SOF_TIMESTAMPING_OPT_TSONLY),
+        )
+        .context("Enable software timestamping")?;
+        let mut port_identity = [0u8; 10];
+        port_identity[..8].copy_from_slice(&clock_identity(config.interface.as_deref()));
+        port_identity[8..].copy_from_slice(&port_number.to_be_bytes());
+        Ok(Self {
+            domain: config.domain,
+            utc_offset: config.utc_offset,
+            port_identity,
+            event,
+            general,
+            event_dest,
+            general_dest,
+        })
+    }
+
+    pub(crate) fn spawn(self) {
+        tracing::info!(
+            "Serving PTP on {} (domain {})",
+            self.event_dest.ip(),
+            self.domain
+        );
+        let port = Arc::new(self);
+        tokio::spawn(port.clone().send_periodic());
+        tokio::spawn(port.answer_delay_requests());
+    }
+
+    fn header(&self, message_type: u8, length: usize, flags: u16, sequence_id: u16) -> Vec<u8> {
+        let (control, log_interval) = match message_type {
+            MSG_SYNC => (0, LOG_SYNC_INTERVAL),
+            MSG_FOLLOW_UP => (2, LOG_SYNC_INTERVAL),
+            MSG_DELAY_RESP => (3, LOG_MIN_DELAY_REQ_INTERVAL),
+            _ => (5, LOG_ANNOUNCE_INTERVAL),
+        };
+        let flags = if self.event_dest.ip().is_multicast() {
+            flags
+        } else {
+            flags | FLAG_UNICAST
+        };
+        let mut msg = Vec::with_capacity(length);
+        msg.push(message_type);
+        msg.push(2);
+        msg.extend_from_slice(&(length as u16).to_be_bytes());
+        msg.push(self.domain);
+        msg.push(0);
+        msg.extend_from_slice(&flags.to_be_bytes());
+        msg.extend_from_slice(&[0; 8]);
+        msg.extend_from_slice(&[0; 4]);
+        msg.extend_from_slice(&self.port_identity);
+        msg.extend_from_slice(&sequence_id.to_be_bytes());
+        msg.push(control);
+        msg.push(log_interval as u8);
+        msg
+    }
+
+    /// Converts a system clock timestamp into the PTP (TAI) timescale,
+    /// correcting it against the reference source first.
+    fn ptp_timestamp(&self, ts: Duration) -> [u8; 10] {
+        let utc = crate::clock::adjust(UNIX_EPOCH + ts)
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let tai = utc + Duration::from_secs(u64::from(self.utc_offset));
+        let mut out = [0u8; 10];
+        out[..6].copy_from_slice(&tai.as_secs().to_be_bytes()[2..]);
+        out[6..].copy_from_slice(&tai.subsec_nanos().to_be_bytes());
+        out
+    }
+
+    async fn send_periodic(self: Arc<Self>) {
+        let mut sync_interval = tokio::time::interval(Duration::from_secs(1));
+        sync_interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        let mut sync_sequence: u16 = 0;
+        let mut announce_sequence: u16 = 0;
+        loop {
+            sync_interval.tick().await;
+            if sync_sequence.is_multiple_of(2) {
+                if let Err(e) = self.send_announce(announce_sequence).await {
+                    tracing::error!("Failed to send PTP Announce: {e:?}");
+                }
+                announce_sequence = announce_sequence.wrapping_add(1);
+            }
+            if let Err(e) = self.send_sync(sync_sequence).await {
+                tracing::error!("Failed to send PTP Sync: {e:?}");
+            }
+            sync_sequence = sync_sequence.wrapping_add(1);
+        }
+    }
+
+    async fn send_announce(&self, sequence_id: u16) -> anyhow::Result<()> {
+        let flags = FLAG_PTP_TIMESCALE | FLAG_CURRENT_UTC_OFFSET_VALID;
+        let mut msg = self.header(MSG_ANNOUNCE, ANNOUNCE_LEN, flags, sequence_id);
+        msg.extend_from_slice(&self.ptp_timestamp(SystemTime::now().duration_since(UNIX_EPOCH)?));
+        msg.extend_from_slice(&self.utc_offset.to_be_bytes());
+        msg.push(0);
+        msg.push(PRIORITY_DEFAULT);
+        msg.push(CLOCK_CLASS_DEFAULT);
+        msg.push(CLOCK_ACCURACY_UNKNOWN);
+        msg.extend_from_slice(&0xffffu16.to_be_bytes());
+        msg.push(PRIORITY_DEFAULT);
+        msg.extend_from_slice(&self.port_identity[..8]);
+        msg.extend_from_slice(&0u16.to_be_bytes());
+        msg.push(TIME_SOURCE_INTERNAL_OSCILLATOR);
+        self.general.send_to(&msg, self.general_dest).await?;
+        Ok(())
+    }
+
+    async fn send_sync(&self, sequence_id: u16) -> anyhow::Result<()> {
+        // Discard any transmit timestamp that arrived after we gave up on it.
+        while self
+            .event
+            .try_io(Interest::ERROR, || {
+                recv_timestamped(self.event.as_raw_fd(), &mut [], MsgFlags::MSG_ERRQUEUE)
+            })
+            .is_ok()
+        {}
+
+        let mut msg = self.header(MSG_SYNC, SYNC_LEN, FLAG_TWO_STEP, sequence_id);
+        msg.extend_from_slice(&[0; 10]);
+        self.event.send_to(&msg, self.event_dest).await?;
+        let fallback = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let tx_timestamp = match tokio::time::timeout(
+            TX_TIMESTAMP_TIMEOUT,
+            self.event.async_io(Interest::ERROR, || {
+                recv_timestamped(self.event.as_raw_fd(), &mut [], MsgFlags::MSG_ERRQUEUE)
+            }),
+        )
+        .await
+        {
+            Ok(Ok((_, _, Some(ts)))) => ts,
+            _ => {
+                tracing::debug!("No transmit timestamp for PTP Sync, using system time");
+                fallback
+            }
+        };
+
+        let mut msg = self.header(MSG_FOLLOW_UP, SYNC_LEN, 0, sequence_id);
+        msg.extend_from_slice(&self.ptp_timestamp(tx_timestamp));
+        self.general.send_to(&msg, self.general_dest).await?;
+        Ok(())
+    }
+
+    async fn answer_delay_requests(self: Arc<Self>) {
+        let mut buf = [0u8; 128];
+        loop {
+            let (len, peer, rx_timestamp) = match self
+                .event
+                .async_io(Interest::READABLE, || {
+                    recv_timestamped(self.event.as_raw_fd(), &mut buf, MsgFlags::empty())
+                })
+                .await
+            {
+                Ok(received) => received,
+                Err(e) => {
+                    tracing::debug!("Failed to receive PTP event message: {e:?}");
+                    continue;
+                }
+            };
+            let msg = &buf[..len];
+            if len < SYNC_LEN
+                || msg[0] & 0x0f != MSG_DELAY_REQ
+                || msg[1] & 0x0f != 2
+                || msg[4] != self.domain
+            {
+                continue;
+            }
+            let Some(peer) = peer else {
+                continue;
+            };
+            let rx_timestamp = match rx_timestamp {
+                Some(ts) => ts,
+                None => match SystemTime::now().duration_since(UNIX_EPOCH) {
+                    Ok(ts) => ts,
+                    Err(_) => continue,
+                },
+            };
+
+            let sequence_id = u16::from_be_bytes([msg[30], msg[31]]);
+            let mut resp = self.header(MSG_DELAY_RESP, DELAY_RESP_LEN, 0, sequence_id);
+            resp[8..16].copy_from_slice(&msg[8..16]);
+            resp.extend_from_slice(&self.ptp_timestamp(rx_timestamp));
+            resp.extend_from_slice(&msg[20..30]);
+            // Answer multicast requests on the group, unicast ones directly.
+            let dest = if self.general_dest.ip().is_multicast() {
+                self.general_dest
+            } else {
+                SocketAddr::new(peer.ip(), self.general_dest.port())
+            };
+            if let Err(e) = self.general.send_to(&resp, dest).await {
+                tracing::debug!("Failed to send PTP Delay_Resp to {dest}: {e:?}");
+            }
+        }
+    }
+}
+
+fn bind_socket(addr: SocketAddr) -> anyhow::Result<Socket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() {
+        // Keep IPv6 sockets off the IPv4 ports bound by the other transport.
+        socket.set_only_v6(true)?;
+    }
+    socket.set_reuse_address(true)?;
+    socket.set_nonblocking(true)?;
+    socket
+        .bind(&addr.into())
+        .with_context(|| format!("Bind PTP socket {addr}"))?;
+    Ok(socket)
+}
+
+fn into_tokio(socket: Socket) -> anyhow::Result<UdpSocket> {
+    Ok(UdpSocket::from_std(socket.into())?)
+}
+
+fn interface_ipv4(name: &str) -> anyhow::Result<Ipv4Addr> {
+    nix::ifaddrs::getifaddrs()?
+        .filter(|ifaddr| ifaddr.interface_name == name)
+        .find_map(|ifaddr| ifaddr.address?.as_sockaddr_in().map(|sin| sin.ip()))
+        .ok_or_else(|| anyhow::anyhow!("Interface {name} has no IPv4 address"))
+}
+
+/// Derives an EUI-64 clock identity from the interface's MAC address, falling
+/// back to any interface with one, and finally to a per-process value.
+fn clock_identity(interface: Option<&str>) -> [u8; 8] {
+    let mac = nix::ifaddrs::getifaddrs().ok().and_then(|mut addrs| {
+        addrs.find_map(|ifaddr| {
+            if interface.is_some_and(|name| ifaddr.interface_name != name) {
+                return None;
+            }
+            let mac = ifaddr.address?.as_link_addr()?.addr()?;
+            (mac != [0; 6]).then_some(mac)
+        })
+    });
+    match mac {
+        Some(mac) => [mac[0], mac[1], mac[2], 0xff, 0xfe, mac[3], mac[4], mac[5]],
+        None => {
+            let seed = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_nanos()
+                .to_le_bytes();
+            Sha256::digest(seed)[..8].try_into().unwrap()
+        }
+    }
+}
+
+/// Receives one message (or error queue entry) along with its kernel software
+/// timestamp, if any.
+fn recv_timestamped(
+    fd: RawFd,
+    buf: &mut [u8],
+    flags: MsgFlags,
+) -> std::io::Result<(usize, Option<SocketAddr>, Option<Duration>)> {
+    let mut iov = [IoSliceMut::new(buf)];
+    let mut cmsg = [0u8; 256];
+    let msg = recvmsg::<SockaddrStorage>(
+        fd,
+        &mut iov,
+        Some(&mut cmsg),
+        flags | MsgFlags::MSG_DONTWAIT,
+    )?;
+    let peer = msg.address.and_then(|addr| {
+        if let Some(sin) = addr.as_sockaddr_in() {
+            Some(SocketAddr::from(std::net::SocketAddrV4::from(*sin)))
+        } else {
+            addr.as_sockaddr_in6()
+                .map(|sin6| SocketAddr::from(std::net::SocketAddrV6::from(*sin6)))
+        }
+    });
+    let mut timestamp = None;
+    for cmsg in msg.cmsgs()? {
+        if let ControlMessageOwned::ScmTimestampsns(ts) = cmsg
+            && (ts.system.tv_sec() != 0 || ts.system.tv_nsec() != 0)
+        {
+            timestamp = Some(Duration::new(
+                ts.system.tv_sec() as u64,
+                ts.system.tv_nsec() as u32,
+            ));
+        }
+    }
+    Ok((msg.bytes, peer, timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+    use tokio::net::UdpSocket;
+
+    use super::{PtpConfig, PtpPort};
+
+    fn now() -> f64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64()
+    }
+
+    fn ptp_time(ts: &[u8], utc_offset: u16) -> f64 {
+        let mut secs = [0u8; 8];
+        secs[2..].copy_from_slice(&ts[..6]);
+        let nanos = u32::from_be_bytes(ts[6..10].try_into().unwrap());
+        (u64::from_be_bytes(secs) as f64 - f64::from(utc_offset)) + nanos as f64 / 1e9
+    }
+
+    async fn recv_type(socket: &UdpSocket, message_type: u8) -> (Vec<u8>, f64) {
+        let mut buf = [0u8; 128];
+        loop {
+            let len = tokio::time::timeout(Duration::from_secs(5), socket.recv(&mut buf))
+                .await
+                .expect("timed out waiting for PTP message")
+                .unwrap();
+            let received = now();
+            if buf[0] & 0x0f == message_type {
+                return (buf[..len].to_vec(), received);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_ptp_master() {
+        let config = PtpConfig {
+            domain: 24,
+            utc_offset: 37,
+            interface: None,
+        };
+
+        // In-process slave listening on ephemeral event and general ports.
+        let slave_event = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let slave_general = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let master_event = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let master_general = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let master_addr = master_event.local_addr().unwrap();
+        PtpPort::new(
+            &config,
+            1,
+            master_event,
+            master_general,
+            slave_event.local_addr().unwrap(),
+            slave_general.local_addr().unwrap(),
+        )
+        .unwrap()
+        .spawn();
+
+        let (announce, _) = recv_type(&slave_general, 0xb).await;
+        assert_eq!(announce.len(), 64);
+        assert_eq!(announce[4], 24, "wrong domain");
+        assert_eq!(i16::from_be_bytes([announce[44], announce[45]]), 37);
+
+        let (sync, sync_received) = recv_type(&slave_event, 0x0).await;
+        assert_eq!(sync[6] & 0x02, 0x02, "Sync is not two-step");
+        let (follow_up, _) = recv_type(&slave_general, 0x8).await;
+        assert_eq!(follow_up[30..32], sync[30..32], "sequence mismatch");
+        let origin = ptp_time(&follow_up[34..44], 37);
+        assert!(
+            origin <= sync_received && sync_received - origin < 1.0,
+            "origin timestamp {origin} does not precede Sync receipt {sync_received}"
+        );
+
+        let mut delay_req = sync.clone();
+        delay_req[0] = 0x1;
+        delay_req[20..30].copy_from_slice(&[1, 2, 3, 4, 5, 6, 7, 8, 0, 1]);
+        delay_req[30..32].copy_from_slice(&77u16.to_be_bytes());
+        let t1 = now();
+        slave_event.send_to(&delay_req, master_addr).await.unwrap();
+        let (delay_resp, t2) = recv_type(&slave_general, 0x9).await;
+        assert_eq!(delay_resp.len(), 54);
+        assert_eq!(delay_resp[30..32], 77u16.to_be_bytes());
+        assert_eq!(delay_resp[44..54], delay_req[20..30]);
+        let receive = ptp_time(&delay_resp[34..44], 37);
+        assert!(receive >= t1, "receive time {receive} is before t1 {t1}");
+        assert!(receive <= t2, "receive time {receive} is after t2 {t2}");
+    }
+}
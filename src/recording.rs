@@ -0,0 +1,281 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use base64::Engine;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::mpsc;
+
+use crate::chaos::Transport;
+use crate::{clock, history};
+
+/// HTTP exchanges from the same peer address belong to the same session
+/// until it stays idle for this long.
+const HTTP_SESSION_IDLE: Duration = Duration::from_secs(60);
+
+/// Records waiting to be written; further records are dropped while the
+/// writer can't keep up.
+const RECORDER_BACKLOG: usize = 1024;
+
+/// One time exchange, as written to the recording: a line of JSON per
+/// exchange. Times are served Unix times in seconds.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Record {
+    pub(crate) session: u64,
+    pub(crate) transport: Transport,
+    pub(crate) peer: String,
+    /// The request payload, base64-encoded: the WS message, the WT datagram
+    /// or the HTTP nonce.
+    pub(crate) request: String,
+    /// When the request arrived.
+    pub(crate) received: f64,
+    /// The server timestamp sent in the response.
+    pub(crate) sent: f64,
+    /// When the response was handed to the transport.
+    pub(crate) replied: f64,
+    /// Served time minus system time, in seconds.
+    pub(crate) offset: f64,
+}
+
+/// HTTP sessions by peer address, with when they were last used.
+type HttpSessions = HashMap<String, (Arc<Session>, Instant)>;
+
+static RECORDER: OnceLock<mpsc::Sender<Record>> = OnceLock::new();
+static REPLAY: OnceLock<Vec<Record>> = OnceLock::new();
+static HTTP_SESSIONS: Mutex<Option<HttpSessions>> = Mutex::new(None);
+
+/// Appends every time exchange to the file at `path`. A dedicated task does
+/// the writing, so that responses never wait on the disk.
+pub(crate) fn set_recorder(path: &str) -> anyhow::Result<()> {
+    let file = std::fs::File::options()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("Failed to open {path}"))?;
+    let (tx, mut rx) = mpsc::channel::<Record>(RECORDER_BACKLOG);
+    if RECORDER.set(tx).is_err() {
+        return Ok(());
+    }
+    let mut file = tokio::fs::File::from_std(file);
+    tokio::spawn(async move {
+        while let Some(record) = rx.recv().await {
+            let line = serde_json::to_string(&record).unwrap() + "\n";
+            // Flush each record, as tokio only finishes a write on flush.
+            let written = file.write_all(line.as_bytes()).await;
+            if let Err(e) = written.and(file.flush().await) {
+                tracing::error!("Failed to write recording: {e}");
+            }
+        }
+    });
+    Ok(())
+}
+
+/// Reads the exchanges of `session`, or of the first session, from a
+/// recording.
+pub(crate) fn load_session(
+    recording: &str,
+    mut session: Option<u64>,
+) -> anyhow::Result<Vec<Record>> {
+    let mut records = Vec::new();
+    for (number, line) in recording.lines().enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        let record: Record =
+            serde_json::from_str(line).with_context(|| format!("Line {}", number + 1))?;
+        if record.session == *session.get_or_insert(record.session) {
+            records.push(record);
+        }
+    }
+    if records.is_empty() {
+        anyhow::bail!("No such session");
+    }
+    Ok(records)
+}
+
+/// Answers time requests with the timings and offsets of `records` instead
+/// of the live clock. Every new session starts over from the first record.
+pub(crate) fn set_replay(records: Vec<Record>) {
+    REPLAY.set(records).ok();
+}
+
+/// The peer address of a request, as recorded.
+pub(crate) fn peer(addr: &salvo::conn::SocketAddr) -> String {
+    addr.clone()
+        .into_std()
+        .map_or_else(|| addr.to_string(), |addr| addr.to_string())
+}
+
+fn secs(time: SystemTime) -> f64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs_f64(),
+        Err(e) => -e.duration().as_secs_f64(),
+    }
+}
+
+fn shift(time: SystemTime, secs: f64) -> SystemTime {
+    let shifted = Duration::try_from_secs_f64(secs.abs())
+        .ok()
+        .and_then(|amount| {
+            if secs >= 0.0 {
+                time.checked_add(amount)
+            } else {
+                time.checked_sub(amount)
+            }
+        });
+    shifted.unwrap_or(time)
+}
+
+async fn sleep(secs: f64) {
+    if let Ok(delay) = Duration::try_from_secs_f64(secs)
+        && !delay.is_zero()
+    {
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// A WS or WT connection, or a series of HTTP requests from the same peer.
+pub(crate) struct Session {
+    id: u64,
+    transport: Transport,
+    peer: String,
+    exchanges: AtomicUsize,
+}
+
+impl Session {
+    pub(crate) fn new(transport: Transport, peer: String) -> Self {
+        Session {
+            id: fastrand::u64(..),
+            transport,
+            peer,
+            exchanges: AtomicUsize::new(0),
+        }
+    }
+
+    /// The session of an HTTP request from `peer`, kept across requests only
+    /// while recording or replaying.
+    pub(crate) fn http(peer: String) -> Arc<Session> {
+        if RECORDER.get().is_none() && REPLAY.get().is_none() {
+            return Arc::new(Session::new(Transport::Http, peer));
+        }
+        let mut sessions = HTTP_SESSIONS.lock().unwrap();
+        let sessions = sessions.get_or_insert_default();
+        sessions.retain(|_, (_, used)| used.elapsed() < HTTP_SESSION_IDLE);
+        let (session, used) = sessions.entry(peer.clone()).or_insert_with(|| {
+            (
+                Arc::new(Session::new(Transport::Http, peer)),
+                Instant::now(),
+            )
+        });
+        *used = Instant::now();
+        session.clone()
+    }
+
+    /// Starts an exchange upon receiving `request`.
+    pub(crate) fn start(self: &Arc<Self>, request: &[u8]) -> Exchange {
+        history::count(self.transport);
+        let index = self.exchanges.fetch_add(1, Ordering::Relaxed);
+        Exchange {
+            session: self.clone(),
+            request: base64::engine::general_purpose::STANDARD.encode(request),
+            replayed: REPLAY
+                .get()
+                .map(|records| records[index.min(records.len() - 1)].clone()),
+            started: Instant::now(),
+            stamped: None,
+        }
+    }
+}
+
+/// A time exchange in progress.
+pub(crate) struct Exchange {
+    session: Arc<Session>,
+    request: String,
+    replayed: Option<Record>,
+    started: Instant,
+    /// When the server timestamp was taken, with its value and offset.
+    stamped: Option<(Instant, f64, f64)>,
+}
+
+impl Exchange {
+    /// Takes the server timestamp for the response: the served time, or when
+    /// replaying, the system time shifted by the recorded offset after the
+    /// recorded processing delay.
+    pub(crate) async fn timestamp(&mut self) -> SystemTime {
+        let system;
+        let served;
+        if let Some(record) = &self.replayed {
+            sleep(record.sent - record.received).await;
+            system = SystemTime::now();
+            served = shift(system, record.offset);
+        } else {
+            system = SystemTime::now();
+            served = clock::adjust(system);
+        }
+        self.stamped = Some((Instant::now(), secs(served), secs(served) - secs(system)));
+        served
+    }
+
+    /// Finishes the exchange just before the response is sent, waiting out
+    /// the recorded sending delay when replaying and writing the record when
+    /// recording.
+    pub(crate) async fn finish(self) {
+        if let Some(record) = &self.replayed {
+            sleep(record.replied - record.sent).await;
+        }
+        let (Some(recorder), Some((stamped, sent, offset))) = (RECORDER.get(), self.stamped) else {
+            return;
+        };
+        let record = Record {
+            session: self.session.id,
+            transport: self.session.transport,
+            peer: self.session.peer.clone(),
+            request: self.request,
+            received: sent - (stamped - self.started).as_secs_f64(),
+            sent,
+            replied: sent + stamped.elapsed().as_secs_f64(),
+            offset,
+        };
+        if recorder.try_send(record).is_err() {
+            tracing::warn!("Recording can't keep up, dropping an exchange");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Record, load_session};
+    use crate::chaos::Transport;
+
+    #[test]
+    fn test_load_session() {
+        let record = |session, sent| Record {
+            session,
+            transport: Transport::Ws,
+            peer: "127.0.0.1:1234".to_string(),
+            request: "AA==".to_string(),
+            received: sent - 0.001,
+            sent,
+            replied: sent + 0.002,
+            offset: -0.5,
+        };
+        let recording = [record(7, 1.0), record(9, 2.0), record(7, 3.0)]
+            .iter()
+            .map(|r| serde_json::to_string(r).unwrap() + "\n")
+            .collect::<String>();
+        assert!(recording.starts_with(
+            r#"{"session":7,"transport":"ws","peer":"127.0.0.1:1234","request":"AA==","#
+        ));
+
+        assert_eq!(
+            load_session(&recording, None).unwrap(),
+            [record(7, 1.0), record(7, 3.0)]
+        );
+        assert_eq!(load_session(&recording, Some(9)).unwrap(), [record(9, 2.0)]);
+        assert!(load_session(&recording, Some(8)).is_err());
+        assert!(load_session("{", None).is_err());
+    }
+}
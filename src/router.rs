@@ -1,7 +1,9 @@
+use std::sync::OnceLock;
+
 use salvo::logging::Logger;
 use salvo::prelude::*;
 
-use crate::{assets, http, websocket, webtransport};
+use crate::{acme, assets, history, http, probe, proxy, telemetry, tsa, websocket, webtransport};
 
 #[handler]
 async fn cross_origin_isolation(
@@ -11,27 +13,133 @@ async fn cross_origin_isolation(
     ctrl: &mut FlowCtrl,
 ) {
     ctrl.call_next(req, depot, res).await;
-    if !req.uri().path().starts_with("/.well-known/") {
-        res.add_header("cross-origin-opener-policy", "same-origin", true)
-            .ok();
-        res.add_header("cross-origin-embedder-policy", "require-corp", true)
-            .ok();
-    }
+    res.add_header("cross-origin-opener-policy", "same-origin", true)
+        .ok();
+    res.add_header("cross-origin-embedder-policy", "require-corp", true)
+        .ok();
+}
+
+static BASE_PATH: OnceLock<String> = OnceLock::new();
+
+/// Parses a path such as `/time/` to mount the routes under, normalized
+/// without the trailing slash.
+pub(crate) fn parse_base_path(s: &str) -> Result<String, String> {
+    let parse = || -> anyhow::Result<String> {
+        if !s.starts_with('/') {
+            anyhow::bail!("Expected a path starting with /: {s}");
+        }
+        if let Some(c) = s.chars().find(|c| "?#{}<>%\"\\".contains(*c)) {
+            anyhow::bail!("Unsupported character in path: {c}");
+        }
+        if s.split('/')
+            .any(|segment| segment == "." || segment == "..")
+        {
+            anyhow::bail!("Expected a normalized path: {s}");
+        }
+        Ok(s.trim_end_matches('/').to_string())
+    };
+    parse().map_err(|e| format!("{e:#}"))
+}
+
+/// Mounts the routes under `path` instead of `/`.
+pub(crate) fn set_base_path(path: String) {
+    BASE_PATH.set(path).ok();
+}
+
+/// The path the routes are mounted under, without the trailing slash: empty
+/// when they are at the root.
+pub(crate) fn base_path() -> &'static str {
+    BASE_PATH.get().map_or("", String::as_str)
 }
 
 pub(crate) fn router() -> Router {
-    Router::new()
-        .hoop(Logger::new())
-        .hoop(cross_origin_isolation)
-        .get(assets::index)
-        .push(Router::with_path("countdown").get(assets::countdown))
+    router_at(base_path())
+}
+
+fn router_at(base_path: &str) -> Router {
+    let routes = Router::new()
         .push(
             Router::with_path(".well-known/time")
                 .get(http::time)
                 .head(http::time)
-                .options(http::time_options),
+                .options(http::time_options)
+                .push(Router::with_path("key").get(http::time_key))
+                .push(Router::with_path("status").get(http::time_status))
+                .push(Router::with_path("health").get(http::time_health))
+                .push(Router::with_path("history").get(history::time_history))
+                .push(Router::with_path("clients").get(probe::clients))
+                .push(
+                    Router::with_path("telemetry")
+                        .get(telemetry::summaries)
+                        .post(telemetry::beacon),
+                )
+                .push(
+                    Router::with_path("wait")
+                        .get(http::time_wait)
+                        .options(http::time_wait_options),
+                ),
         )
-        .push(Router::with_path("time-ws").goal(websocket::time_ws))
-        .push(Router::with_path("time-wt").goal(webtransport::time_wt))
-        .push(Router::with_path("{*path}").get(assets::static_files()))
+        .push(
+            Router::new()
+                .hoop(cross_origin_isolation)
+                .get(assets::index)
+                .push(Router::with_path("countdown").get(assets::countdown))
+                .push(Router::with_path("tsa").post(tsa::timestamp))
+                .push(Router::with_path("time-ws").goal(websocket::time_ws))
+                .push(Router::with_path("time-wt").goal(webtransport::time_wt))
+                .push(Router::with_path("{*path}").get(assets::static_files())),
+        );
+    let routes = match base_path.trim_start_matches('/') {
+        "" => routes,
+        path => Router::with_path(path).push(routes),
+    };
+    Router::new()
+        .hoop(proxy::forwarded)
+        .hoop(Logger::new())
+        // Certificate authorities only check the root of the domain.
+        .push(Router::with_path(".well-known/acme-challenge/{token}").get(acme::http_challenge))
+        .push(routes)
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::prelude::*;
+    use salvo::test::TestClient;
+
+    use super::{parse_base_path, router_at};
+
+    #[test]
+    fn test_parse_base_path() {
+        assert_eq!(parse_base_path("/time/"), Ok("/time".to_string()));
+        assert_eq!(parse_base_path("/a/b"), Ok("/a/b".to_string()));
+        assert_eq!(parse_base_path("/"), Ok(String::new()));
+        assert!(parse_base_path("time").is_err());
+        assert!(parse_base_path("/{path}").is_err());
+        assert!(parse_base_path("/a/../b").is_err());
+    }
+
+    #[tokio::test]
+    async fn test_base_path() {
+        let service = Service::new(router_at("/time"));
+        let res = TestClient::get("http://localhost/time/.well-known/time")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        assert!(res.headers().get("cross-origin-opener-policy").is_none());
+        let res = TestClient::get("http://localhost/.well-known/time")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::NOT_FOUND));
+
+        let res = TestClient::get("http://localhost/time/")
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::OK));
+        assert!(res.headers().get("cross-origin-opener-policy").is_some());
+        // Relative links only work below the trailing slash.
+        let res = TestClient::get("http://localhost/time?mode=Fetch")
+            .send(&service)
+            .await;
+        assert_eq!(res.headers().get("location").unwrap(), "/time/?mode=Fetch");
+    }
 }
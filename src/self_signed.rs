@@ -1,28 +1,278 @@
-use base64::Engine;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, OnceLock};
+
+use anyhow::Context;
 use rcgen::{CertificateParams, KeyPair};
-use salvo::conn::rustls::{Keycert, RustlsConfig};
-use sha2::{Digest, Sha256};
+use rustls::pki_types::CertificateDer;
+use rustls::pki_types::pem::PemObject;
+use rustls::sign::CertifiedKey;
 use time::{Duration, OffsetDateTime};
+use x509_parser::extensions::GeneralName;
+
+use crate::tls::{self, ReloadableCert, TlsConfig};
+
+/// Validity of generated certificates, the longest that browsers accept by
+/// hash for WebTransport.
+const VALIDITY: Duration = Duration::days(14);
+
+/// How long after its start of validity a certificate's successor is
+/// announced.
+const ANNOUNCE_AFTER: Duration = Duration::days(7);
+
+/// How long after its start of validity a certificate is replaced, leaving a
+/// day of margin before it expires.
+const ROTATE_AFTER: Duration = Duration::days(13);
+
+/// Margin for clients whose clocks are behind.
+const CLOCK_MARGIN: Duration = Duration::hours(1);
+
+/// Names of the files kept in the state directory: the served certificate,
+/// the announced one, and the hashes pages pin, one per line.
+const CURRENT: &str = "cert";
+const NEXT: &str = "next-cert";
+const HASHES: &str = "cert-hashes";
+
+static NAMES: OnceLock<Vec<String>> = OnceLock::new();
+static DIR: OnceLock<PathBuf> = OnceLock::new();
+
+/// Issues certificates for `names`, which are host names or IP addresses,
+/// instead of `localhost`.
+pub(crate) fn set_names(names: Vec<String>) {
+    NAMES.set(names).ok();
+}
 
-pub(crate) fn generate() -> anyhow::Result<(RustlsConfig, String)> {
+/// Keeps the certificates in `dir`, so that they are reused across restarts.
+pub(crate) fn set_dir(dir: &str) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dir).with_context(|| format!("Failed to create {dir}"))?;
+    DIR.set(dir.into()).ok();
+    Ok(())
+}
+
+fn names() -> Vec<String> {
+    NAMES
+        .get()
+        .cloned()
+        .unwrap_or_else(|| vec!["localhost".to_string()])
+}
+
+/// A generated certificate, in the form it is served and stored.
+struct Generated {
+    key: CertifiedKey,
+    cert_pem: String,
+    key_pem: String,
+    not_before: OffsetDateTime,
+}
+
+fn generate_key(not_before: OffsetDateTime) -> anyhow::Result<Generated> {
     let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256)?;
-    let now = OffsetDateTime::now_utc();
-    let mut params = CertificateParams::new(vec!["localhost".to_string()])?;
-    params.not_before = now;
-    params.not_after = now + Duration::days(14);
+    let mut params = CertificateParams::new(names())?;
+    params.not_before = not_before;
+    params.not_after = not_before + VALIDITY;
 
     let cert = params.self_signed(&key_pair)?;
-    let cert_der: &[u8] = cert.der();
-    let cert_hash =
-        base64::engine::general_purpose::STANDARD.encode(Sha256::digest(cert_der).as_slice());
-    tracing::info!("Certificate SHA-256 fingerprint (base64): {}", cert_hash);
-
-    Ok((
-        RustlsConfig::new(
-            Keycert::new()
-                .cert(cert.pem().as_bytes())
-                .key(key_pair.serialize_pem().as_bytes()),
-        ),
-        cert_hash,
-    ))
+    let (cert_pem, key_pem) = (cert.pem(), key_pair.serialize_pem());
+    Ok(Generated {
+        key: tls::certified_key(cert_pem.as_bytes(), key_pem.as_bytes())?,
+        cert_pem,
+        key_pem,
+        not_before,
+    })
+}
+
+/// The start of validity of `cert`, if it is valid for long enough and for
+/// exactly `names`.
+fn reusable(cert: &[u8], names: &[String]) -> Option<OffsetDateTime> {
+    let (_, cert) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let not_before =
+        OffsetDateTime::from_unix_timestamp(cert.validity().not_before.timestamp()).ok()?;
+    let not_after =
+        OffsetDateTime::from_unix_timestamp(cert.validity().not_after.timestamp()).ok()?;
+    if not_after - not_before > VALIDITY || OffsetDateTime::now_utc() >= not_before + ROTATE_AFTER {
+        return None;
+    }
+
+    let mut cert_names: Vec<String> = cert
+        .subject_alternative_name()
+        .ok()??
+        .value
+        .general_names
+        .iter()
+        .filter_map(|name| match name {
+            GeneralName::DNSName(name) => Some(name.to_string()),
+            GeneralName::IPAddress(ip) => match ip.len() {
+                4 => Some(IpAddr::from(<[u8; 4]>::try_from(*ip).ok()?).to_string()),
+                _ => Some(IpAddr::from(<[u8; 16]>::try_from(*ip).ok()?).to_string()),
+            },
+            _ => None,
+        })
+        .collect();
+    let mut names: Vec<String> = names
+        .iter()
+        .map(|name| match name.parse::<IpAddr>() {
+            Ok(ip) => ip.to_string(),
+            Err(_) => name.clone(),
+        })
+        .collect();
+    cert_names.sort();
+    names.sort();
+    (cert_names == names).then_some(not_before)
+}
+
+fn load(dir: &Path, name: &str) -> Option<Generated> {
+    let cert_pem = std::fs::read_to_string(dir.join(format!("{name}.pem"))).ok()?;
+    let key_pem = std::fs::read_to_string(dir.join(format!("{name}.key"))).ok()?;
+    let not_before = reusable(
+        &CertificateDer::from_pem_slice(cert_pem.as_bytes()).ok()?,
+        &names(),
+    )?;
+    Some(Generated {
+        key: tls::certified_key(cert_pem.as_bytes(), key_pem.as_bytes()).ok()?,
+        cert_pem,
+        key_pem,
+        not_before,
+    })
+}
+
+fn store(dir: &Path, name: &str, generated: &Generated) -> anyhow::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::OpenOptionsExt;
+
+    let key_path = dir.join(format!("{name}.key"));
+    std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(&key_path)
+        .and_then(|mut file| file.write_all(generated.key_pem.as_bytes()))
+        .with_context(|| format!("Failed to write {}", key_path.display()))?;
+    let cert_path = dir.join(format!("{name}.pem"));
+    std::fs::write(&cert_path, &generated.cert_pem)
+        .with_context(|| format!("Failed to write {}", cert_path.display()))
+}
+
+/// Records a change of certificates in the state directory, if any.
+fn persist(cert: &ReloadableCert, update: impl FnOnce(&Path) -> anyhow::Result<()>) {
+    let Some(dir) = DIR.get() else {
+        return;
+    };
+    let hashes = dir.join(HASHES);
+    let result = update(dir).and_then(|()| {
+        std::fs::write(&hashes, cert.hashes().join("\n") + "\n")
+            .with_context(|| format!("Failed to write {}", hashes.display()))
+    });
+    if let Err(e) = result {
+        tracing::error!("Failed to store self-signed certificate: {e:#}");
+    }
+}
+
+async fn sleep_until(time: OffsetDateTime) {
+    let duration = time - OffsetDateTime::now_utc();
+    tokio::time::sleep(duration.try_into().unwrap_or_default()).await;
+}
+
+/// Replaces the certificate before it expires. Its successor is generated and
+/// announced well ahead, so that pages loaded in the meantime pin both and
+/// keep connecting after the switch.
+async fn rotate(
+    cert: Arc<ReloadableCert>,
+    mut not_before: OffsetDateTime,
+    mut next: Option<Generated>,
+) {
+    loop {
+        sleep_until(not_before + ANNOUNCE_AFTER).await;
+        let upcoming = match next.take() {
+            Some(upcoming) => upcoming,
+            None => loop {
+                match generate_key(not_before + ROTATE_AFTER - CLOCK_MARGIN) {
+                    Ok(upcoming) => break upcoming,
+                    Err(e) => {
+                        tracing::error!("Failed to generate certificate: {e:#}");
+                        tokio::time::sleep(std::time::Duration::from_secs(60)).await;
+                    }
+                }
+            },
+        };
+        cert.announce(&upcoming.key);
+        persist(&cert, |dir| store(dir, NEXT, &upcoming));
+        tracing::info!("Announced certificate: {:?}", cert.hashes().last());
+
+        sleep_until(not_before + ROTATE_AFTER).await;
+        not_before = upcoming.not_before;
+        cert.replace(upcoming.key.clone());
+        persist(&cert, |dir| store(dir, CURRENT, &upcoming));
+        tracing::info!("Rotated certificate: {:?}", cert.hashes());
+    }
+}
+
+/// Generates a self-signed certificate, or reuses the one in the state
+/// directory while it is valid, and rotates it every 13 days.
+pub(crate) fn generate() -> anyhow::Result<TlsConfig> {
+    let (current, next) = match DIR.get() {
+        Some(dir) => match load(dir, CURRENT) {
+            Some(current) => {
+                let next = load(dir, NEXT).filter(|next| next.not_before > current.not_before);
+                (Some(current), next)
+            }
+            // The server was down when the announced certificate was due
+            // to take over.
+            None => {
+                let next =
+                    load(dir, NEXT).filter(|next| next.not_before <= OffsetDateTime::now_utc());
+                (next, None)
+            }
+        },
+        None => (None, None),
+    };
+    let current = match current {
+        Some(current) => current,
+        None => generate_key(OffsetDateTime::now_utc())?,
+    };
+
+    let cert = Arc::new(ReloadableCert::new(current.key.clone()));
+    if let Some(next) = &next {
+        cert.announce(&next.key);
+    }
+    persist(&cert, |dir| store(dir, CURRENT, &current));
+    tracing::info!(
+        "Certificate SHA-256 fingerprint (base64): {}",
+        cert.hashes().join(", ")
+    );
+    tokio::spawn(rotate(cert.clone(), current.not_before, next));
+    Ok(TlsConfig::from_cert(cert))
+}
+
+#[cfg(test)]
+mod tests {
+    use time::{Duration, OffsetDateTime};
+
+    use super::{VALIDITY, generate_key, reusable};
+
+    #[test]
+    fn test_reusable() {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .ok();
+        let names = ["localhost".to_string(), "192.168.1.2".to_string()];
+        let now = OffsetDateTime::now_utc();
+        let cert = |not_before| {
+            let mut params = rcgen::CertificateParams::new(names.to_vec()).unwrap();
+            params.not_before = not_before;
+            params.not_after = not_before + VALIDITY;
+            let key_pair = rcgen::KeyPair::generate().unwrap();
+            params.self_signed(&key_pair).unwrap().der().to_vec()
+        };
+
+        let not_before = now - Duration::days(3);
+        let reused = reusable(&cert(not_before), &names).unwrap();
+        assert_eq!(reused.unix_timestamp(), not_before.unix_timestamp());
+        let reordered = [names[1].clone(), names[0].clone()];
+        assert!(reusable(&cert(not_before), &reordered).is_some());
+        assert!(reusable(&cert(not_before), &names[..1]).is_none());
+        // Due for rotation.
+        assert!(reusable(&cert(now - Duration::days(13)), &names).is_none());
+
+        assert!(generate_key(now).is_ok());
+    }
 }
@@ -0,0 +1,49 @@
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use salvo::server::ServerHandle;
+use tokio::signal::unix::{SignalKind, signal};
+use tokio::sync::watch;
+
+use crate::systemd;
+
+static STOPPING: OnceLock<watch::Sender<bool>> = OnceLock::new();
+
+fn stopping_sender() -> &'static watch::Sender<bool> {
+    STOPPING.get_or_init(|| watch::Sender::new(false))
+}
+
+/// Resolves once the server starts shutting down, so that long-lived sessions
+/// can say goodbye to their clients.
+pub(crate) async fn stopping() {
+    stopping_sender()
+        .subscribe()
+        .wait_for(|stopping| *stopping)
+        .await
+        .ok();
+}
+
+/// On SIGTERM or SIGINT, stops accepting connections and gives the open ones
+/// up to `drain_timeout` to finish. A second signal stops at once.
+pub(crate) fn spawn(handle: ServerHandle, drain_timeout: Duration) -> anyhow::Result<()> {
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::spawn(async move {
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        tracing::info!("Shutting down, draining connections for up to {drain_timeout:?}");
+        systemd::notify("STOPPING=1");
+        stopping_sender().send_replace(true);
+        handle.stop_graceful(drain_timeout);
+
+        tokio::select! {
+            _ = terminate.recv() => {}
+            _ = interrupt.recv() => {}
+        }
+        tracing::warn!("Stopping without draining");
+        handle.stop_forcible();
+    });
+    Ok(())
+}
@@ -0,0 +1,69 @@
+use std::sync::OnceLock;
+
+use anyhow::Context;
+use base64::Engine;
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use rustls::pki_types::PrivatePkcs8KeyDer;
+use rustls::pki_types::pem::PemObject;
+
+/// Domain separation prefix for signed time responses.
+const CONTEXT: &str = "foxtime-time-v1";
+
+/// Longest client nonce that will be signed.
+pub(crate) const MAX_NONCE_LEN: usize = 128;
+
+#[derive(Debug)]
+pub(crate) struct Signer {
+    key_pair: Ed25519KeyPair,
+    identity: String,
+}
+
+impl Signer {
+    /// Loads a PEM-encoded PKCS#8 Ed25519 private key, such as one generated by
+    /// `openssl genpkey -algorithm ed25519`.
+    pub(crate) fn from_pem_file(path: &str, identity: String) -> anyhow::Result<Self> {
+        let der = PrivatePkcs8KeyDer::from_pem_file(path)
+            .with_context(|| format!("Read signing key {path}"))?;
+        Self::from_pkcs8(der.secret_pkcs8_der(), identity)
+    }
+
+    pub(crate) fn from_pkcs8(der: &[u8], identity: String) -> anyhow::Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8_maybe_unchecked(der)
+            .map_err(|e| anyhow::anyhow!("Invalid Ed25519 signing key: {e}"))?;
+        Ok(Self { key_pair, identity })
+    }
+
+    pub(crate) fn identity(&self) -> &str {
+        &self.identity
+    }
+
+    /// The raw 32-byte public key, base64-encoded.
+    pub(crate) fn public_key(&self) -> String {
+        base64::engine::general_purpose::STANDARD.encode(self.key_pair.public_key().as_ref())
+    }
+
+    /// Signs a timestamp exactly as sent in `x-httpstime`, returning the
+    /// base64-encoded signature.
+    pub(crate) fn sign(&self, timestamp: &str, nonce: &str) -> String {
+        let signature = self
+            .key_pair
+            .sign(message(&self.identity, nonce, timestamp).as_bytes());
+        base64::engine::general_purpose::STANDARD.encode(signature.as_ref())
+    }
+}
+
+/// The signed message: a context string, the server identity, the client
+/// nonce and the timestamp, separated by newlines.
+pub(crate) fn message(identity: &str, nonce: &str, timestamp: &str) -> String {
+    format!("{CONTEXT}\n{identity}\n{nonce}\n{timestamp}")
+}
+
+static SIGNER: OnceLock<Option<Signer>> = OnceLock::new();
+
+pub(crate) fn set_signer(signer: Option<Signer>) {
+    SIGNER.set(signer).expect("SIGNER already set");
+}
+
+pub(crate) fn signer() -> Option<&'static Signer> {
+    SIGNER.get().and_then(|s| s.as_ref())
+}
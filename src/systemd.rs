@@ -0,0 +1,191 @@
+use std::ffi::OsStr;
+use std::os::fd::{FromRawFd, OwnedFd, RawFd};
+use std::os::linux::net::SocketAddrExt;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::net::{SocketAddr, UnixDatagram};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyhow::Context;
+use nix::fcntl::{FcntlArg, FdFlag, fcntl};
+use salvo::conn::{Accepted, Acceptor, Holding};
+use salvo::fuse::ArcFuseFactory;
+use tokio::time::Interval;
+
+use crate::clock;
+
+/// First file descriptor passed by socket activation.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// When the server last waited for a connection, as seen by `Heartbeat`.
+static HEARTBEAT: Mutex<Option<Instant>> = Mutex::new(None);
+
+fn for_us(pid_var: &str) -> bool {
+    std::env::var(pid_var).is_ok_and(|pid| pid.parse() == Ok(std::process::id()))
+}
+
+/// Takes the sockets passed by socket activation (`LISTEN_FDS`), with their
+/// `FileDescriptorName=` (`LISTEN_FDNAMES`). Must only be called once.
+pub(crate) fn listen_fds() -> anyhow::Result<Vec<(String, OwnedFd)>> {
+    if !for_us("LISTEN_PID") {
+        return Ok(Vec::new());
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .context("LISTEN_FDS is not set")?
+        .parse()
+        .context("Invalid LISTEN_FDS")?;
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    (LISTEN_FDS_START..LISTEN_FDS_START + count)
+        .map(|raw| {
+            // Passed to us by the service manager, and owned by nothing else
+            // in this process.
+            let fd = unsafe { OwnedFd::from_raw_fd(raw) };
+            fcntl(&fd, FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))
+                .with_context(|| format!("Invalid file descriptor {raw}"))?;
+            let name = names.next().unwrap_or("unknown").to_string();
+            Ok((name, fd))
+        })
+        .collect()
+}
+
+fn notify_to(path: &OsStr, state: &str) -> std::io::Result<()> {
+    let addr = match path.as_bytes().strip_prefix(b"@") {
+        Some(name) => SocketAddr::from_abstract_name(name)?,
+        None => SocketAddr::from_pathname(path)?,
+    };
+    UnixDatagram::unbound()?.send_to_addr(state.as_bytes(), &addr)?;
+    Ok(())
+}
+
+/// Reports `state`, such as `READY=1`, to the service manager, if started by
+/// one with `Type=notify`.
+pub(crate) fn notify(state: &str) {
+    let Some(path) = std::env::var_os("NOTIFY_SOCKET") else {
+        return;
+    };
+    if let Err(e) = notify_to(&path, state) {
+        tracing::warn!("Failed to notify the service manager: {e}");
+    }
+}
+
+/// The watchdog period (`WatchdogSec=`), if the service manager expects us
+/// to ping it.
+fn watchdog_period() -> Option<Duration> {
+    let period = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros)?;
+    if std::env::var_os("WATCHDOG_PID").is_some() && !for_us("WATCHDOG_PID") {
+        return None;
+    }
+    Some(period)
+}
+
+/// Wraps the server's acceptor to show the watchdog that its accept loop is
+/// still running: while waiting for a connection, it beats four times per
+/// watchdog period.
+pub(crate) struct Heartbeat<A> {
+    inner: A,
+    interval: Option<Interval>,
+}
+
+impl<A: Acceptor> Heartbeat<A> {
+    pub(crate) fn new(inner: A) -> Self {
+        Heartbeat {
+            inner,
+            interval: watchdog_period().map(|period| tokio::time::interval(period / 4)),
+        }
+    }
+}
+
+fn beat() {
+    *HEARTBEAT.lock().unwrap() = Some(Instant::now());
+}
+
+impl<A: Acceptor> Acceptor for Heartbeat<A> {
+    type Coupler = A::Coupler;
+    type Stream = A::Stream;
+
+    fn holdings(&self) -> &[Holding] {
+        self.inner.holdings()
+    }
+
+    async fn accept(
+        &mut self,
+        fuse_factory: Option<ArcFuseFactory>,
+    ) -> std::io::Result<Accepted<Self::Coupler, Self::Stream>> {
+        let Some(interval) = &mut self.interval else {
+            return self.inner.accept(fuse_factory).await;
+        };
+        let accept = self.inner.accept(fuse_factory);
+        tokio::pin!(accept);
+        loop {
+            tokio::select! {
+                accepted = &mut accept => {
+                    beat();
+                    return accepted;
+                }
+                _ = interval.tick() => beat(),
+            }
+        }
+    }
+}
+
+/// Pings the service manager's watchdog twice per period, as long as the
+/// server keeps waiting for connections and the clock can be read, so that a
+/// hung server gets restarted.
+pub(crate) fn spawn_watchdog() {
+    let Some(period) = watchdog_period() else {
+        return;
+    };
+    tokio::spawn(async move {
+        // Give the accept loop half a period to start beating.
+        let mut interval =
+            tokio::time::interval_at(tokio::time::Instant::now() + period / 2, period / 2);
+        loop {
+            interval.tick().await;
+            let serving = HEARTBEAT
+                .lock()
+                .unwrap()
+                .is_some_and(|beat| beat.elapsed() < period / 2);
+            if !serving {
+                tracing::error!("Accept loop stalled; not pinging the watchdog");
+                continue;
+            }
+            let check = tokio::task::spawn_blocking(clock::status);
+            match tokio::time::timeout(period / 2, check).await {
+                Ok(Ok(_)) => notify("WATCHDOG=1"),
+                _ => tracing::error!("Health check failed; not pinging the watchdog"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::linux::net::SocketAddrExt;
+    use std::os::unix::net::{SocketAddr, UnixDatagram};
+
+    use super::notify_to;
+
+    #[test]
+    fn test_notify() {
+        let dir = std::env::temp_dir().join(format!("foxtime-notify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("notify.sock");
+        let socket = UnixDatagram::bind(&path).unwrap();
+        notify_to(path.as_os_str(), "READY=1").unwrap();
+        let mut buf = [0; 16];
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"READY=1");
+
+        let name = format!("@foxtime-notify-{}", std::process::id());
+        let addr = SocketAddr::from_abstract_name(&name.as_bytes()[1..]).unwrap();
+        let socket = UnixDatagram::bind_addr(&addr).unwrap();
+        notify_to(name.as_ref(), "WATCHDOG=1").unwrap();
+        let len = socket.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..len], b"WATCHDOG=1");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,249 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Mutex, OnceLock};
+
+use salvo::http::header::USER_AGENT;
+use salvo::prelude::*;
+use serde::{Deserialize, Serialize};
+
+/// Reports kept per group; older ones are forgotten.
+const MAX_SAMPLES: usize = 1000;
+/// Groups kept; reports for new groups beyond this are ignored.
+const MAX_GROUPS: usize = 256;
+const MAX_LABEL_LEN: usize = 16;
+const TRANSPORTS: [&str; 3] = ["WebTransport", "WebSocket", "Fetch"];
+
+/// What the web worker reports after each measurement.
+#[derive(Debug, Deserialize)]
+struct Report {
+    /// Round-trip time, in milliseconds.
+    delay: f64,
+    /// Client clock minus server clock, in milliseconds.
+    offset: f64,
+    /// The transport the measurement used.
+    mode: String,
+    /// The browser's effective network type, such as `4g`.
+    network: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash, PartialOrd, Ord, Serialize)]
+struct Group {
+    user_agent: &'static str,
+    transport: String,
+    network: String,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Percentiles {
+    p50: f64,
+    p90: f64,
+    p99: f64,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Summary {
+    #[serde(flatten)]
+    group: Group,
+    samples: usize,
+    delay: Percentiles,
+    offset: Percentiles,
+}
+
+#[derive(Default)]
+struct Stats {
+    groups: HashMap<Group, VecDeque<(f64, f64)>>,
+}
+
+impl Stats {
+    fn add(&mut self, group: Group, delay: f64, offset: f64) {
+        if self.groups.len() >= MAX_GROUPS && !self.groups.contains_key(&group) {
+            return;
+        }
+        let samples = self.groups.entry(group).or_default();
+        if samples.len() >= MAX_SAMPLES {
+            samples.pop_front();
+        }
+        samples.push_back((delay, offset));
+    }
+
+    fn summary(&self) -> Vec<Summary> {
+        let mut summary: Vec<_> = self
+            .groups
+            .iter()
+            .map(|(group, samples)| Summary {
+                group: group.clone(),
+                samples: samples.len(),
+                delay: percentiles(samples.iter().map(|s| s.0).collect()),
+                offset: percentiles(samples.iter().map(|s| s.1).collect()),
+            })
+            .collect();
+        summary.sort_by(|a, b| a.group.cmp(&b.group));
+        summary
+    }
+}
+
+/// Nearest-rank percentiles of a non-empty set of values.
+fn percentiles(mut values: Vec<f64>) -> Percentiles {
+    values.sort_by(f64::total_cmp);
+    let rank = |p: f64| values[((p * values.len() as f64).ceil() as usize).max(1) - 1];
+    Percentiles {
+        p50: rank(0.5),
+        p90: rank(0.9),
+        p99: rank(0.99),
+    }
+}
+
+/// The browser family of a User-Agent header. Order matters, as Edge claims
+/// to be Chrome and Chrome claims to be Safari.
+fn browser(user_agent: &str) -> &'static str {
+    if user_agent.contains("Edg/") {
+        "Edge"
+    } else if user_agent.contains("Firefox/") {
+        "Firefox"
+    } else if user_agent.contains("Chrome/") || user_agent.contains("CriOS/") {
+        "Chrome"
+    } else if user_agent.contains("Safari/") {
+        "Safari"
+    } else {
+        "Other"
+    }
+}
+
+fn label(network: Option<&str>) -> String {
+    match network {
+        Some(n)
+            if !n.is_empty()
+                && n.len() <= MAX_LABEL_LEN
+                && n.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-') =>
+        {
+            n.to_string()
+        }
+        _ => "unknown".to_string(),
+    }
+}
+
+static STATS: OnceLock<Mutex<Stats>> = OnceLock::new();
+
+/// Accepts reports from the pages and tells them to send some.
+pub(crate) fn enable() {
+    STATS.get_or_init(Default::default);
+}
+
+pub(crate) fn enabled() -> bool {
+    STATS.get().is_some()
+}
+
+/// Accepts a JSON report from the web worker.
+#[handler]
+pub(crate) async fn beacon(req: &mut Request, res: &mut Response) {
+    let Some(groups) = STATS.get() else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    let report = match req.parse_json::<Report>().await {
+        Ok(report) => report,
+        Err(e) => {
+            res.status_code(StatusCode::BAD_REQUEST);
+            res.render(Text::Plain(e.to_string()));
+            return;
+        }
+    };
+    let Some(&transport) = TRANSPORTS.iter().find(|&&t| t == report.mode) else {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain("Unknown mode"));
+        return;
+    };
+    if !report.delay.is_finite() || report.delay < 0.0 || !report.offset.is_finite() {
+        res.status_code(StatusCode::BAD_REQUEST);
+        res.render(Text::Plain("Invalid measurement"));
+        return;
+    }
+    let group = Group {
+        user_agent: browser(req.header::<&str>(USER_AGENT).unwrap_or_default()),
+        transport: transport.to_string(),
+        network: label(report.network.as_deref()),
+    };
+    groups
+        .lock()
+        .unwrap()
+        .add(group, report.delay, report.offset);
+    res.status_code(StatusCode::NO_CONTENT);
+}
+
+/// Serves percentiles of the reported delays and offsets by browser,
+/// transport and network type.
+#[handler]
+pub(crate) async fn summaries(res: &mut Response) {
+    let Some(groups) = STATS.get() else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    res.render(Json(groups.lock().unwrap().summary()));
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::prelude::*;
+    use salvo::test::{ResponseExt, TestClient};
+
+    use super::{Percentiles, browser, percentiles};
+    use crate::router;
+
+    const FIREFOX: &str = "Mozilla/5.0 (X11; Linux x86_64; rv:140.0) Gecko/20100101 Firefox/140.0";
+
+    #[tokio::test]
+    async fn test_telemetry() {
+        assert_eq!(browser(FIREFOX), "Firefox");
+        assert_eq!(
+            browser("Mozilla/5.0 (Macintosh) AppleWebKit/605.1.15 Version/18.0 Safari/605.1.15"),
+            "Safari"
+        );
+        assert_eq!(
+            percentiles((1..=100).map(f64::from).collect()),
+            Percentiles {
+                p50: 50.0,
+                p90: 90.0,
+                p99: 99.0
+            }
+        );
+
+        super::enable();
+        let service = Service::new(router::router());
+        for i in 1..=10 {
+            let res = TestClient::post("http://127.0.0.1/.well-known/time/telemetry")
+                .add_header("user-agent", FIREFOX, true)
+                .json(&serde_json::json!({
+                    "delay": i,
+                    "offset": -i,
+                    "mode": "WebSocket",
+                    "network": "4g",
+                }))
+                .send(&service)
+                .await;
+            assert_eq!(res.status_code, Some(StatusCode::NO_CONTENT));
+        }
+        let res = TestClient::post("http://127.0.0.1/.well-known/time/telemetry")
+            .json(&serde_json::json!({"delay": 1, "offset": 0, "mode": "Carrier pigeon"}))
+            .send(&service)
+            .await;
+        assert_eq!(res.status_code, Some(StatusCode::BAD_REQUEST));
+
+        let summary: serde_json::Value =
+            TestClient::get("http://127.0.0.1/.well-known/time/telemetry")
+                .send(&service)
+                .await
+                .take_json()
+                .await
+                .unwrap();
+        assert_eq!(
+            summary,
+            serde_json::json!([{
+                "user_agent": "Firefox",
+                "transport": "WebSocket",
+                "network": "4g",
+                "samples": 10,
+                "delay": {"p50": 5.0, "p90": 9.0, "p99": 10.0},
+                "offset": {"p50": -6.0, "p90": -2.0, "p99": -1.0},
+            }])
+        );
+    }
+}
@@ -0,0 +1,220 @@
+use std::future::{Ready, ready};
+use std::sync::{Arc, RwLock};
+
+use anyhow::Context;
+use base64::Engine;
+use futures_util::stream::{Once, once};
+use quinn::crypto::rustls::QuicServerConfig;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::{ClientHello, ResolvesServerCert};
+use rustls::sign::CertifiedKey;
+use salvo::conn::IntoConfigStream;
+use sha2::{Digest, Sha256};
+
+/// ALPN protocol of TLS-ALPN-01 challenge handshakes (RFC 8737).
+pub(crate) const ACME_TLS_ALPN: &[u8] = b"acme-tls/1";
+
+/// Longest validity of a certificate that browsers accept by hash for
+/// WebTransport (`serverCertificateHashes`).
+const MAX_PINNED_VALIDITY: i64 = 14 * 86400;
+
+/// A certificate that can be replaced while listeners are running. New
+/// handshakes get the new certificate; established connections are unaffected.
+#[derive(Debug)]
+pub(crate) struct ReloadableCert {
+    key: RwLock<Arc<CertifiedKey>>,
+    /// See [`ReloadableCert::hashes`].
+    hashes: RwLock<Vec<String>>,
+}
+
+impl ReloadableCert {
+    pub(crate) fn new(key: CertifiedKey) -> Self {
+        ReloadableCert {
+            hashes: RwLock::new(pinned_hash(&key).into_iter().collect()),
+            key: RwLock::new(Arc::new(key)),
+        }
+    }
+
+    pub(crate) fn from_pem(cert: &[u8], key: &[u8]) -> anyhow::Result<Self> {
+        Ok(ReloadableCert::new(certified_key(cert, key)?))
+    }
+
+    pub(crate) fn replace(&self, key: CertifiedKey) {
+        *self.hashes.write().unwrap() = pinned_hash(&key).into_iter().collect();
+        *self.key.write().unwrap() = Arc::new(key);
+    }
+
+    /// Announces `next` as the certificate that will replace the current one,
+    /// so that pages loaded in the meantime pin both. Announcing it again
+    /// changes nothing.
+    pub(crate) fn announce(&self, next: &CertifiedKey) {
+        let mut hashes = self.hashes.write().unwrap();
+        if let Some(hash) = pinned_hash(next)
+            && !hashes.contains(&hash)
+        {
+            hashes.push(hash);
+        }
+    }
+
+    /// The base64-encoded SHA-256 hashes of the current and announced
+    /// certificates that browsers would accept by hash for WebTransport.
+    pub(crate) fn hashes(&self) -> Vec<String> {
+        self.hashes.read().unwrap().clone()
+    }
+}
+
+impl ResolvesServerCert for ReloadableCert {
+    fn resolve(&self, _client_hello: ClientHello<'_>) -> Option<Arc<CertifiedKey>> {
+        Some(self.key.read().unwrap().clone())
+    }
+}
+
+/// The hash under which browsers may pin `key`'s certificate, which must not
+/// have expired nor be valid for longer than 14 days.
+fn pinned_hash(key: &CertifiedKey) -> Option<String> {
+    let cert = key.end_entity_cert().ok()?;
+    let (_, parsed) = x509_parser::parse_x509_certificate(cert).ok()?;
+    let validity = parsed.validity();
+    if validity.time_to_expiration().is_none()
+        || validity.not_after.timestamp() - validity.not_before.timestamp() > MAX_PINNED_VALIDITY
+    {
+        return None;
+    }
+    Some(base64::engine::general_purpose::STANDARD.encode(Sha256::digest(cert.as_ref())))
+}
+
+/// Where a TLS or QUIC listener gets its certificate from. The resolver is
+/// consulted on every handshake, so certificates can change without
+/// rebinding the listeners.
+#[derive(Clone, Debug)]
+pub(crate) struct TlsConfig {
+    resolver: Arc<dyn ResolvesServerCert>,
+    /// The certificate served by `resolver`, unless it picks one per
+    /// handshake.
+    cert: Option<Arc<ReloadableCert>>,
+    /// Also offers `acme-tls/1` so that TLS-ALPN-01 challenges can be
+    /// answered.
+    acme: bool,
+}
+
+impl TlsConfig {
+    pub(crate) fn new(resolver: Arc<dyn ResolvesServerCert>, acme: bool) -> Self {
+        TlsConfig {
+            resolver,
+            cert: None,
+            acme,
+        }
+    }
+
+    /// Serves `cert`, including after it is replaced.
+    pub(crate) fn from_cert(cert: Arc<ReloadableCert>) -> Self {
+        TlsConfig {
+            resolver: cert.clone(),
+            cert: Some(cert),
+            acme: false,
+        }
+    }
+
+    /// See [`ReloadableCert::hashes`].
+    pub(crate) fn cert_hashes(&self) -> Vec<String> {
+        self.cert
+            .as_ref()
+            .map(|cert| cert.hashes())
+            .unwrap_or_default()
+    }
+
+    fn server_config(&self, alpn: &[&[u8]]) -> rustls::ServerConfig {
+        let mut config = rustls::ServerConfig::builder()
+            .with_no_client_auth()
+            .with_cert_resolver(self.resolver.clone());
+        config.alpn_protocols = alpn.iter().map(|p| p.to_vec()).collect();
+        config
+    }
+
+    /// The configuration of a QUIC listener, which only speaks HTTP/3.
+    pub(crate) fn quic(&self) -> anyhow::Result<QuicServerConfig> {
+        let config = self.server_config(&[b"h3"]);
+        QuicServerConfig::try_from(config).context("Build QUIC configuration")
+    }
+}
+
+impl IntoConfigStream<rustls::ServerConfig> for TlsConfig {
+    type Stream = Once<Ready<rustls::ServerConfig>>;
+
+    fn into_stream(self) -> Self::Stream {
+        let config = if self.acme {
+            self.server_config(&[b"h2", b"http/1.1", ACME_TLS_ALPN])
+        } else {
+            self.server_config(&[b"h2", b"http/1.1"])
+        };
+        once(ready(config))
+    }
+}
+
+/// Parses a PEM-encoded certificate chain and private key.
+pub(crate) fn certified_key(cert: &[u8], key: &[u8]) -> anyhow::Result<CertifiedKey> {
+    let chain = CertificateDer::pem_slice_iter(cert)
+        .collect::<Result<Vec<_>, _>>()
+        .context("Invalid certificate")?;
+    if chain.is_empty() {
+        anyhow::bail!("No certificate found");
+    }
+    let key = PrivateKeyDer::from_pem_slice(key).context("Invalid private key")?;
+    let provider = rustls::crypto::CryptoProvider::get_default()
+        .context("No TLS crypto provider installed")?;
+    CertifiedKey::from_der(chain, key, provider).context("Certificate does not match key")
+}
+
+#[cfg(test)]
+mod tests {
+    use rcgen::{CertificateParams, KeyPair};
+    use time::{Duration, OffsetDateTime};
+
+    use super::{ReloadableCert, certified_key};
+
+    fn generate(days: i64) -> (String, String) {
+        let key_pair = KeyPair::generate().unwrap();
+        let mut params = CertificateParams::new(vec!["localhost".to_string()]).unwrap();
+        params.not_before = OffsetDateTime::now_utc() - Duration::hours(1);
+        params.not_after = params.not_before + Duration::days(days);
+        let cert = params.self_signed(&key_pair).unwrap();
+        (cert.pem(), key_pair.serialize_pem())
+    }
+
+    fn load((cert, key): (String, String)) -> rustls::sign::CertifiedKey {
+        certified_key(cert.as_bytes(), key.as_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_reloadable_cert() {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .ok();
+        let (cert, key) = generate(14);
+        let reloadable = ReloadableCert::from_pem(cert.as_bytes(), key.as_bytes()).unwrap();
+        let hashes = reloadable.hashes();
+        assert_eq!(hashes.len(), 1);
+        assert_eq!(hashes[0].len(), 44);
+
+        // Both hashes are published until the announced certificate is
+        // served.
+        let next = load(generate(13));
+        reloadable.announce(&next);
+        assert_eq!(reloadable.hashes().len(), 2);
+        assert_eq!(reloadable.hashes()[0], hashes[0]);
+        reloadable.announce(&next);
+        assert_eq!(reloadable.hashes().len(), 2);
+        reloadable.replace(next);
+        assert_eq!(reloadable.hashes().len(), 1);
+        assert_ne!(reloadable.hashes(), hashes);
+
+        // Too long-lived to be pinned.
+        reloadable.replace(load(generate(90)));
+        assert!(reloadable.hashes().is_empty());
+
+        // A mismatched pair does not load.
+        let (other, _) = generate(13);
+        assert!(certified_key(other.as_bytes(), key.as_bytes()).is_err());
+    }
+}
@@ -0,0 +1,556 @@
+use std::sync::OnceLock;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use rustls::SignatureScheme;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::sign::Signer;
+use salvo::prelude::*;
+use sha2::{Digest, Sha256};
+use time::OffsetDateTime;
+use yasna::models::{GeneralizedTime, ObjectIdentifier};
+use yasna::{DERWriter, Tag};
+
+const CONTENT_TYPE_QUERY: &str = "application/timestamp-query";
+const CONTENT_TYPE_REPLY: &str = "application/timestamp-reply";
+
+const OID_SHA256: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 1];
+const OID_SHA384: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 2];
+const OID_SHA512: &[u64] = &[2, 16, 840, 1, 101, 3, 4, 2, 3];
+const OID_SIGNED_DATA: &[u64] = &[1, 2, 840, 113549, 1, 7, 2];
+const OID_TST_INFO: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 1, 4];
+const OID_CONTENT_TYPE: &[u64] = &[1, 2, 840, 113549, 1, 9, 3];
+const OID_MESSAGE_DIGEST: &[u64] = &[1, 2, 840, 113549, 1, 9, 4];
+const OID_SIGNING_CERTIFICATE_V2: &[u64] = &[1, 2, 840, 113549, 1, 9, 16, 2, 47];
+const OID_ECDSA_WITH_SHA256: &[u64] = &[1, 2, 840, 10045, 4, 3, 2];
+const OID_ECDSA_WITH_SHA384: &[u64] = &[1, 2, 840, 10045, 4, 3, 3];
+const OID_SHA256_WITH_RSA: &[u64] = &[1, 2, 840, 113549, 1, 1, 11];
+
+// PKIStatus values.
+const STATUS_GRANTED: u8 = 0;
+const STATUS_REJECTION: u8 = 2;
+
+/// PKIFailureInfo bit positions.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FailureInfo {
+    BadAlg = 0,
+    BadRequest = 2,
+    BadDataFormat = 5,
+    TimeNotAvailable = 14,
+    UnacceptedPolicy = 15,
+    UnacceptedExtension = 16,
+    SystemFailure = 25,
+}
+
+/// An ASN.1 INTEGER as big-endian magnitude and sign.
+type BigInt = (Vec<u8>, bool);
+
+struct TimeStampReq {
+    message_imprint: Vec<u8>,
+    policy: Option<ObjectIdentifier>,
+    nonce: Option<BigInt>,
+    cert_req: bool,
+}
+
+fn parse_request(der: &[u8]) -> Result<TimeStampReq, FailureInfo> {
+    let (version, message_imprint, policy, nonce, cert_req, extensions) =
+        yasna::parse_ber(der, |r| {
+            r.read_sequence(|r| {
+                let version = r.next().read_u8()?;
+                let message_imprint = r.next().read_der()?;
+                let policy = r.read_optional(|r| r.read_oid())?;
+                let nonce = r.read_optional(|r| r.read_bigint_bytes())?;
+                let cert_req = r.read_optional(|r| r.read_bool())?.unwrap_or(false);
+                let extensions = r.read_optional(|r| r.read_der())?;
+                Ok((
+                    version,
+                    message_imprint,
+                    policy,
+                    nonce,
+                    cert_req,
+                    extensions,
+                ))
+            })
+        })
+        .map_err(|_| FailureInfo::BadDataFormat)?;
+    if version != 1 {
+        return Err(FailureInfo::BadRequest);
+    }
+    if extensions.is_some() {
+        return Err(FailureInfo::UnacceptedExtension);
+    }
+
+    let (hash_algorithm, hashed_message) = yasna::parse_ber(&message_imprint, |r| {
+        r.read_sequence(|r| {
+            let hash_algorithm = r.next().read_sequence(|r| {
+                let oid = r.next().read_oid()?;
+                r.read_optional(|r| r.read_null())?;
+                Ok(oid)
+            })?;
+            let hashed_message = r.next().read_bytes()?;
+            Ok((hash_algorithm, hashed_message))
+        })
+    })
+    .map_err(|_| FailureInfo::BadDataFormat)?;
+    let digest_len = match hash_algorithm.components().as_slice() {
+        OID_SHA256 => 32,
+        OID_SHA384 => 48,
+        OID_SHA512 => 64,
+        _ => return Err(FailureInfo::BadAlg),
+    };
+    if hashed_message.len() != digest_len {
+        return Err(FailureInfo::BadDataFormat);
+    }
+
+    Ok(TimeStampReq {
+        message_imprint,
+        policy,
+        nonce,
+        cert_req,
+    })
+}
+
+/// Extracts the DER-encoded issuer name and the serial number from a
+/// certificate, for the signer's IssuerAndSerialNumber.
+fn issuer_and_serial(cert: &[u8]) -> yasna::ASN1Result<(Vec<u8>, BigInt)> {
+    yasna::parse_ber(cert, |r| {
+        r.read_sequence(|r| {
+            let result = r.next().read_sequence(|r| {
+                r.read_optional(|r| r.read_tagged(Tag::context(0), |r| r.read_der()))?;
+                let serial = r.next().read_bigint_bytes()?;
+                r.next().read_der()?;
+                let issuer = r.next().read_der()?;
+                while r.read_optional(|r| r.read_der())?.is_some() {}
+                Ok((issuer, serial))
+            })?;
+            r.next().read_der()?;
+            r.next().read_der()?;
+            Ok(result)
+        })
+    })
+}
+
+fn write_algorithm(w: DERWriter, oid: &[u64], null_params: bool) {
+    w.write_sequence(|w| {
+        w.next().write_oid(&ObjectIdentifier::from_slice(oid));
+        if null_params {
+            w.next().write_null();
+        }
+    });
+}
+
+fn write_attribute(w: DERWriter, oid: &[u64], value: impl FnOnce(DERWriter)) {
+    w.write_sequence(|w| {
+        w.next().write_oid(&ObjectIdentifier::from_slice(oid));
+        w.next().write_set_of(|w| value(w.next()));
+    });
+}
+
+fn rejection(failure: FailureInfo) -> Vec<u8> {
+    let bit = failure as usize;
+    let mut fail_info = vec![0u8; bit / 8 + 1];
+    fail_info[bit / 8] = 0x80 >> (bit % 8);
+    yasna::construct_der(|w| {
+        w.write_sequence(|w| {
+            w.next().write_sequence(|w| {
+                w.next().write_u8(STATUS_REJECTION);
+                w.next().write_bitvec_bytes(&fail_info, bit + 1);
+            });
+        });
+    })
+}
+
+/// Accuracy as (seconds, millis, micros), derived from the kernel's maximum
+/// clock error so that the token never claims more than the clock can back.
+fn accuracy() -> (u64, u16, u16) {
+    let max_error = crate::clock::status()
+        .max_error
+        .max(Duration::from_micros(1));
+    let micros = max_error.as_micros();
+    (
+        (micros / 1_000_000) as u64,
+        (micros / 1_000 % 1_000) as u16,
+        (micros % 1_000) as u16,
+    )
+}
+
+pub(crate) struct Authority {
+    certs: Vec<CertificateDer<'static>>,
+    cert_hash: Vec<u8>,
+    issuer: Vec<u8>,
+    serial: BigInt,
+    signer: Box<dyn Signer>,
+    signature_algorithm: (&'static [u64], bool),
+    policy: ObjectIdentifier,
+    next_serial: AtomicU64,
+}
+
+impl Authority {
+    /// Creates a TSA from a PEM certificate chain (signing certificate first),
+    /// a PEM private key and the OID of the policy tokens are issued under.
+    pub(crate) fn new(cert_pem: &[u8], key_pem: &[u8], policy: &str) -> anyhow::Result<Self> {
+        let certs = CertificateDer::pem_slice_iter(cert_pem)
+            .collect::<Result<Vec<_>, _>>()
+            .context("Parse TSA certificate")?;
+        let cert = certs
+            .first()
+            .ok_or_else(|| anyhow::anyhow!("No TSA certificate found"))?;
+        let key = PrivateKeyDer::from_pem_slice(key_pem).context("Parse TSA key")?;
+        let key = rustls::crypto::ring::sign::any_supported_type(&key)
+            .map_err(|e| anyhow::anyhow!("Unsupported TSA key: {e}"))?;
+        let signer = key
+            .choose_scheme(&[
+                SignatureScheme::ECDSA_NISTP256_SHA256,
+                SignatureScheme::ECDSA_NISTP384_SHA384,
+                SignatureScheme::RSA_PKCS1_SHA256,
+            ])
+            .ok_or_else(|| anyhow::anyhow!("TSA key must be ECDSA P-256, P-384 or RSA"))?;
+        let signature_algorithm = match signer.scheme() {
+            SignatureScheme::ECDSA_NISTP256_SHA256 => (OID_ECDSA_WITH_SHA256, false),
+            SignatureScheme::ECDSA_NISTP384_SHA384 => (OID_ECDSA_WITH_SHA384, false),
+            _ => (OID_SHA256_WITH_RSA, true),
+        };
+        let (issuer, serial) =
+            issuer_and_serial(cert).map_err(|e| anyhow::anyhow!("Invalid TSA certificate: {e}"))?;
+        let policy = ObjectIdentifier::from_slice(
+            &policy
+                .split('.')
+                .map(|c| c.parse::<u64>())
+                .collect::<Result<Vec<_>, _>>()
+                .with_context(|| format!("Invalid TSA policy OID {policy}"))?,
+        );
+        let first_serial = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos() as u64;
+        Ok(Self {
+            cert_hash: Sha256::digest(cert).to_vec(),
+            certs,
+            issuer,
+            serial,
+            signer,
+            signature_algorithm,
+            policy,
+            next_serial: AtomicU64::new(first_serial),
+        })
+    }
+
+    fn respond(&self, request: &[u8]) -> Vec<u8> {
+        match self.issue(request) {
+            Ok(response) => response,
+            Err(failure) => {
+                tracing::debug!("Rejected time-stamp request: {failure:?}");
+                rejection(failure)
+            }
+        }
+    }
+
+    fn issue(&self, request: &[u8]) -> Result<Vec<u8>, FailureInfo> {
+        let req = parse_request(request)?;
+        if req.policy.as_ref().is_some_and(|p| *p != self.policy) {
+            return Err(FailureInfo::UnacceptedPolicy);
+        }
+        if !crate::clock::status().synchronized {
+            return Err(FailureInfo::TimeNotAvailable);
+        }
+
+        let now = crate::clock::now()
+            .duration_since(UNIX_EPOCH)
+            .map_err(|_| FailureInfo::TimeNotAvailable)?;
+        let gen_time = OffsetDateTime::UNIX_EPOCH + now;
+        let (seconds, millis, micros) = accuracy();
+        let serial = self.next_serial.fetch_add(1, Ordering::Relaxed);
+
+        let tst_info = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_u8(1);
+                w.next().write_oid(&self.policy);
+                w.next().write_der(&req.message_imprint);
+                w.next().write_u64(serial);
+                w.next()
+                    .write_generalized_time(&GeneralizedTime::from_datetime(gen_time));
+                w.next().write_sequence(|w| {
+                    if seconds > 0 {
+                        w.next().write_u64(seconds);
+                    }
+                    if millis > 0 {
+                        w.next()
+                            .write_tagged_implicit(Tag::context(0), |w| w.write_u16(millis));
+                    }
+                    if micros > 0 {
+                        w.next()
+                            .write_tagged_implicit(Tag::context(1), |w| w.write_u16(micros));
+                    }
+                });
+                if let Some((nonce, positive)) = &req.nonce {
+                    w.next().write_bigint_bytes(nonce, *positive);
+                }
+            });
+        });
+
+        // Signed attributes are signed as a SET but sent as [0] IMPLICIT.
+        let mut signed_attrs = yasna::construct_der(|w| {
+            w.write_set_of(|w| {
+                write_attribute(w.next(), OID_CONTENT_TYPE, |w| {
+                    w.write_oid(&ObjectIdentifier::from_slice(OID_TST_INFO))
+                });
+                write_attribute(w.next(), OID_MESSAGE_DIGEST, |w| {
+                    w.write_bytes(&Sha256::digest(&tst_info))
+                });
+                write_attribute(w.next(), OID_SIGNING_CERTIFICATE_V2, |w| {
+                    w.write_sequence(|w| {
+                        w.next().write_sequence(|w| {
+                            w.next().write_sequence(|w| {
+                                w.next().write_bytes(&self.cert_hash);
+                            });
+                        });
+                    });
+                });
+            });
+        });
+        let signature = self
+            .signer
+            .sign(&signed_attrs)
+            .map_err(|_| FailureInfo::SystemFailure)?;
+        signed_attrs[0] = 0xa0;
+
+        Ok(yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_sequence(|w| {
+                    w.next().write_u8(STATUS_GRANTED);
+                });
+                w.next().write_sequence(|w| {
+                    w.next()
+                        .write_oid(&ObjectIdentifier::from_slice(OID_SIGNED_DATA));
+                    w.next().write_tagged(Tag::context(0), |w| {
+                        self.write_signed_data(w, &req, &tst_info, &signed_attrs, &signature)
+                    });
+                });
+            });
+        }))
+    }
+
+    fn write_signed_data(
+        &self,
+        w: DERWriter,
+        req: &TimeStampReq,
+        tst_info: &[u8],
+        signed_attrs: &[u8],
+        signature: &[u8],
+    ) {
+        w.write_sequence(|w| {
+            w.next().write_u8(3);
+            w.next()
+                .write_set_of(|w| write_algorithm(w.next(), OID_SHA256, false));
+            w.next().write_sequence(|w| {
+                w.next()
+                    .write_oid(&ObjectIdentifier::from_slice(OID_TST_INFO));
+                w.next()
+                    .write_tagged(Tag::context(0), |w| w.write_bytes(tst_info));
+            });
+            if req.cert_req {
+                w.next().write_tagged_implicit(Tag::context(0), |w| {
+                    w.write_set_of(|w| {
+                        for cert in &self.certs {
+                            w.next().write_der(cert);
+                        }
+                    })
+                });
+            }
+            w.next().write_set_of(|w| {
+                w.next().write_sequence(|w| {
+                    w.next().write_u8(1);
+                    w.next().write_sequence(|w| {
+                        w.next().write_der(&self.issuer);
+                        w.next().write_bigint_bytes(&self.serial.0, self.serial.1);
+                    });
+                    write_algorithm(w.next(), OID_SHA256, false);
+                    w.next().write_der(signed_attrs);
+                    let (oid, null_params) = self.signature_algorithm;
+                    write_algorithm(w.next(), oid, null_params);
+                    w.next().write_bytes(signature);
+                });
+            });
+        });
+    }
+}
+
+static AUTHORITY: OnceLock<Option<Authority>> = OnceLock::new();
+
+pub(crate) fn set_authority(authority: Option<Authority>) {
+    AUTHORITY.set(authority).ok();
+}
+
+/// RFC 3161 time-stamp protocol over HTTP.
+#[handler]
+pub(crate) async fn timestamp(req: &mut Request, res: &mut Response) {
+    let Some(authority) = AUTHORITY.get().and_then(|a| a.as_ref()) else {
+        res.status_code(StatusCode::NOT_FOUND);
+        return;
+    };
+    if req.content_type().map(|m| m.essence_str().to_string()) != Some(CONTENT_TYPE_QUERY.into()) {
+        res.status_code(StatusCode::UNSUPPORTED_MEDIA_TYPE);
+        return;
+    }
+    let response = match req.payload().await {
+        Ok(payload) => authority.respond(payload),
+        Err(_) => rejection(FailureInfo::BadDataFormat),
+    };
+    res.add_header("content-type", CONTENT_TYPE_REPLY, true)
+        .ok();
+    res.body(response);
+}
+
+#[cfg(test)]
+mod tests {
+    use salvo::test::{ResponseExt, TestClient};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use rcgen::{CertificateParams, ExtendedKeyUsagePurpose, KeyPair};
+    use ring::signature::{ECDSA_P256_SHA256_ASN1, UnparsedPublicKey};
+    use sha2::{Digest, Sha256};
+    use yasna::Tag;
+    use yasna::models::ObjectIdentifier;
+
+    use crate::tsa::{self, FailureInfo, OID_SHA256, OID_TST_INFO};
+    use crate::{clock, router};
+
+    const POLICY: &str = "1.3.6.1.4.1.99999.1";
+
+    #[tokio::test]
+    async fn test_tsa() {
+        let key_pair = KeyPair::generate_for(&rcgen::PKCS_ECDSA_P256_SHA256).unwrap();
+        let mut params = CertificateParams::new(vec!["tsa.localhost".to_string()]).unwrap();
+        params.extended_key_usages = vec![ExtendedKeyUsagePurpose::TimeStamping];
+        let cert = params.self_signed(&key_pair).unwrap();
+        tsa::set_authority(Some(
+            tsa::Authority::new(
+                cert.pem().as_bytes(),
+                key_pair.serialize_pem().as_bytes(),
+                POLICY,
+            )
+            .unwrap(),
+        ));
+
+        let imprint = Sha256::digest(b"build artifact");
+        let nonce = [0x5a, 0x11, 0xce, 0x0f, 0x0d];
+        let request = yasna::construct_der(|w| {
+            w.write_sequence(|w| {
+                w.next().write_u8(1);
+                w.next().write_sequence(|w| {
+                    w.next().write_sequence(|w| {
+                        w.next()
+                            .write_oid(&ObjectIdentifier::from_slice(OID_SHA256));
+                    });
+                    w.next().write_bytes(&imprint);
+                });
+                w.next().write_bigint_bytes(&nonce, true);
+                w.next().write_bool(true);
+            });
+        });
+
+        let router = router::router();
+        let service = salvo::Service::new(router);
+        let post = || {
+            TestClient::post("http://localhost/tsa")
+                .add_header("content-type", "application/timestamp-query", true)
+                .body(request.clone())
+        };
+
+        // No tokens are issued while the clock is not synchronized.
+        if !clock::status().synchronized {
+            let mut response = post().send(&service).await;
+            assert_eq!(
+                response.take_bytes(None).await.unwrap(),
+                tsa::rejection(FailureInfo::TimeNotAvailable)
+            );
+        }
+        clock::set_reference(0, Duration::from_millis(1));
+
+        let t1 = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+        let mut response = post().send(&service).await;
+        let t2 = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        assert_eq!(response.status_code, Some(salvo::http::StatusCode::OK));
+        assert_eq!(
+            response.headers().get("content-type").unwrap(),
+            "application/timestamp-reply"
+        );
+        let body = response.take_bytes(None).await.unwrap();
+
+        let (status, (tst_info, signed_attrs, signature)) = yasna::parse_der(&body, |r| {
+            r.read_sequence(|r| {
+                let status = r.next().read_sequence(|r| r.next().read_u8())?;
+                let token = r.next().read_sequence(|r| {
+                    r.next().read_oid()?;
+                    r.next().read_tagged(Tag::context(0), |r| {
+                        r.read_sequence(|r| {
+                            assert_eq!(r.next().read_u8()?, 3);
+                            r.next().read_der()?;
+                            let tst_info = r.next().read_sequence(|r| {
+                                assert_eq!(
+                                    r.next().read_oid()?,
+                                    ObjectIdentifier::from_slice(OID_TST_INFO)
+                                );
+                                r.next().read_tagged(Tag::context(0), |r| r.read_bytes())
+                            })?;
+                            let certificates = r.next().read_der()?;
+                            assert_eq!(certificates[0], 0xa0);
+                            let mut signer_info = None;
+                            r.next().read_set_of(|r| {
+                                signer_info = Some(r.read_sequence(|r| {
+                                    r.next().read_u8()?;
+                                    r.next().read_der()?;
+                                    r.next().read_der()?;
+                                    let signed_attrs = r.next().read_der()?;
+                                    r.next().read_der()?;
+                                    let signature = r.next().read_bytes()?;
+                                    Ok((signed_attrs, signature))
+                                })?);
+                                Ok(())
+                            })?;
+                            let (signed_attrs, signature) = signer_info.unwrap();
+                            Ok((tst_info, signed_attrs, signature))
+                        })
+                    })
+                })?;
+                Ok((status, token))
+            })
+        })
+        .expect("malformed TimeStampResp");
+        assert_eq!(status, 0);
+
+        let (message_imprint, gen_time, response_nonce) = yasna::parse_der(&tst_info, |r| {
+            r.read_sequence(|r| {
+                r.next().read_u8()?;
+                assert_eq!(r.next().read_oid()?.to_string(), POLICY);
+                let message_imprint = r.next().read_sequence(|r| {
+                    r.next().read_der()?;
+                    r.next().read_bytes()
+                })?;
+                r.next().read_u64()?;
+                let gen_time = r.next().read_generalized_time()?;
+                r.next().read_sequence(|r| {
+                    while r.read_optional(|r| r.read_der())?.is_some() {}
+                    Ok(())
+                })?;
+                let nonce = r.next().read_bigint_bytes()?;
+                Ok((message_imprint, gen_time, nonce))
+            })
+        })
+        .expect("malformed TSTInfo");
+        assert_eq!(message_imprint, imprint.as_slice());
+        assert_eq!(response_nonce, (nonce.to_vec(), true));
+        let gen_time = gen_time.datetime().unix_timestamp_nanos();
+        assert!(gen_time >= t1.as_nanos() as i128 && gen_time <= t2.as_nanos() as i128);
+
+        // The signature covers the signed attributes encoded as a SET.
+        let mut signed_attrs = signed_attrs;
+        signed_attrs[0] = 0x31;
+        UnparsedPublicKey::new(&ECDSA_P256_SHA256_ASN1, key_pair.public_key_raw())
+            .verify(&signed_attrs, &signature)
+            .expect("token signature does not verify");
+    }
+}
@@ -0,0 +1,141 @@
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, UNIX_EPOCH};
+
+use tokio::sync::{Semaphore, watch};
+
+/// Longest a client may ask to be woken in advance. Clients that need to wait
+/// longer can simply issue another request.
+pub(crate) const MAX_WAIT: Duration = Duration::from_secs(60);
+
+/// Maximum number of outstanding wake-ups on a single WS or WT session.
+pub(crate) const MAX_PENDING: usize = 16;
+
+// Tokio timers have millisecond resolution, so the last stretch is spun.
+const SPIN_THRESHOLD: Duration = Duration::from_millis(2);
+
+/// Spinners that may run at once, each busying a thread of the blocking pool.
+/// Waits beyond that sleep instead, at the timers' resolution.
+const MAX_SPINNERS: usize = 2;
+
+static SPINNERS: Semaphore = Semaphore::const_new(MAX_SPINNERS);
+
+type Outcome = Option<Result<f64, WaitError>>;
+
+/// Spinners in progress, by the bits of their instant, so that everyone
+/// waiting for the same instant shares one.
+static SPINS: Mutex<BTreeMap<u64, watch::Receiver<Outcome>>> = Mutex::new(BTreeMap::new());
+
+#[derive(Clone, Debug)]
+pub(crate) enum WaitError {
+    Invalid,
+    TooFar,
+    Clock,
+}
+
+impl std::fmt::Display for WaitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WaitError::Invalid => write!(f, "Invalid instant"),
+            WaitError::TooFar => write!(
+                f,
+                "Instant is more than {} seconds away",
+                MAX_WAIT.as_secs()
+            ),
+            WaitError::Clock => write!(f, "System clock before epoch"),
+        }
+    }
+}
+
+fn now() -> Result<f64, WaitError> {
+    crate::clock::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|ts| ts.as_secs_f64())
+        .map_err(|_| WaitError::Clock)
+}
+
+/// Waits until the Unix time `instant` (in seconds) and returns the time at
+/// which the wait ended, for use as the send timestamp of the response.
+/// Instants in the past return immediately.
+pub(crate) async fn until(instant: f64) -> Result<f64, WaitError> {
+    if !instant.is_finite() {
+        return Err(WaitError::Invalid);
+    }
+    let now = now()?;
+    let remaining = instant - now;
+    if remaining <= 0.0 {
+        return Ok(now);
+    }
+    if remaining > MAX_WAIT.as_secs_f64() {
+        return Err(WaitError::TooFar);
+    }
+    if remaining > SPIN_THRESHOLD.as_secs_f64() {
+        tokio::time::sleep(Duration::from_secs_f64(remaining) - SPIN_THRESHOLD).await;
+    }
+    let Some(mut spin) = spin(instant) else {
+        return sleep_until(instant).await;
+    };
+    spin.wait_for(Option::is_some)
+        .await
+        .map_err(|_| WaitError::Clock)?
+        .clone()
+        .unwrap_or(Err(WaitError::Clock))
+}
+
+/// Joins the spinner for `instant`, starting one if none is running and there
+/// is room for another.
+fn spin(instant: f64) -> Option<watch::Receiver<Outcome>> {
+    let key = instant.to_bits();
+    let mut spins = SPINS.lock().unwrap();
+    if let Some(spin) = spins.get(&key) {
+        return Some(spin.clone());
+    }
+    let permit = SPINNERS.try_acquire().ok()?;
+    let (sender, receiver) = watch::channel(None);
+    spins.insert(key, receiver.clone());
+    tokio::task::spawn_blocking(move || {
+        let _permit = permit;
+        let outcome = loop {
+            match now() {
+                Ok(now) if now < instant => std::hint::spin_loop(),
+                outcome => break outcome,
+            }
+        };
+        SPINS.lock().unwrap().remove(&key);
+        sender.send_replace(Some(outcome));
+    });
+    Some(receiver)
+}
+
+async fn sleep_until(instant: f64) -> Result<f64, WaitError> {
+    loop {
+        let now = now()?;
+        if now >= instant {
+            return Ok(now);
+        }
+        tokio::time::sleep(Duration::from_secs_f64(instant - now)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{now, until};
+
+    #[tokio::test]
+    async fn test_until() {
+        let past = now().unwrap() - 1.0;
+        assert!(until(past).await.unwrap() >= past);
+
+        // Many waiters on the same instant share a spinner, and those on
+        // others beyond the limit sleep.
+        let instant = now().unwrap() + 0.05;
+        let waits = (0..64).map(|i| until(instant + f64::from(i % 8) * 0.001));
+        for (i, woken) in futures_util::future::join_all(waits)
+            .await
+            .into_iter()
+            .enumerate()
+        {
+            assert!(woken.unwrap() >= instant + (i % 8) as f64 * 0.001);
+        }
+    }
+}
@@ -1,40 +1,140 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
-use bytes::BytesMut;
+use bytes::{Bytes, BytesMut};
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use salvo::prelude::*;
 use salvo::websocket::{Message, WebSocketUpgrade};
 
+use crate::chaos::{self, Transport};
+use crate::probe::Prober;
+use crate::recording::{self, Exchange, Session};
+use crate::{federation, shutdown, wait};
+
+/// First byte of a "notify at T" request, followed by the instant as a
+/// little-endian f64 Unix time. Apart from probe replies (see
+/// `probe::PROBE_REPLY`), any other binary message is a plain time request,
+/// answered with the server time as a little-endian f64 followed by a flags
+/// byte (see `federation::FLAG_DEGRADED`).
+pub(crate) const WAIT_REQUEST: u8 = b'W';
+
+const CLOSE_GOING_AWAY: u16 = 1001;
+const CLOSE_POLICY_VIOLATION: u16 = 1008;
+
+fn wait_request(payload: &[u8]) -> Option<f64> {
+    match payload {
+        [WAIT_REQUEST, instant @ ..] => Some(f64::from_le_bytes(instant.try_into().ok()?)),
+        _ => None,
+    }
+}
+
+/// Takes the server time for a plain time request, after the simulated
+/// network delays if any.
+async fn time_response(mut exchange: Exchange) -> Option<Bytes> {
+    chaos::before(Transport::Ws).await;
+    let server_ts = exchange
+        .timestamp()
+        .await
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs_f64();
+    let mut response = BytesMut::with_capacity(9);
+    response.extend_from_slice(&server_ts.to_le_bytes());
+    response.extend_from_slice(&[federation::flags()]);
+    chaos::after(Transport::Ws).await;
+    exchange.finish().await;
+    Some(response.freeze())
+}
+
 #[handler]
+#[allow(clippy::collapsible_match)]
 pub(crate) async fn time_ws(req: &mut Request, res: &mut Response) -> Result<(), StatusError> {
+    let peer = recording::peer(req.remote_addr());
+    let user_agent = req.header::<String>("user-agent");
     WebSocketUpgrade::new()
         .upgrade(req, res, |mut ws| async move {
-            while let Some(msg) = ws.recv().await {
-                match msg {
-                    Ok(msg) if msg.is_binary() => {
-                        match SystemTime::now().duration_since(UNIX_EPOCH) {
-                            Ok(ts) => {
-                                let server_ts = ts.as_secs_f64();
-                                let mut response = BytesMut::with_capacity(8);
-                                response.extend_from_slice(&server_ts.to_le_bytes());
-                                if ws.send(Message::binary(response.freeze())).await.is_err() {
+            let mut prober = Prober::new(Transport::Ws, peer.clone(), user_agent);
+            let exchanges = Arc::new(Session::new(Transport::Ws, peer));
+            let mut waits = FuturesUnordered::new();
+            let mut replies = FuturesUnordered::new();
+            let stopping = shutdown::stopping();
+            tokio::pin!(stopping);
+            loop {
+                tokio::select! {
+                    _ = &mut stopping => {
+                        ws.send(Message::close_with(CLOSE_GOING_AWAY, "Server shutting down"))
+                            .await
+                            .ok();
+                        break;
+                    }
+                    // Stop reading while too many time responses are held back
+                    // so a client can't queue them without bound.
+                    msg = ws.recv(), if replies.len() < chaos::MAX_PENDING => match msg {
+                        Some(Ok(msg)) if msg.is_binary() => {
+                            if prober.reply(msg.as_bytes()) {
+                                continue;
+                            }
+                            if let Some(until) = wait_request(msg.as_bytes()) {
+                                if waits.len() >= wait::MAX_PENDING {
+                                    ws.send(Message::close_with(
+                                        CLOSE_POLICY_VIOLATION,
+                                        "Too many pending notifications",
+                                    ))
+                                    .await
+                                    .ok();
                                     break;
                                 }
+                                waits.push(async move { (until, wait::until(until).await) });
+                                continue;
                             }
-                            Err(_) => break,
+                            replies.push(time_response(exchanges.start(msg.as_bytes())));
+                        }
+                        Some(Ok(msg)) if msg.is_ping() => {
+                            if ws
+                                .send(Message::pong(msg.as_bytes().to_vec()))
+                                .await
+                                .is_err()
+                            {
+                                break;
+                            }
+                        }
+                        Some(Ok(msg)) if msg.is_close() => break,
+                        Some(Err(_)) | None => break,
+                        _ => {}
+                    },
+                    _ = prober.tick() => {
+                        if ws.send(Message::binary(prober.request())).await.is_err() {
+                            break;
                         }
                     }
-                    Ok(msg) if msg.is_ping() => {
-                        if ws
-                            .send(Message::pong(msg.as_bytes().to_vec()))
-                            .await
-                            .is_err()
-                        {
+                    Some(reply) = replies.next() => {
+                        let Some(response) = reply else { break };
+                        let mut failed = false;
+                        for _ in 0..chaos::copies(Transport::Ws) {
+                            failed |= ws.send(Message::binary(response.clone())).await.is_err();
+                        }
+                        if failed {
                             break;
                         }
                     }
-                    Ok(msg) if msg.is_close() => break,
-                    Err(_) => break,
-                    _ => {}
+                    Some((until, result)) = waits.next() => match result {
+                        Ok(server_ts) => {
+                            // Server time first so the reply also reads as a
+                            // plain time response.
+                            let mut response = BytesMut::with_capacity(16);
+                            response.extend_from_slice(&server_ts.to_le_bytes());
+                            response.extend_from_slice(&until.to_le_bytes());
+                            if ws.send(Message::binary(response.freeze())).await.is_err() {
+                                break;
+                            }
+                        }
+                        Err(e) => {
+                            ws.send(Message::close_with(CLOSE_POLICY_VIOLATION, e.to_string()))
+                                .await
+                                .ok();
+                            break;
+                        }
+                    },
                 }
             }
         })
@@ -116,4 +216,81 @@ mod tests {
             "server time {server_time} is after t2 {t2}"
         );
     }
+
+    #[tokio::test]
+    async fn test_time_ws_wait() {
+        let acceptor = TcpListener::new("127.0.0.1:0").bind().await;
+        let port = acceptor.holdings()[0]
+            .local_addr
+            .port()
+            .expect("could not get bound port");
+
+        let router = router();
+        tokio::spawn(async move {
+            Server::new(acceptor).serve(router).await;
+        });
+
+        let url = format!("ws://127.0.0.1:{port}/time-ws");
+        let mut websocket = reqwest::Client::new()
+            .get(&url)
+            .upgrade()
+            .send()
+            .await
+            .expect("failed to connect to WebSocket server")
+            .into_websocket()
+            .await
+            .expect("WebSocket upgrade failed");
+
+        let until = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64()
+            + 0.25;
+        let mut request = vec![super::WAIT_REQUEST];
+        request.extend_from_slice(&until.to_le_bytes());
+        websocket
+            .send(reqwest_websocket::Message::Binary(request.into()))
+            .await
+            .expect("failed to send WebSocket message");
+
+        // A plain time request is answered while the notification is pending.
+        websocket
+            .send(reqwest_websocket::Message::Binary(vec![0].into()))
+            .await
+            .expect("failed to send WebSocket message");
+        match websocket.next().await {
+            Some(Ok(reqwest_websocket::Message::Binary(bin))) => assert_eq!(bin.len(), 9),
+            other => panic!("unexpected WebSocket message: {other:?}"),
+        }
+
+        let message = websocket
+            .next()
+            .await
+            .expect("WebSocket closed before notification")
+            .expect("WebSocket error");
+        let t2 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64();
+
+        let (server_time, echoed) = match message {
+            reqwest_websocket::Message::Binary(bin) => {
+                assert_eq!(bin.len(), 16, "unexpected notification length");
+                (
+                    f64::from_le_bytes(bin[..8].try_into().unwrap()),
+                    f64::from_le_bytes(bin[8..16].try_into().unwrap()),
+                )
+            }
+            other => panic!("unexpected WebSocket message type: {other:?}"),
+        };
+        assert_eq!(echoed, until);
+        assert!(
+            server_time >= until,
+            "server time {server_time} is before until {until}"
+        );
+        assert!(
+            server_time <= t2,
+            "server time {server_time} is after t2 {t2}"
+        );
+    }
 }
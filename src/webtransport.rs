@@ -1,10 +1,90 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::marker::PhantomData;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
 
 use bytes::{Bytes, BytesMut};
+use futures_util::stream::{FuturesUnordered, StreamExt};
 use salvo::prelude::*;
+use salvo::proto::quic::OpenStreams;
+use salvo::proto::webtransport::server::AcceptedBi;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::chaos::{self, Transport};
+use crate::probe::Prober;
+use crate::recording::{self, Exchange, Session};
+use crate::{federation, shutdown, wait};
+
+/// Application error code a session's QUIC connection is closed with when the
+/// server shuts down: WebTransport error code 1001, "going away" as in
+/// WebSocket, mapped into the HTTP/3 error code space.
+pub(crate) const WT_GOING_AWAY: u64 = 0x52e4a40fa8db + 1001 + 1001 / 0x1e;
+
+/// Serves one "notify at T" request on a bidirectional stream: the client
+/// writes the instant as a little-endian f64 Unix time and the server replies
+/// with its send time followed by the instant, then finishes the stream.
+async fn notify<S>(mut stream: S) -> anyhow::Result<()>
+where
+    S: tokio::io::AsyncRead + tokio::io::AsyncWrite + Unpin,
+{
+    let until = stream.read_f64_le().await?;
+    let server_ts = wait::until(until)
+        .await
+        .map_err(|e| anyhow::anyhow!("{e}"))?;
+    let mut response = BytesMut::with_capacity(16);
+    response.extend_from_slice(&server_ts.to_le_bytes());
+    response.extend_from_slice(&until.to_le_bytes());
+    stream.write_all(&response).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Answers a time datagram with its first 8 bytes echoed, the server time and
+/// a flags byte, after the simulated network delays if any.
+async fn time_response(mut exchange: Exchange, echo: Bytes) -> Option<Bytes> {
+    chaos::before(Transport::Wt).await;
+    let server_ts = exchange
+        .timestamp()
+        .await
+        .duration_since(UNIX_EPOCH)
+        .ok()?
+        .as_secs_f64();
+    let mut response = BytesMut::with_capacity(17);
+    response.extend_from_slice(&echo);
+    response.extend_from_slice(&server_ts.to_le_bytes());
+    response.extend_from_slice(&[federation::flags()]);
+    chaos::after(Transport::Wt).await;
+    exchange.finish().await;
+    Some(response.freeze())
+}
+
+fn session_type<T>(_session: &T) -> PhantomData<T> {
+    PhantomData
+}
+
+/// Takes the WebTransport session back out of `req`, so that its connection
+/// can be closed. The QUIC connection type isn't exported, so `session` names
+/// the session type instead.
+fn take_session<T: Send + Sync + 'static>(
+    req: &mut Request,
+    _session: PhantomData<T>,
+) -> Option<T> {
+    Arc::into_inner(req.extensions_mut().remove::<Arc<T>>()?)
+}
 
 #[handler]
 pub(crate) async fn time_wt(req: &mut Request, res: &mut Response) -> Result<(), salvo::Error> {
+    serve(req, res, shutdown::stopping()).await
+}
+
+/// Serves a WebTransport session until the client leaves, or until `stopping`
+/// resolves and the session is closed with `WT_GOING_AWAY`.
+async fn serve(
+    req: &mut Request,
+    res: &mut Response,
+    stopping: impl Future<Output = ()>,
+) -> Result<(), salvo::Error> {
+    let peer = recording::peer(req.remote_addr());
+    let user_agent = req.header::<String>("user-agent");
     let session = match req.web_transport_mut().await {
         Ok(session) => session,
         Err(_) => {
@@ -12,31 +92,38 @@ pub(crate) async fn time_wt(req: &mut Request, res: &mut Response) -> Result<(),
             return Ok(());
         }
     };
+    let session_type = session_type(&*session);
 
+    let mut prober = Prober::new(Transport::Wt, peer.clone(), user_agent);
+    let exchanges = Arc::new(Session::new(Transport::Wt, peer));
     let mut datagram_reader = session.datagram_reader();
     let mut datagram_sender = session.datagram_sender();
+    let mut waits = FuturesUnordered::new();
+    let mut replies = FuturesUnordered::new();
+    tokio::pin!(stopping);
+    let mut going_away = false;
 
-    loop {
+    'session: loop {
         tokio::select! {
+            _ = &mut stopping => {
+                going_away = true;
+                break;
+            }
             result = datagram_reader.read_datagram() => {
                 match result {
                     Ok(datagram) => {
                         let payload: Bytes = datagram.into_payload();
-                        if payload.len() >= 8 {
-                            match SystemTime::now().duration_since(UNIX_EPOCH) {
-                                Ok(ts) => {
-                                    let server_ts = ts.as_secs_f64();
-                                    let mut response = BytesMut::with_capacity(16);
-                                    response.extend_from_slice(&payload[..8]);
-                                    response.extend_from_slice(&server_ts.to_le_bytes());
-                                    if let Err(e) = datagram_sender.send_datagram(response.freeze()) {
-                                        tracing::error!("Failed to send datagram: {e:?}");
-                                        break;
-                                    }
-                                }
-                                Err(_) => break,
-                            }
+                        if prober.reply(&payload) {
+                            continue;
+                        }
+                        if payload.len() < 8 {
+                            continue;
                         }
+                        if replies.len() >= chaos::MAX_PENDING {
+                            tracing::debug!("Too many pending time responses");
+                            continue;
+                        }
+                        replies.push(time_response(exchanges.start(&payload), payload.slice(..8)));
                     }
                     Err(e) => {
                         tracing::error!("Failed to read datagram: {e:?}");
@@ -44,10 +131,59 @@ pub(crate) async fn time_wt(req: &mut Request, res: &mut Response) -> Result<(),
                     }
                 }
             }
+            result = session.accept_bi() => {
+                match result {
+                    Ok(Some(AcceptedBi::BidiStream(_, stream))) => {
+                        if waits.len() >= wait::MAX_PENDING {
+                            tracing::debug!("Too many pending notifications");
+                            continue;
+                        }
+                        waits.push(notify(stream));
+                    }
+                    Ok(Some(AcceptedBi::Request(..))) => {}
+                    Ok(None) => break,
+                    Err(e) => {
+                        tracing::error!("Failed to accept stream: {e:?}");
+                        break;
+                    }
+                }
+            }
+            _ = prober.tick() => {
+                if let Err(e) = datagram_sender.send_datagram(prober.request()) {
+                    tracing::error!("Failed to send datagram: {e:?}");
+                    break;
+                }
+            }
+            Some(reply) = replies.next() => {
+                let Some(response) = reply else { break };
+                for _ in 0..chaos::copies(Transport::Wt) {
+                    if let Err(e) = datagram_sender.send_datagram(response.clone()) {
+                        tracing::error!("Failed to send datagram: {e:?}");
+                        break 'session;
+                    }
+                }
+            }
+            Some(result) = waits.next() => {
+                if let Err(e) = result {
+                    tracing::debug!("Failed to serve notification: {e:?}");
+                }
+            }
             else => break,
         }
     }
 
+    // Dropping the session would close its HTTP/3 connection with
+    // H3_NO_ERROR, which clients can't tell from the end of a session.
+    if going_away && let Some(session) = take_session(req, session_type) {
+        let (conn, _) = session.split();
+        if let Ok(mut conn) = conn.into_inner() {
+            <_ as OpenStreams<Bytes>>::close(
+                &mut conn.inner.conn,
+                WT_GOING_AWAY.into(),
+                b"Server shutting down",
+            );
+        }
+    }
     Ok(())
 }
 
@@ -57,18 +193,30 @@ mod tests {
     use salvo::conn::QuinnListener;
     use salvo::prelude::*;
     use std::time::{SystemTime, UNIX_EPOCH};
+    use tokio::io::AsyncReadExt;
+    use tokio::sync::Notify;
+    use wtransport::error::ConnectionError;
     use wtransport::tls::Sha256Digest;
     use wtransport::{ClientConfig, Endpoint};
 
+    use super::{WT_GOING_AWAY, serve};
     use crate::{router, self_signed};
 
+    static STOP: Notify = Notify::const_new();
+
+    #[handler]
+    async fn stopping_wt(req: &mut Request, res: &mut Response) -> Result<(), salvo::Error> {
+        serve(req, res, STOP.notified()).await
+    }
+
     #[tokio::test]
     async fn test_time_wt() {
         rustls::crypto::ring::default_provider()
             .install_default()
             .ok();
 
-        let (config, cert_hash) = self_signed::generate().unwrap();
+        let config = self_signed::generate().unwrap();
+        let cert_hash = config.cert_hashes().remove(0);
 
         // QuinnListener doesn't update its holdings after binding, so it can't
         // report the OS-assigned port when given port 0. Reserve a free UDP port
@@ -77,7 +225,7 @@ mod tests {
         let port = udp.local_addr().unwrap().port();
         drop(udp);
 
-        let acceptor = QuinnListener::new(config, format!("127.0.0.1:{port}"))
+        let acceptor = QuinnListener::new(config.quic().unwrap(), format!("127.0.0.1:{port}"))
             .bind()
             .await;
 
@@ -135,4 +283,116 @@ mod tests {
             "server time {server_time} is after t2 {t2}"
         );
     }
+
+    #[tokio::test]
+    async fn test_time_wt_wait() {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .ok();
+
+        let config = self_signed::generate().unwrap();
+        let cert_hash = config.cert_hashes().remove(0);
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = udp.local_addr().unwrap().port();
+        drop(udp);
+
+        let acceptor = QuinnListener::new(config.quic().unwrap(), format!("127.0.0.1:{port}"))
+            .bind()
+            .await;
+
+        let router = router::router();
+        tokio::spawn(async move {
+            Server::new(acceptor).serve(router).await;
+        });
+
+        let url = format!("https://127.0.0.1:{port}/time-wt");
+
+        let hash_bytes = base64::engine::general_purpose::STANDARD
+            .decode(&cert_hash)
+            .unwrap();
+        let hash = Sha256Digest::new(hash_bytes.try_into().unwrap());
+        let client_config = ClientConfig::builder()
+            .with_bind_config(wtransport::config::IpBindConfig::InAddrAnyDual)
+            .with_server_certificate_hashes([hash])
+            .build();
+
+        let endpoint = Endpoint::client(client_config).unwrap();
+        let session = endpoint.connect(&url).await.unwrap();
+
+        let until = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64()
+            + 0.25;
+
+        let (mut send, mut recv) = session.open_bi().await.unwrap().await.unwrap();
+        send.write_all(&until.to_le_bytes()).await.unwrap();
+        let mut response = Vec::new();
+        recv.read_to_end(&mut response).await.unwrap();
+
+        let t2 = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .expect("system clock before epoch")
+            .as_secs_f64();
+
+        assert_eq!(response.len(), 16, "unexpected notification length");
+        let server_time = f64::from_le_bytes(response[..8].try_into().unwrap());
+        let echoed = f64::from_le_bytes(response[8..16].try_into().unwrap());
+        assert_eq!(echoed, until);
+        assert!(
+            server_time >= until,
+            "server time {server_time} is before until {until}"
+        );
+        assert!(
+            server_time <= t2,
+            "server time {server_time} is after t2 {t2}"
+        );
+    }
+
+    #[tokio::test]
+    async fn test_time_wt_going_away() {
+        rustls::crypto::ring::default_provider()
+            .install_default()
+            .ok();
+
+        let config = self_signed::generate().unwrap();
+        let cert_hash = config.cert_hashes().remove(0);
+
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let port = udp.local_addr().unwrap().port();
+        drop(udp);
+
+        let acceptor = QuinnListener::new(config.quic().unwrap(), format!("127.0.0.1:{port}"))
+            .bind()
+            .await;
+
+        let router = Router::with_path("stopping-wt").goal(stopping_wt);
+        tokio::spawn(async move {
+            Server::new(acceptor).serve(router).await;
+        });
+
+        let url = format!("https://127.0.0.1:{port}/stopping-wt");
+
+        let hash_bytes = base64::engine::general_purpose::STANDARD
+            .decode(&cert_hash)
+            .unwrap();
+        let hash = Sha256Digest::new(hash_bytes.try_into().unwrap());
+        let client_config = ClientConfig::builder()
+            .with_bind_config(wtransport::config::IpBindConfig::InAddrAnyDual)
+            .with_server_certificate_hashes([hash])
+            .build();
+
+        let endpoint = Endpoint::client(client_config).unwrap();
+        let session = endpoint.connect(&url).await.unwrap();
+
+        STOP.notify_one();
+        match session.closed().await {
+            ConnectionError::ApplicationClosed(close) => {
+                assert_eq!(close.code().into_inner(), WT_GOING_AWAY);
+                assert_eq!(close.reason(), b"Server shutting down");
+            }
+            error => panic!("unexpected close: {error:?}"),
+        }
+    }
 }
@@ -4,6 +4,8 @@ import { resolve } from 'path'
 
 export default defineConfig({
   root: 'web',
+  // Relative asset URLs, so that pages work under --base-path.
+  base: './',
   build: {
     outDir: '../dist',
     emptyOutDir: true,
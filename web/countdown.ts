@@ -6,6 +6,8 @@ declare global {
     INITIAL_SERVER_TIME: number;
     WEB_TRANSPORT_PORT: number;
     WEB_TRANSPORT_CERT: string;
+    TELEMETRY: boolean;
+    BASE_PATH: string;
   }
 }
 
@@ -158,6 +160,8 @@ modeSelect.addEventListener('change', () => {
 const workerConfig = {
   webTransportPort: window.WEB_TRANSPORT_PORT,
   webTransportCert: window.WEB_TRANSPORT_CERT,
+  telemetry: window.TELEMETRY,
+  basePath: window.BASE_PATH,
   node: modeSelect.value,
 };
 if (typeof SharedWorker !== 'undefined') {
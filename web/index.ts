@@ -5,6 +5,8 @@ declare global {
     INITIAL_SERVER_TIME: number;
     WEB_TRANSPORT_PORT: number;
     WEB_TRANSPORT_CERT: string;
+    TELEMETRY: boolean;
+    BASE_PATH: string;
   }
 }
 
@@ -64,6 +66,8 @@ modeSelect.addEventListener('change', () => {
 const workerConfig = {
   webTransportPort: window.WEB_TRANSPORT_PORT,
   webTransportCert: window.WEB_TRANSPORT_CERT,
+  telemetry: window.TELEMETRY,
+  basePath: window.BASE_PATH,
   mode: modeSelect.value,
 };
 if (typeof SharedWorker !== 'undefined') {
@@ -9,6 +9,9 @@ type TransportMode = 'Auto' | 'WebTransport' | 'WebSocket' | 'Fetch';
 let webTransportPort: number | undefined;
 let webTransportCert: string | undefined;
 let mode: TransportMode | undefined;
+let telemetry = false;
+// Prefix of the server's routes, such as `/time`, without the trailing slash.
+let basePath = '';
 
 let timeoutId: number | undefined;
 let isSyncing = false;
@@ -38,23 +41,38 @@ function broadcast(message: object) {
   }
 }
 
+// Clock probe from the server: 'P?' and the server time, answered with 'P!',
+// the same server time and our wall clock time, as little-endian f64 seconds.
+function isProbe(bytes: Uint8Array) {
+  return bytes.byteLength === 10 && bytes[0] === 0x50 && bytes[1] === 0x3f;
+}
+
+function probeReply(bytes: Uint8Array) {
+  const reply = new Uint8Array(18);
+  reply.set([0x50, 0x21]);
+  reply.set(bytes.subarray(2), 2);
+  new DataView(reply.buffer).setFloat64(10, Date.now() / 1_000, true);
+  return reply;
+}
+
 function average(array: number[]) {
   return array.reduce((a, b) => a + b, 0) / array.length;
 }
 
 async function connectWt() {
-  const url = `https://${self.location.hostname}:${webTransportPort}/time-wt`;
+  const url = `https://${self.location.hostname}:${webTransportPort}${basePath}/time-wt`;
 
   const options: WebTransportOptions = {
     requireUnreliable: true,
   };
 
   if (webTransportCert) {
-    const certHash = Uint8Array.from(atob(webTransportCert), c => c.charCodeAt(0));
-    options.serverCertificateHashes = [{
+    // Comma-separated, as the next certificate is announced before the server
+    // switches to it.
+    options.serverCertificateHashes = webTransportCert.split(',').map(hash => ({
       algorithm: 'sha-256',
-      value: certHash
-    }];
+      value: Uint8Array.from(atob(hash), c => c.charCodeAt(0))
+    }));
   }
 
   wt = new WebTransport(url, options);
@@ -91,6 +109,10 @@ async function handleWtResponses(reader: ReadableStreamDefaultReader<Uint8Array>
       const responseReceived = performance.now();
 
       if (done) break;
+      if (isProbe(value)) {
+        wtWriter?.write(probeReply(value));
+        continue;
+      }
       if (value.byteLength < 16) continue;
 
       const view = new DataView(value.buffer, value.byteOffset, value.byteLength);
@@ -143,10 +165,17 @@ async function sendWtRequest() {
 
 async function connectWs(): Promise<void> {
   const protocol = self.location.protocol === 'https:' ? 'wss:' : 'ws:';
-  const url = `${protocol}//${self.location.host}/time-ws`;
+  const url = `${protocol}//${self.location.host}${basePath}/time-ws`;
 
-  ws = new WebSocket(url);
+  const socket = new WebSocket(url);
+  ws = socket;
   ws.binaryType = 'arraybuffer';
+  ws.addEventListener('message', (event) => {
+    const bytes = new Uint8Array(event.data);
+    if (isProbe(bytes)) {
+      socket.send(probeReply(bytes));
+    }
+  });
 
   let { promise, resolve, reject } = Promise.withResolvers();
   let timerId = setTimeout(() => {
@@ -189,6 +218,7 @@ async function measureWs(): Promise<void> {
     reject("WebSocket request timeout.");
   }, kConnectionTimeout);
   ws.onmessage = (event) => {
+    if (isProbe(new Uint8Array(event.data))) return;
     const responseReceived = performance.now();
     const view = new DataView(event.data);
     const serverTime = view.getFloat64(0, true) * 1_000;
@@ -212,7 +242,7 @@ async function measureWs(): Promise<void> {
 }
 
 async function measureHttp() {
-  const url = '/.well-known/time';
+  const url = `${basePath}/.well-known/time`;
 
   if (lastFetchRequest === undefined ||
       performance.now() - lastFetchRequest > kSocketTimeout) {
@@ -269,6 +299,21 @@ function updateMeasurements(requestSent: number, responseReceived: number, serve
   const offset: number = Date.now() - new Date(performance.now() + timeOrigin).getTime();
 
   broadcast({delay, timeOriginOffset, offset, mode});
+
+  if (telemetry && timeOrigins.length >= kNumSamples) {
+    reportTelemetry(delay, offset, mode);
+  }
+}
+
+function reportTelemetry(delay: number, offset: number, mode: string) {
+  const network = (navigator as unknown as {connection?: {effectiveType?: string}})
+      .connection?.effectiveType;
+  fetch(`${basePath}/.well-known/time/telemetry`, {
+    method: 'POST',
+    headers: {'content-type': 'application/json'},
+    body: JSON.stringify({delay, offset, mode, network}),
+    keepalive: true,
+  }).catch((e) => console.error('Failed to send telemetry.', e));
 }
 
 function setMode(newMode: TransportMode) {
@@ -351,6 +396,12 @@ function handleMessage(event: MessageEvent) {
   if (event.data.webTransportCert) {
     webTransportCert = event.data.webTransportCert;
   }
+  if (event.data.telemetry) {
+    telemetry = true;
+  }
+  if (event.data.basePath) {
+    basePath = event.data.basePath;
+  }
   if ('mode' in event.data) {
     setMode(event.data.mode);
   }